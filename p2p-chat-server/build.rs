@@ -196,25 +196,140 @@ export class P2PChat {
   }
   
   on_message() {}
-  on_connection() {}
+  on_event() {}
+  on_file() {}
+  on_file_progress() {}
+  on_track() {}
   create_offer() { return Promise.resolve({}); }
   accept_offer() { return Promise.resolve({}); }
+  create_offer_code() { return Promise.resolve(''); }
+  accept_offer_code() { return Promise.resolve(''); }
+  complete_connection_code() { return Promise.resolve(); }
+  set_negotiation_role() {}
+  handle_remote_offer() { return Promise.resolve(undefined); }
   complete_connection() { return Promise.resolve(); }
   add_ice_candidate() { return Promise.resolve(); }
   send_message() { return false; }
+  send_content() { return undefined; }
+  set_sender_id() {}
+  set_peer_sender_id() {}
+  send_file() { return undefined; }
+  set_transfer_store() {}
+  sync_history() {}
+  start_call() { return Promise.reject(new Error('WASM module not compiled. Run make build-wasm first.')); }
+  set_muted() {}
+  is_muted() { return false; }
+  get_local_stream() { return undefined; }
+  start_screen_share() { return Promise.reject(new Error('WASM module not compiled. Run make build-wasm first.')); }
+  stop_screen_share() { return Promise.resolve(); }
+  is_screen_sharing() { return false; }
+  hang_up() {}
   get_encryption_key() { return ''; }
+  set_passphrase() {}
+  is_key_confirmed() { return undefined; }
+  is_key_established() { return false; }
+  get_queue_depth() { return 0; }
+  get_buffered_amount() { return 0; }
+  get_stats() { return Promise.resolve({}); }
+  start_stats_sampling() {}
+  stop_stats_sampling() {}
+  get_authentication_string() { return undefined; }
+  mark_peer_verified() {}
+  is_peer_verified() { return false; }
   get_connection_state() { return 'error'; }
   get_ice_connection_state() { return 'error'; }
+  get_ice_gathering_state() { return 'error'; }
+  close() {}
+  free() {}
+}
+
+export class SignalingClient {
+  constructor() {
+    console.error('WASM module not compiled. Run make build-wasm first.');
+  }
+  
+  on_event() {}
+  get_user_id() { return undefined; }
+  get_users() { return []; }
+  discover() {}
+  connect_to() { return Promise.resolve(); }
+  send_message() {}
+  send_content() { return undefined; }
+  send_file() { return undefined; }
+  start_call() { return Promise.reject(new Error('WASM module not compiled. Run make build-wasm first.')); }
+  set_muted() {}
+  start_screen_share() { return Promise.reject(new Error('WASM module not compiled. Run make build-wasm first.')); }
+  stop_screen_share() { return Promise.resolve(); }
+  hang_up() {}
+  get_queue_depth() { return 0; }
+  get_stats() { return Promise.resolve({}); }
+  get_authentication_string() { return undefined; }
+  mark_peer_verified() {}
+  disconnect_from() {}
+  close() {}
+  free() {}
+}
+
+export class ChatSession {
+  constructor() {
+    console.error('WASM module not compiled. Run make build-wasm first.');
+  }
+  
+  on_event() {}
+  get_user_id() { return undefined; }
+  get_users() { return []; }
+  discover() {}
+  get_members() { return []; }
+  add_member() { return Promise.resolve(); }
+  remove_member() {}
+  send_message() { return undefined; }
+  send_content() { return undefined; }
+  on_join_request() {}
+  close() {}
+  free() {}
+}
+
+export class HistoryStore {
+  static open() { return Promise.reject(new Error('WASM module not compiled. Run make build-wasm first.')); }
+  save() { return Promise.resolve(); }
+  load_page() { return Promise.resolve({ messages: [] }); }
+  delete_conversation() { return Promise.resolve(); }
+  prune() { return Promise.resolve(); }
+  get_device_id() { return ''; }
+  pin_device() { return Promise.resolve(); }
+  unpin_device() { return Promise.resolve(); }
+  close() {}
+  wipe() { return Promise.resolve(); }
+  free() {}
+}
+
+export class TransferStore {
+  constructor() {}
+  free() {}
 }
 
 export function fetch_turn_config() {
   return Promise.resolve(null);
 }
 
+export function encode_signal_code() {
+  return '';
+}
+
+export function decode_signal_code() {
+  return {};
+}
+
 export default {
   init,
   P2PChat,
-  fetch_turn_config
+  SignalingClient,
+  ChatSession,
+  HistoryStore,
+  TransferStore,
+  fetch_turn_config,
+  encode_signal_code,
+  decode_signal_code
 };
 "#;
             fs::write(&js_path, js_content).unwrap_or_else(|e| {
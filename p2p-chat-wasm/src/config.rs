@@ -0,0 +1,225 @@
+// Peer connection configuration. P2PChat::new takes either a full ICE configuration shaped like
+// RTCConfiguration ({ iceServers, iceTransportPolicy, bundlePolicy, certificates }) plus our own
+// options, or the { urls, username, credential } object served by /api/turn-config. No
+// third-party STUN or TURN servers are used unless publicFallback is set.
+
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+use web_sys::{RtcBundlePolicy, RtcConfiguration, RtcIceTransportPolicy};
+
+// Public servers added only with publicFallback: Google's STUN server and the Open Relay
+// Project's TURN servers
+const FALLBACK_STUN_URL: &str = "stun:stun.l.google.com:19302";
+const FALLBACK_TURN_URLS: [&str; 2] = ["turn:relay.metered.ca:80", "turn:relay.metered.ca:443?transport=tcp"];
+const FALLBACK_TURN_CREDENTIAL: &str = "openrelayproject";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Urls {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct IceServer {
+    urls: Urls,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    credential: Option<String>,
+}
+
+impl IceServer {
+    fn urls(&self) -> Vec<&str> {
+        match &self.urls {
+            Urls::One(url) => vec![url.as_str()],
+            Urls::Many(urls) => urls.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IceTransportPolicy {
+    #[default]
+    All,
+    // Only relay candidates, so peers never learn each other's IP addresses
+    Relay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BundlePolicy {
+    Balanced,
+    MaxCompat,
+    MaxBundle,
+}
+
+// The serde-readable part of the configuration; certificates are RTCCertificate objects and are
+// passed through untouched
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IceConfig {
+    #[serde(default)]
+    pub(crate) ice_servers: Vec<IceServer>,
+    #[serde(default)]
+    pub(crate) ice_transport_policy: IceTransportPolicy,
+    #[serde(default)]
+    pub(crate) bundle_policy: Option<BundlePolicy>,
+    // Remote DTLS fingerprints ("sha-256 AB:CD:...") we accept; empty accepts any peer
+    #[serde(default)]
+    pub(crate) pinned_fingerprints: Vec<String>,
+    // Add the public STUN/TURN servers above
+    #[serde(default)]
+    pub(crate) public_fallback: bool,
+}
+
+// Legacy shape served by /api/turn-config
+#[derive(Debug, Clone, Deserialize)]
+struct TurnConfig {
+    urls: Vec<String>,
+    username: String,
+    credential: String,
+}
+
+impl IceConfig {
+    // Read a configuration object; null or undefined gives the default (no ICE servers)
+    pub(crate) fn from_js(config: &JsValue) -> Result<Self, JsValue> {
+        if config.is_null() || config.is_undefined() {
+            return Ok(IceConfig::default());
+        }
+
+        let has_field = |name: &str| js_sys::Reflect::has(config, &name.into()).unwrap_or(false);
+        if !has_field("iceServers") && has_field("urls") {
+            let turn: TurnConfig = serde_wasm_bindgen::from_value(config.clone())
+                .map_err(|e| JsValue::from_str(&format!("Invalid TURN configuration: {}", e)))?;
+            return Ok(IceConfig {
+                ice_servers: vec![IceServer {
+                    urls: Urls::Many(turn.urls),
+                    username: Some(turn.username),
+                    credential: Some(turn.credential),
+                }],
+                ..IceConfig::default()
+            });
+        }
+
+        serde_wasm_bindgen::from_value(config.clone())
+            .map_err(|e| JsValue::from_str(&format!("Invalid ICE configuration: {}", e)))
+    }
+
+    // Whether we accept a remote description's DTLS fingerprints: every one of them must be pinned,
+    // so an extra certificate can't ride along with a pinned one
+    pub(crate) fn accepts_fingerprints(&self, fingerprints: &[String]) -> bool {
+        if self.pinned_fingerprints.is_empty() {
+            return true;
+        }
+        !fingerprints.is_empty()
+            && fingerprints.iter().all(|fingerprint| {
+                self.pinned_fingerprints.iter().any(|pinned| normalize_fingerprint(pinned) == *fingerprint)
+            })
+    }
+
+    fn servers(&self) -> Vec<IceServer> {
+        let mut servers = self.ice_servers.clone();
+        if self.public_fallback {
+            servers.push(IceServer {
+                urls: Urls::One(FALLBACK_STUN_URL.to_string()),
+                username: None,
+                credential: None,
+            });
+            servers.push(IceServer {
+                urls: Urls::Many(FALLBACK_TURN_URLS.iter().map(|url| url.to_string()).collect()),
+                username: Some(FALLBACK_TURN_CREDENTIAL.to_string()),
+                credential: Some(FALLBACK_TURN_CREDENTIAL.to_string()),
+            });
+        }
+        servers
+    }
+}
+
+// Build the RTCConfiguration; certificates come straight from the original object
+pub(crate) fn rtc_configuration(config: &IceConfig, config_js: &JsValue) -> Result<RtcConfiguration, JsValue> {
+    let servers = config.servers();
+    if config.ice_transport_policy == IceTransportPolicy::Relay
+        && !servers.iter().any(|server| server.urls().iter().any(|url| is_turn_url(url)))
+    {
+        return Err(JsValue::from_str("Relay-only mode needs at least one TURN server"));
+    }
+
+    let ice_servers = js_sys::Array::new();
+    for server in &servers {
+        let entry = js_sys::Object::new();
+        let urls: js_sys::Array = server.urls().iter().map(|url| JsValue::from_str(url)).collect();
+        js_sys::Reflect::set(&entry, &"urls".into(), &urls)?;
+        if let Some(ref username) = server.username {
+            js_sys::Reflect::set(&entry, &"username".into(), &username.into())?;
+        }
+        if let Some(ref credential) = server.credential {
+            js_sys::Reflect::set(&entry, &"credential".into(), &credential.into())?;
+        }
+        ice_servers.push(&entry);
+    }
+
+    let rtc_config = RtcConfiguration::new();
+    rtc_config.set_ice_servers(&ice_servers);
+    rtc_config.set_ice_transport_policy(match config.ice_transport_policy {
+        IceTransportPolicy::All => RtcIceTransportPolicy::All,
+        IceTransportPolicy::Relay => RtcIceTransportPolicy::Relay,
+    });
+    if let Some(bundle_policy) = config.bundle_policy {
+        rtc_config.set_bundle_policy(match bundle_policy {
+            BundlePolicy::Balanced => RtcBundlePolicy::Balanced,
+            BundlePolicy::MaxCompat => RtcBundlePolicy::MaxCompat,
+            BundlePolicy::MaxBundle => RtcBundlePolicy::MaxBundle,
+        });
+    }
+
+    // Persisted certificates keep our DTLS fingerprint stable, so peers can pin it
+    if !config_js.is_null() && !config_js.is_undefined() {
+        let certificates = js_sys::Reflect::get(config_js, &"certificates".into())?;
+        if js_sys::Array::is_array(&certificates) {
+            rtc_config.set_certificates(&certificates);
+        }
+    }
+
+    Ok(rtc_config)
+}
+
+fn is_turn_url(url: &str) -> bool {
+    url.starts_with("turn:") || url.starts_with("turns:")
+}
+
+// Same form as crypto::extract_fingerprints: lowercase algorithm, uppercase hash
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    match fingerprint.trim().split_once(' ') {
+        Some((algorithm, hash)) => format!("{} {}", algorithm.to_ascii_lowercase(), hash.trim().to_ascii_uppercase()),
+        None => fingerprint.trim().to_ascii_uppercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned(fingerprints: &[&str]) -> IceConfig {
+        IceConfig {
+            pinned_fingerprints: fingerprints.iter().map(|f| f.to_string()).collect(),
+            ..IceConfig::default()
+        }
+    }
+
+    #[test]
+    fn accepts_any_certificate_without_pins() {
+        assert!(IceConfig::default().accepts_fingerprints(&[]));
+        assert!(IceConfig::default().accepts_fingerprints(&["sha-256 AA".to_string()]));
+    }
+
+    #[test]
+    fn requires_every_fingerprint_to_be_pinned() {
+        let config = pinned(&["SHA-256 aa:bb", "sha-1 cc"]);
+        assert!(config.accepts_fingerprints(&["sha-256 AA:BB".to_string()]));
+        assert!(config.accepts_fingerprints(&["sha-1 CC".to_string(), "sha-256 AA:BB".to_string()]));
+        assert!(!config.accepts_fingerprints(&["sha-256 AA:BB".to_string(), "sha-256 DD".to_string()]));
+        assert!(!config.accepts_fingerprints(&[]));
+    }
+}
@@ -0,0 +1,296 @@
+use std::fmt;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use wasm_bindgen::JsValue;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::ratchet::{Ratchet, RatchetError};
+
+// Labels mixed into every key derivation so keys never collide with other protocols
+const KEY_DERIVATION_INFO: &[u8] = b"p2p-chat aes-256-gcm v1";
+const SAS_DERIVATION_INFO: &[u8] = b"p2p-chat sas v1";
+const KEY_CHECK_LABEL: &[u8] = b"p2p-chat key check v1";
+const KEY_COMMITMENT_LABEL: &[u8] = b"p2p-chat key commitment v1";
+
+// Argon2id defaults for passphrase-derived keys (OWASP recommendation: 19 MiB, 2 passes)
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ARGON2_ITERATIONS: u32 = 2;
+const DEFAULT_ARGON2_PARALLELISM: u32 = 1;
+
+// Errors from the key exchange and key derivation code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CryptoError {
+    InvalidPublicKey,
+    NonContributory,
+    HandshakeComplete,
+    MissingFingerprint,
+    KeyDerivation,
+    InvalidPassphraseParams(String),
+    Ratchet(RatchetError),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidPublicKey => write!(f, "Invalid peer public key, must be 32 bytes"),
+            CryptoError::NonContributory => write!(f, "Peer public key is a low-order point"),
+            CryptoError::HandshakeComplete => write!(f, "Key exchange already completed"),
+            CryptoError::MissingFingerprint => write!(f, "DTLS fingerprint missing from session description"),
+            CryptoError::KeyDerivation => write!(f, "Key derivation failed"),
+            CryptoError::InvalidPassphraseParams(e) => write!(f, "Invalid passphrase parameters: {}", e),
+            CryptoError::Ratchet(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RatchetError> for CryptoError {
+    fn from(error: RatchetError) -> Self {
+        CryptoError::Ratchet(error)
+    }
+}
+
+impl From<CryptoError> for JsValue {
+    fn from(error: CryptoError) -> Self {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+// Output of a completed key exchange
+pub(crate) struct DerivedKeys {
+    // Double Ratchet session seeded with the derived secret
+    pub(crate) ratchet: Ratchet,
+    // Whether we hold the initiator role and may send immediately
+    pub(crate) initiator: bool,
+    // Short authentication string users compare out of band
+    pub(crate) authentication_string: String,
+}
+
+// One side of an X25519 key exchange; the secret is consumed by derive_key
+pub(crate) struct KeyExchange {
+    secret: Option<StaticSecret>,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    pub(crate) fn new() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        Self {
+            secret: Some(secret),
+            public_key,
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> [u8; 32] {
+        self.public_key.to_bytes()
+    }
+
+    // Hash of our public key, sent before the key itself so neither side can pick its key after
+    // seeing the other's
+    pub(crate) fn commitment(&self, session_id: &[u8; 16]) -> [u8; 32] {
+        key_commitment(session_id, &self.public_key.to_bytes())
+    }
+
+    // Drop the secret without finishing the exchange; StaticSecret zeroes itself on drop
+    pub(crate) fn discard(&mut self) {
+        self.secret = None;
+    }
+
+    // Run ECDH with the peer's public key and derive the shared secret with HKDF-SHA256, then seed a
+    // Double Ratchet with it. The party with the lower public key is the ratchet initiator, and the
+    // responder's handshake key pair doubles as its first ratchet key pair.
+    // The DTLS fingerprints of both ends are part of the transcript, so a signaling server that
+    // swapped SDPs to sit in the middle produces a different authentication string on each side.
+    // Public keys are committed to before they are revealed (see commitment), so a man in the
+    // middle gets one guess at the six-digit string instead of grinding keys until it matches.
+    pub(crate) fn derive_key(
+        &mut self,
+        peer_public_key: &[u8],
+        local_fingerprint: Option<&str>,
+        remote_fingerprint: Option<&str>,
+    ) -> Result<DerivedKeys, CryptoError> {
+        let peer_bytes: [u8; 32] = peer_public_key
+            .try_into()
+            .map_err(|_| CryptoError::InvalidPublicKey)?;
+        let local_fingerprint = local_fingerprint.ok_or(CryptoError::MissingFingerprint)?;
+        let remote_fingerprint = remote_fingerprint.ok_or(CryptoError::MissingFingerprint)?;
+        let secret = self.secret.take().ok_or(CryptoError::HandshakeComplete)?;
+
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(peer_bytes));
+        if !shared_secret.was_contributory() {
+            return Err(CryptoError::NonContributory);
+        }
+
+        // Both sides must build the same transcript, so order the parties by public key
+        let own_bytes = self.public_key.to_bytes();
+        let own = (own_bytes, local_fingerprint);
+        let peer = (peer_bytes, remote_fingerprint);
+        let (first, second) = if own_bytes <= peer_bytes { (own, peer) } else { (peer, own) };
+
+        let mut transcript = Vec::new();
+        for (public_key, fingerprint) in [first, second] {
+            transcript.extend_from_slice(&public_key);
+            transcript.extend_from_slice(&(fingerprint.len() as u16).to_be_bytes());
+            transcript.extend_from_slice(fingerprint.as_bytes());
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript), shared_secret.as_bytes());
+        let mut root_key = [0u8; 32];
+        hkdf.expand(KEY_DERIVATION_INFO, &mut root_key)
+            .map_err(|_| CryptoError::KeyDerivation)?;
+        let mut sas_bytes = [0u8; 4];
+        hkdf.expand(SAS_DERIVATION_INFO, &mut sas_bytes)
+            .map_err(|_| CryptoError::KeyDerivation)?;
+
+        let initiator = own_bytes < peer_bytes;
+        let ratchet = if initiator {
+            Ratchet::new_initiator(root_key, peer_bytes)?
+        } else {
+            Ratchet::new_responder(root_key, secret)
+        };
+
+        Ok(DerivedKeys {
+            ratchet,
+            initiator,
+            authentication_string: format_sas(sas_bytes),
+        })
+    }
+}
+
+// Render SAS bytes as six digits in two groups, e.g. "042 917"
+fn format_sas(bytes: [u8; 4]) -> String {
+    let code = u32::from_be_bytes(bytes) % 1_000_000;
+    format!("{:03} {:03}", code / 1000, code % 1000)
+}
+
+// Commitment to a public key, bound to the session that will reveal it
+pub(crate) fn key_commitment(session_id: &[u8; 16], public_key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_COMMITMENT_LABEL);
+    hasher.update(session_id);
+    hasher.update(public_key);
+    hasher.finalize().into()
+}
+
+// Every DTLS fingerprint ("sha-256 AB:CD:...") in an SDP blob, normalized, sorted and deduplicated
+pub(crate) fn extract_fingerprints(sdp: &str) -> Vec<String> {
+    let mut fingerprints: Vec<String> = sdp
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .filter_map(|value| {
+            let (algorithm, hash) = value.trim().split_once(' ')?;
+            Some(format!("{} {}", algorithm.to_ascii_lowercase(), hash.trim().to_ascii_uppercase()))
+        })
+        .collect();
+    fingerprints.sort();
+    fingerprints.dedup();
+    fingerprints
+}
+
+// All DTLS fingerprints of an SDP blob as one string, so every certificate it names (one per media
+// section, or several hash algorithms) ends up in the key exchange transcript
+pub(crate) fn extract_fingerprint(sdp: &str) -> Option<String> {
+    let fingerprints = extract_fingerprints(sdp);
+    if fingerprints.is_empty() {
+        None
+    } else {
+        Some(fingerprints.join("\n"))
+    }
+}
+
+// Argon2id cost parameters; anything left unset falls back to the defaults
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PassphraseParams {
+    pub(crate) memory_kib: Option<u32>,
+    pub(crate) iterations: Option<u32>,
+    pub(crate) parallelism: Option<u32>,
+}
+
+// Derive a 32-byte room key from a passphrase and salt with Argon2id
+pub(crate) fn derive_passphrase_key(passphrase: &str, salt: &str, params: PassphraseParams) -> Result<[u8; 32], CryptoError> {
+    let params = Params::new(
+        params.memory_kib.unwrap_or(DEFAULT_ARGON2_MEMORY_KIB),
+        params.iterations.unwrap_or(DEFAULT_ARGON2_ITERATIONS),
+        params.parallelism.unwrap_or(DEFAULT_ARGON2_PARALLELISM),
+        Some(32),
+    )
+    .map_err(|e| CryptoError::InvalidPassphraseParams(e.to_string()))?;
+
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+        .map_err(|e| CryptoError::InvalidPassphraseParams(e.to_string()))?;
+    Ok(key)
+}
+
+// Key check value a peer sends to prove it holds the same static key, bound to its session id
+pub(crate) fn key_check_value(key: &[u8; 32], session_id: &[u8; 16]) -> [u8; 32] {
+    let mut check = [0u8; 32];
+    check.copy_from_slice(&key_check_mac(key, session_id).finalize().into_bytes());
+    check
+}
+
+// Compare a peer's key check value against our key, in constant time
+pub(crate) fn verify_key_check(key: &[u8; 32], session_id: &[u8; 16], check: &[u8]) -> bool {
+    key_check_mac(key, session_id).verify_slice(check).is_ok()
+}
+
+fn key_check_mac(key: &[u8; 32], session_id: &[u8; 16]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(KEY_CHECK_LABEL);
+    mac.update(session_id);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=fingerprint:SHA-256 ab:cd:ef\r\n\
+        m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+        a=fingerprint:sha-1 12:34\r\n\
+        a=fingerprint:sha-256 AB:CD:EF\r\n";
+
+    #[test]
+    fn binds_every_fingerprint_line_in_sorted_order() {
+        assert_eq!(extract_fingerprints(SDP), vec!["sha-1 12:34", "sha-256 AB:CD:EF"]);
+        assert_eq!(extract_fingerprint(SDP).as_deref(), Some("sha-1 12:34\nsha-256 AB:CD:EF"));
+        assert_eq!(extract_fingerprint("v=0\r\n"), None);
+    }
+
+    #[test]
+    fn commitment_binds_key_and_session() {
+        let exchange = KeyExchange::new();
+        let session_id = [1; 16];
+        let commitment = exchange.commitment(&session_id);
+        assert_eq!(commitment, key_commitment(&session_id, &exchange.public_key()));
+        assert_ne!(commitment, key_commitment(&[2; 16], &exchange.public_key()));
+        assert_ne!(commitment, key_commitment(&session_id, &KeyExchange::new().public_key()));
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_authentication_string() {
+        let mut alice = KeyExchange::new();
+        let mut bob = KeyExchange::new();
+        let (alice_public, bob_public) = (alice.public_key(), bob.public_key());
+        let alice_keys = alice.derive_key(&bob_public, Some("sha-256 AA"), Some("sha-256 BB")).unwrap();
+        let bob_keys = bob.derive_key(&alice_public, Some("sha-256 BB"), Some("sha-256 AA")).unwrap();
+        assert_eq!(alice_keys.authentication_string, bob_keys.authentication_string);
+        assert_ne!(alice_keys.initiator, bob_keys.initiator);
+
+        // A swapped certificate on one side shows up as a different string
+        let mut carol = KeyExchange::new();
+        let mut dave = KeyExchange::new();
+        let (carol_public, dave_public) = (carol.public_key(), dave.public_key());
+        let carol_keys = carol.derive_key(&dave_public, Some("sha-256 AA"), Some("sha-256 CC")).unwrap();
+        let dave_keys = dave.derive_key(&carol_public, Some("sha-256 BB"), Some("sha-256 AA")).unwrap();
+        assert_ne!(carol_keys.authentication_string, dave_keys.authentication_string);
+    }
+}
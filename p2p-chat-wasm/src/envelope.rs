@@ -0,0 +1,315 @@
+// Plaintext envelope carried inside every encrypted chat frame.
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::sync::{SyncWatermark, SyncedMessage};
+use crate::transfer::FileOffer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+// What a message carries; serialized with a "kind" tag next to the envelope fields
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum MessageContent {
+    Text {
+        text: String,
+    },
+    Typing {
+        active: bool,
+    },
+    Receipt {
+        message_id: String,
+        status: ReceiptStatus,
+    },
+    Edit {
+        message_id: String,
+        text: String,
+    },
+    Delete {
+        message_id: String,
+    },
+    Reaction {
+        message_id: String,
+        reaction: String,
+        #[serde(default)]
+        remove: bool,
+    },
+    // Application-defined control messages; the payload is passed through untouched
+    Control {
+        action: String,
+        #[serde(default)]
+        data: serde_json::Value,
+    },
+    // File transfer frames, handled by P2PChat and never handed to on_message; the chunks
+    // themselves travel in their own binary frames
+    FileOffer(FileOffer),
+    FileAck {
+        transfer_id: String,
+        next_index: u32,
+    },
+    FileCancel {
+        transfer_id: String,
+        reason: String,
+    },
+    // A group member's sender key, handed to the group session and never to on_message
+    SenderKey {
+        key_id: u32,
+        // Base64 chain key
+        chain_key: String,
+        iteration: u32,
+    },
+    // History sync frames, handled by P2PChat and never handed to on_message
+    SyncHello {
+        device_id: String,
+    },
+    SyncRequest {
+        watermark: Option<SyncWatermark>,
+    },
+    SyncBatch {
+        messages: Vec<SyncedMessage>,
+        through: Option<SyncWatermark>,
+        done: bool,
+    },
+    SyncAck {
+        through: Option<SyncWatermark>,
+    },
+    // The peer has no history attached
+    SyncUnavailable,
+    // The peer hung up the call; handled by P2PChat
+    CallEnded,
+    // A group message under the sender's sender key, handed to the group session unopened
+    Group(GroupFrame),
+}
+
+// A group message encrypted once under a sender key and sent unchanged to every member
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct GroupFrame {
+    pub(crate) key_id: u32,
+    pub(crate) iteration: u32,
+    pub(crate) nonce: String,
+    pub(crate) ciphertext: String,
+}
+
+impl MessageContent {
+    // The kinds an application may send with send_content; everything else is protocol traffic
+    // that only this crate produces
+    pub(crate) fn is_user_content(&self) -> bool {
+        matches!(
+            self,
+            MessageContent::Text { .. }
+                | MessageContent::Typing { .. }
+                | MessageContent::Receipt { .. }
+                | MessageContent::Edit { .. }
+                | MessageContent::Delete { .. }
+                | MessageContent::Reaction { .. }
+                | MessageContent::Control { .. }
+        )
+    }
+
+    pub(crate) fn is_file_transfer(&self) -> bool {
+        matches!(
+            self,
+            MessageContent::FileOffer(_)
+                | MessageContent::FileAck { .. }
+                | MessageContent::FileCancel { .. }
+        )
+    }
+
+    // Group traffic, handed to ChatSession rather than on_message
+    pub(crate) fn is_group(&self) -> bool {
+        matches!(self, MessageContent::Group(_) | MessageContent::SenderKey { .. })
+    }
+
+    pub(crate) fn is_sync(&self) -> bool {
+        matches!(
+            self,
+            MessageContent::SyncHello { .. }
+                | MessageContent::SyncRequest { .. }
+                | MessageContent::SyncBatch { .. }
+                | MessageContent::SyncAck { .. }
+                | MessageContent::SyncUnavailable
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub(crate) id: String,
+    pub(crate) sender: String,
+    // Milliseconds since the Unix epoch, as reported by the sender's clock
+    pub(crate) timestamp: u64,
+    #[serde(flatten)]
+    pub(crate) content: MessageContent,
+}
+
+impl Envelope {
+    pub(crate) fn new(sender: &str, timestamp: u64, content: MessageContent) -> Self {
+        Self {
+            id: random_id(),
+            sender: sender.to_string(),
+            timestamp,
+            content,
+        }
+    }
+
+    // Parse a decrypted payload; peers that predate the envelope send bare text
+    pub(crate) fn from_plaintext(plaintext: &str, sender: &str, timestamp: u64) -> Self {
+        serde_json::from_str(plaintext).unwrap_or_else(|_| {
+            Envelope::new(sender, timestamp, MessageContent::Text {
+                text: plaintext.to_string(),
+            })
+        })
+    }
+}
+
+// Random 128-bit id as 32 hex characters, for messages and file transfers
+pub(crate) fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_kind() -> Vec<MessageContent> {
+        let watermark = SyncWatermark {
+            stored_at: 1_700_000_000_000.0,
+            id: "m1".to_string(),
+        };
+        vec![
+            MessageContent::Text { text: "hello".to_string() },
+            MessageContent::Typing { active: true },
+            MessageContent::Receipt {
+                message_id: "m1".to_string(),
+                status: ReceiptStatus::Read,
+            },
+            MessageContent::Edit {
+                message_id: "m1".to_string(),
+                text: "hello again".to_string(),
+            },
+            MessageContent::Delete { message_id: "m1".to_string() },
+            MessageContent::Reaction {
+                message_id: "m1".to_string(),
+                reaction: "👍".to_string(),
+                remove: false,
+            },
+            MessageContent::Control {
+                action: "ping".to_string(),
+                data: serde_json::json!({ "n": 1 }),
+            },
+            MessageContent::FileOffer(FileOffer {
+                transfer_id: "t1".to_string(),
+                name: "notes.txt".to_string(),
+                size: 10,
+                chunk_size: 16,
+                chunk_count: 1,
+                sha256: "00".repeat(32),
+            }),
+            MessageContent::FileAck {
+                transfer_id: "t1".to_string(),
+                next_index: 1,
+            },
+            MessageContent::FileCancel {
+                transfer_id: "t1".to_string(),
+                reason: "cancelled".to_string(),
+            },
+            MessageContent::SenderKey {
+                key_id: 3,
+                chain_key: "AAAA".to_string(),
+                iteration: 7,
+            },
+            MessageContent::SyncHello { device_id: "d1".to_string() },
+            MessageContent::SyncRequest { watermark: Some(watermark.clone()) },
+            MessageContent::SyncBatch {
+                messages: vec![SyncedMessage {
+                    conversation: "c1".to_string(),
+                    message: Envelope::new("alice", 1, MessageContent::Text { text: "hi".to_string() }),
+                }],
+                through: Some(watermark.clone()),
+                done: true,
+            },
+            MessageContent::SyncAck { through: Some(watermark) },
+            MessageContent::SyncUnavailable,
+            MessageContent::CallEnded,
+            MessageContent::Group(GroupFrame {
+                key_id: 3,
+                iteration: 7,
+                nonce: "bm9uY2U=".to_string(),
+                ciphertext: "Y2lwaGVy".to_string(),
+            }),
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        for content in every_kind() {
+            let envelope = Envelope::new("alice", 1_700_000_000_000, content);
+            let json = serde_json::to_string(&envelope).unwrap();
+            assert_eq!(serde_json::from_str::<Envelope>(&json).unwrap(), envelope, "{}", json);
+        }
+    }
+
+    #[test]
+    fn tags_the_kind_next_to_the_envelope_fields() {
+        let envelope = Envelope::new("alice", 5, MessageContent::Reaction {
+            message_id: "m1".to_string(),
+            reaction: "👍".to_string(),
+            remove: true,
+        });
+        let value = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(value["kind"], "reaction");
+        assert_eq!(value["sender"], "alice");
+        assert_eq!(value["timestamp"], 5);
+        assert_eq!(value["message_id"], "m1");
+        assert_eq!(value["remove"], true);
+
+        let value = serde_json::to_value(Envelope::new("alice", 5, MessageContent::SyncUnavailable)).unwrap();
+        assert_eq!(value["kind"], "sync_unavailable");
+    }
+
+    #[test]
+    fn defaults_optional_fields() {
+        let envelope: Envelope = serde_json::from_str(
+            r#"{"id":"m2","sender":"bob","timestamp":1,"kind":"reaction","message_id":"m1","reaction":"x"}"#,
+        )
+        .unwrap();
+        assert_eq!(envelope.content, MessageContent::Reaction {
+            message_id: "m1".to_string(),
+            reaction: "x".to_string(),
+            remove: false,
+        });
+    }
+
+    #[test]
+    fn parses_an_envelope_from_plaintext() {
+        let sent = Envelope::new("bob", 42, MessageContent::Typing { active: false });
+        let received = Envelope::from_plaintext(&serde_json::to_string(&sent).unwrap(), "alice", 99);
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn wraps_bare_text_from_older_peers() {
+        for plaintext in ["hello", "", "{\"not\": \"an envelope\"}", "{\"kind\":\"unknown\"}"] {
+            let envelope = Envelope::from_plaintext(plaintext, "alice", 99);
+            assert_eq!(envelope.sender, "alice");
+            assert_eq!(envelope.timestamp, 99);
+            assert_eq!(envelope.content, MessageContent::Text { text: plaintext.to_string() });
+            assert_eq!(envelope.id.len(), 32);
+        }
+    }
+
+    #[test]
+    fn only_user_kinds_are_user_content() {
+        let user: Vec<_> = every_kind().into_iter().filter(MessageContent::is_user_content).collect();
+        assert_eq!(user.len(), 7);
+        assert!(user.iter().all(|content| !content.is_file_transfer() && !content.is_sync() && !content.is_group()));
+    }
+}
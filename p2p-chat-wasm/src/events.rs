@@ -0,0 +1,217 @@
+// Typed connection events. P2PChat reports everything about its connection through the
+// on_event callback as a plain object with a "type" tag and a structured payload. The
+// TypeScript definitions below are emitted into the generated .d.ts and must be kept in step
+// with ChatEvent.
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use web_sys::{RtcIceConnectionState, RtcIceGatheringState, RtcSignalingState};
+
+use crate::{IceCandidate, SessionDescription};
+
+#[wasm_bindgen(typescript_custom_section)]
+const CHAT_EVENT_TS: &'static str = r#"
+export interface IceCandidateData {
+  candidate: string;
+  sdp_mid: string | null;
+  sdp_m_line_index: number | null;
+  username_fragment: string | null;
+}
+
+export interface SessionDescriptionData {
+  sdp: string;
+  type_: "offer" | "answer";
+}
+
+export type SignalingStateName =
+  "stable" | "have-local-offer" | "have-remote-offer" | "have-local-pranswer" | "have-remote-pranswer" | "closed" | "unknown";
+export type IceConnectionStateName =
+  "new" | "checking" | "connected" | "completed" | "failed" | "disconnected" | "closed" | "unknown";
+export type IceGatheringStateName = "new" | "gathering" | "complete" | "unknown";
+
+export type ChatEvent =
+  | { type: "signaling_state"; state: SignalingStateName }
+  | { type: "ice_connection_state"; state: IceConnectionStateName }
+  | { type: "ice_gathering_state"; state: IceGatheringStateName }
+  | { type: "ice_candidate"; candidate: IceCandidateData }
+  | { type: "offer"; reason: "ice_restart" | "renegotiation"; description: SessionDescriptionData }
+  | { type: "data_channel"; state: "received" | "open" | "closed"; label: string }
+  | { type: "key"; state: "established" | "confirmed" | "mismatch" }
+  | { type: "reconnect"; state: "reconnecting" | "reconnected" | "failed" }
+  | { type: "call"; state: "started" | "ended" | "remote_ended" }
+  | { type: "screen_share"; state: "started" | "stopped" }
+  | { type: "history_sync"; state: "started" | "complete" | "unavailable" | "failed"; merged?: number; reason?: string }
+  | { type: "error"; source: "frame"; reason: string; counter?: number }
+  | { type: "error"; source: "ice_candidate"; reason: string; candidate: string }
+  | { type: "error"; source: "key_exchange"; reason: "malformed" | "commitment_mismatch" | "derivation_failed" };
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "(event: ChatEvent) => void")]
+    pub type ChatEventCallback;
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ChatEvent<'a> {
+    SignalingState {
+        state: &'static str,
+    },
+    IceConnectionState {
+        state: &'static str,
+    },
+    IceGatheringState {
+        state: &'static str,
+    },
+    // A local candidate to deliver to the peer; an empty candidate marks the end of candidates
+    IceCandidate {
+        candidate: &'a IceCandidate,
+    },
+    // An offer made on our own, to deliver to the peer over signaling
+    Offer {
+        reason: OfferReason,
+        description: &'a SessionDescription,
+    },
+    DataChannel {
+        state: DataChannelStatus,
+        label: &'a str,
+    },
+    Key {
+        state: KeyStatus,
+    },
+    Reconnect {
+        state: ReconnectStatus,
+    },
+    Call {
+        state: CallStatus,
+    },
+    ScreenShare {
+        state: ScreenShareStatus,
+    },
+    HistorySync {
+        state: HistorySyncStatus,
+        // On completion, how many messages were added or replaced
+        #[serde(skip_serializing_if = "Option::is_none")]
+        merged: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+    Error {
+        source: ErrorSource,
+        reason: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        counter: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        candidate: Option<&'a str>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OfferReason {
+    IceRestart,
+    Renegotiation,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DataChannelStatus {
+    // The answerer was handed the offerer's channel
+    Received,
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum KeyStatus {
+    Established,
+    // Manual keys only: the peer's key check matched
+    Confirmed,
+    // The peer's manual key check didn't match, or the exchanged secret failed key confirmation
+    Mismatch,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReconnectStatus {
+    Reconnecting,
+    Reconnected,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CallStatus {
+    Started,
+    // We hung up
+    Ended,
+    // The peer hung up, which ends our side too
+    RemoteEnded,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ScreenShareStatus {
+    Started,
+    // Stopped by us or from the browser's controls; the camera is back
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HistorySyncStatus {
+    Started,
+    // Everything the peer had for us is merged
+    Complete,
+    // The peer hasn't attached a history
+    Unavailable,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorSource {
+    // An incoming data channel frame was dropped
+    Frame,
+    // A remote ICE candidate was rejected
+    IceCandidate,
+    // The peer's key exchange was malformed, didn't match its commitment or gave no usable key;
+    // the channel can't carry messages until it re-keys
+    KeyExchange,
+}
+
+pub(crate) fn signaling_state_name(state: RtcSignalingState) -> &'static str {
+    match state {
+        RtcSignalingState::Stable => "stable",
+        RtcSignalingState::HaveLocalOffer => "have-local-offer",
+        RtcSignalingState::HaveRemoteOffer => "have-remote-offer",
+        RtcSignalingState::HaveLocalPranswer => "have-local-pranswer",
+        RtcSignalingState::HaveRemotePranswer => "have-remote-pranswer",
+        RtcSignalingState::Closed => "closed",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn ice_connection_state_name(state: RtcIceConnectionState) -> &'static str {
+    match state {
+        RtcIceConnectionState::New => "new",
+        RtcIceConnectionState::Checking => "checking",
+        RtcIceConnectionState::Connected => "connected",
+        RtcIceConnectionState::Completed => "completed",
+        RtcIceConnectionState::Failed => "failed",
+        RtcIceConnectionState::Disconnected => "disconnected",
+        RtcIceConnectionState::Closed => "closed",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn ice_gathering_state_name(state: RtcIceGatheringState) -> &'static str {
+    match state {
+        RtcIceGatheringState::New => "new",
+        RtcIceGatheringState::Gathering => "gathering",
+        RtcIceGatheringState::Complete => "complete",
+        _ => "unknown",
+    }
+}
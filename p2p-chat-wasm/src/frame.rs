@@ -0,0 +1,212 @@
+// Encrypted data frames and their compact binary encoding. Peers that announce binary support
+// in their key exchange get frames as ArrayBuffers laid out as
+//
+//   version (1) | kind (1) | sender session id (16) | counter (8, big-endian)
+//   | ratchet header (40, ratchet frames only) | nonce (12) | ciphertext
+//
+// Older peers keep getting the base64-in-JSON frames.
+//
+// The kind says both how the frame is encrypted and what it carries: a JSON message envelope, or
+// (since version 2) a raw file chunk, which has no JSON form and needs binary frames.
+
+use crate::ratchet::{RatchetHeader, RatchetMessage, HEADER_LEN};
+
+pub(crate) const BINARY_FRAME_VERSION: u8 = 2;
+
+const KIND_RATCHET: u8 = 1;
+const KIND_STATIC: u8 = 2;
+const KIND_RATCHET_CHUNK: u8 = 3;
+const KIND_STATIC_CHUNK: u8 = 4;
+// Appended to the associated data of file chunk frames, so a chunk can't be passed off as a
+// message envelope or the other way around
+const FILE_CHUNK_AAD_LABEL: &[u8] = b"file chunk";
+const PREFIX_LEN: usize = 2 + 16 + 8;
+const NONCE_LEN: usize = 12;
+
+// What the plaintext of a data frame holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameContent {
+    Envelope,
+    FileChunk,
+}
+
+impl FrameContent {
+    // Associated data for a frame with this content
+    pub(crate) fn bind(self, mut aad: Vec<u8>) -> Vec<u8> {
+        if self == FrameContent::FileChunk {
+            aad.extend_from_slice(FILE_CHUNK_AAD_LABEL);
+        }
+        aad
+    }
+}
+
+// An encrypted chat frame, independent of how it travels over the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SealedFrame {
+    // Message under the Double Ratchet session
+    Ratchet {
+        session_id: [u8; 16],
+        counter: u64,
+        message: RatchetMessage,
+    },
+    // Message under a manually set static key
+    Static {
+        session_id: [u8; 16],
+        counter: u64,
+        nonce: [u8; NONCE_LEN],
+        ciphertext: Vec<u8>,
+    },
+}
+
+impl SealedFrame {
+    pub(crate) fn counter(&self) -> u64 {
+        match self {
+            SealedFrame::Ratchet { counter, .. } | SealedFrame::Static { counter, .. } => *counter,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self, content: FrameContent) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            SealedFrame::Ratchet { session_id, counter, message } => {
+                let kind = match content {
+                    FrameContent::Envelope => KIND_RATCHET,
+                    FrameContent::FileChunk => KIND_RATCHET_CHUNK,
+                };
+                bytes.reserve(PREFIX_LEN + HEADER_LEN + NONCE_LEN + message.ciphertext.len());
+                bytes.extend_from_slice(&[BINARY_FRAME_VERSION, kind]);
+                bytes.extend_from_slice(session_id);
+                bytes.extend_from_slice(&counter.to_be_bytes());
+                bytes.extend_from_slice(&message.header.to_bytes());
+                bytes.extend_from_slice(&message.nonce);
+                bytes.extend_from_slice(&message.ciphertext);
+            }
+            SealedFrame::Static { session_id, counter, nonce, ciphertext } => {
+                let kind = match content {
+                    FrameContent::Envelope => KIND_STATIC,
+                    FrameContent::FileChunk => KIND_STATIC_CHUNK,
+                };
+                bytes.reserve(PREFIX_LEN + NONCE_LEN + ciphertext.len());
+                bytes.extend_from_slice(&[BINARY_FRAME_VERSION, kind]);
+                bytes.extend_from_slice(session_id);
+                bytes.extend_from_slice(&counter.to_be_bytes());
+                bytes.extend_from_slice(nonce);
+                bytes.extend_from_slice(ciphertext);
+            }
+        }
+        bytes
+    }
+
+    // Parse a binary frame; None for unknown versions, unknown kinds and truncated frames
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<(FrameContent, Self)> {
+        if bytes.len() < PREFIX_LEN || bytes[0] != BINARY_FRAME_VERSION {
+            return None;
+        }
+
+        let kind = bytes[1];
+        let session_id: [u8; 16] = bytes[2..18].try_into().ok()?;
+        let counter = u64::from_be_bytes(bytes[18..PREFIX_LEN].try_into().ok()?);
+        let rest = &bytes[PREFIX_LEN..];
+
+        let content = match kind {
+            KIND_RATCHET | KIND_STATIC => FrameContent::Envelope,
+            KIND_RATCHET_CHUNK | KIND_STATIC_CHUNK => FrameContent::FileChunk,
+            _ => return None,
+        };
+
+        let frame = match kind {
+            KIND_RATCHET | KIND_RATCHET_CHUNK => {
+                if rest.len() < HEADER_LEN + NONCE_LEN {
+                    return None;
+                }
+                let header = RatchetHeader::from_bytes(&rest[..HEADER_LEN]).ok()?;
+                let nonce = rest[HEADER_LEN..HEADER_LEN + NONCE_LEN].try_into().ok()?;
+                SealedFrame::Ratchet {
+                    session_id,
+                    counter,
+                    message: RatchetMessage {
+                        header,
+                        nonce,
+                        ciphertext: rest[HEADER_LEN + NONCE_LEN..].to_vec(),
+                    },
+                }
+            }
+            _ => {
+                if rest.len() < NONCE_LEN {
+                    return None;
+                }
+                SealedFrame::Static {
+                    session_id,
+                    counter,
+                    nonce: rest[..NONCE_LEN].try_into().ok()?,
+                    ciphertext: rest[NONCE_LEN..].to_vec(),
+                }
+            }
+        };
+        Some((content, frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratchet_frame() -> SealedFrame {
+        SealedFrame::Ratchet {
+            session_id: [7; 16],
+            counter: 42,
+            message: RatchetMessage {
+                header: RatchetHeader {
+                    public_key: [9; 32],
+                    previous_chain_length: 3,
+                    message_number: 5,
+                },
+                nonce: [1; NONCE_LEN],
+                ciphertext: vec![0xaa; 48],
+            },
+        }
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        let frame = ratchet_frame();
+        let bytes = frame.to_bytes(FrameContent::Envelope);
+        assert_eq!(bytes.len(), PREFIX_LEN + HEADER_LEN + NONCE_LEN + 48);
+        assert_eq!(SealedFrame::from_bytes(&bytes), Some((FrameContent::Envelope, frame.clone())));
+        let bytes = frame.to_bytes(FrameContent::FileChunk);
+        assert_eq!(bytes[1], KIND_RATCHET_CHUNK);
+        assert_eq!(SealedFrame::from_bytes(&bytes), Some((FrameContent::FileChunk, frame)));
+
+        let frame = SealedFrame::Static {
+            session_id: [3; 16],
+            counter: u64::MAX,
+            nonce: [2; NONCE_LEN],
+            ciphertext: vec![1, 2, 3],
+        };
+        for content in [FrameContent::Envelope, FrameContent::FileChunk] {
+            assert_eq!(SealedFrame::from_bytes(&frame.to_bytes(content)), Some((content, frame.clone())));
+        }
+    }
+
+    #[test]
+    fn binds_the_content_into_the_associated_data() {
+        assert_eq!(FrameContent::Envelope.bind(b"aad".to_vec()), b"aad");
+        assert_ne!(FrameContent::FileChunk.bind(b"aad".to_vec()), b"aad");
+    }
+
+    #[test]
+    fn rejects_unknown_and_truncated_frames() {
+        let bytes = ratchet_frame().to_bytes(FrameContent::Envelope);
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = BINARY_FRAME_VERSION + 1;
+        assert_eq!(SealedFrame::from_bytes(&wrong_version), None);
+
+        let mut wrong_kind = bytes.clone();
+        wrong_kind[1] = 0xff;
+        assert_eq!(SealedFrame::from_bytes(&wrong_kind), None);
+
+        assert_eq!(SealedFrame::from_bytes(&bytes[..PREFIX_LEN - 1]), None);
+        assert_eq!(SealedFrame::from_bytes(&bytes[..PREFIX_LEN + HEADER_LEN]), None);
+    }
+}
@@ -0,0 +1,32 @@
+// Ownership of the closures installed as on* handlers. Instead of forgetting them, each
+// connection keeps its closures here and drops them on close(), after the on* properties that
+// point at them have been cleared.
+
+use std::any::Any;
+use std::collections::HashMap;
+
+use wasm_bindgen::closure::{Closure, WasmClosure};
+
+#[derive(Default)]
+pub(crate) struct Handlers {
+    closures: Vec<Box<dyn Any>>,
+    // Handlers installed again on every negotiation, by name, so only the latest set is kept
+    slots: HashMap<&'static str, Handlers>,
+}
+
+impl Handlers {
+    pub(crate) fn keep<T: ?Sized + WasmClosure + 'static>(&mut self, closure: Closure<T>) {
+        self.closures.push(Box::new(closure));
+    }
+
+    // Keep handlers in place of the ones last kept under the same name. The replaced ones are
+    // returned, to be dropped once whatever pointed at them has been detached or re-pointed.
+    pub(crate) fn replace(&mut self, slot: &'static str, handlers: Handlers) -> Option<Handlers> {
+        self.slots.insert(slot, handlers)
+    }
+
+    // Hand the closures over to be dropped outside any borrow of the state that holds them
+    pub(crate) fn take(&mut self) -> Handlers {
+        std::mem::take(self)
+    }
+}
@@ -0,0 +1,940 @@
+// Opt-in message history in IndexedDB, encrypted at rest. Nothing is stored unless the page opens
+// a HistoryStore and saves messages into it. Each envelope is sealed with AES-256-GCM under a
+// random data key; the data key itself is stored wrapped by a key derived from the user's
+// passphrase with Argon2id. Only the conversation id, message id and timestamps are readable on
+// disk, since IndexedDB needs them to page through a conversation in order.
+//
+// Every record also gets a local storage position (stored_at), increasing on each device, which
+// history sync uses as its watermark.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use base64::{decode, encode};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbCursorDirection, IdbCursorWithValue, IdbDatabase, IdbKeyRange, IdbObjectStore, IdbObjectStoreParameters,
+    IdbOpenDbRequest, IdbRequest, IdbTransaction, IdbTransactionMode, IdbVersionChangeEvent,
+};
+use zeroize::Zeroize;
+
+use crate::crypto::{derive_passphrase_key, PassphraseParams};
+use crate::envelope::{random_id, Envelope};
+use crate::sync::{SyncBatch, SyncWatermark, SyncedMessage};
+use crate::{decrypt_message, encrypt_message, now_millis};
+
+const DEFAULT_DATABASE_NAME: &str = "p2p-chat-history";
+// 2 added the stored_at index, 3 moved retention onto stored_at
+const DATABASE_VERSION: u32 = 3;
+
+const MESSAGES_STORE: &str = "messages";
+const META_STORE: &str = "meta";
+// Messages of one conversation in time order: [conversation, timestamp, id]
+const CONVERSATION_INDEX: &str = "conversation";
+// Version 1 and 2 index of all messages by timestamp, dropped in version 3
+const TIMESTAMP_INDEX: &str = "timestamp";
+// All messages in local storage order, for sync and age-based retention: [stored_at, id]
+const STORED_INDEX: &str = "stored";
+// Messages of one conversation in local storage order, for the per-conversation limit:
+// [conversation, stored_at, id]
+const CONVERSATION_STORED_INDEX: &str = "conversation_stored";
+const DATA_KEY_RECORD: &str = "data_key";
+const DEVICE_ID_RECORD: &str = "device_id";
+// Peer device ids history sync is allowed with without verifying the connection again
+const PINNED_DEVICES_RECORD: &str = "pinned_devices";
+// Sync watermarks are kept per peer device under this prefix
+const WATERMARK_RECORD_PREFIX: &str = "sync:";
+
+const DATA_KEY_AAD: &[u8] = b"p2p-chat history key v1";
+const RECORD_AAD_LABEL: &[u8] = b"p2p-chat history v1";
+
+// Messages returned by load_page when no limit is given
+const DEFAULT_PAGE_SIZE: u32 = 50;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryOptions {
+    // Separate histories (e.g. per local account) need separate databases
+    #[serde(default)]
+    database_name: Option<String>,
+    // Messages stored longest ago beyond this many in a conversation are deleted as new ones are saved
+    #[serde(default)]
+    max_messages_per_conversation: Option<u32>,
+    // Messages stored on this device longer ago than this are deleted on open and by prune()
+    #[serde(default)]
+    max_age_ms: Option<f64>,
+}
+
+// The data key, wrapped under the passphrase key
+#[derive(Serialize, Deserialize)]
+struct WrappedKey {
+    salt: String,
+    nonce: String,
+    wrapped_key: String,
+}
+
+// One message as stored
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    conversation: String,
+    id: String,
+    timestamp: f64,
+    stored_at: f64,
+    // Device the message was synced from, so it isn't sent straight back there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<String>,
+    nonce: String,
+    ciphertext: String,
+}
+
+// Where the next (older) page starts
+#[derive(Serialize, Deserialize)]
+struct PageCursor {
+    timestamp: f64,
+    id: String,
+}
+
+#[derive(Serialize)]
+struct HistoryPage {
+    // Oldest first
+    messages: Vec<Envelope>,
+    // Pass back to load_page for older messages; absent once the start is reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<PageCursor>,
+}
+
+#[wasm_bindgen]
+pub struct HistoryStore {
+    inner: Rc<HistoryDatabase>,
+}
+
+// The open database, shared with connections that sync it
+pub(crate) struct HistoryDatabase {
+    database: IdbDatabase,
+    name: String,
+    options: HistoryOptions,
+    device_id: String,
+    pinned_devices: RefCell<Vec<String>>,
+    data_key: RefCell<[u8; 32]>,
+    // Highest stored_at handed out, so positions keep increasing if the clock steps back
+    last_stored_at: Cell<f64>,
+    closed: Cell<bool>,
+}
+
+#[wasm_bindgen]
+impl HistoryStore {
+    // Open (or create) the history database. The passphrase unwraps the data key, so a wrong
+    // passphrase fails here rather than on the first read. Options: { databaseName,
+    // maxMessagesPerConversation, maxAgeMs }.
+    #[wasm_bindgen]
+    pub async fn open(passphrase: String, options: JsValue) -> Result<HistoryStore, JsValue> {
+        let options: HistoryOptions = if options.is_null() || options.is_undefined() {
+            HistoryOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options)
+                .map_err(|e| JsValue::from_str(&format!("Invalid history options: {}", e)))?
+        };
+        let name = options.database_name.clone().unwrap_or_else(|| DEFAULT_DATABASE_NAME.to_string());
+
+        let database = open_database(&name).await?;
+        let loaded = async {
+            let data_key = load_data_key(&database, &passphrase).await?;
+            let device_id = load_device_id(&database).await?;
+            let pinned_devices = load_pinned_devices(&database).await?;
+            let last_stored_at = last_stored_at(&database).await?;
+            Ok::<_, JsValue>((data_key, device_id, pinned_devices, last_stored_at))
+        }
+        .await;
+        let (data_key, device_id, pinned_devices, last_stored_at) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                database.close();
+                return Err(e);
+            }
+        };
+
+        let inner = Rc::new(HistoryDatabase {
+            database,
+            name,
+            options,
+            device_id,
+            pinned_devices: RefCell::new(pinned_devices),
+            data_key: RefCell::new(data_key),
+            last_stored_at: Cell::new(last_stored_at),
+            closed: Cell::new(false),
+        });
+        inner.prune().await?;
+        Ok(HistoryStore { inner })
+    }
+
+    // Store a message envelope (as handed to on_message, or returned by send_content) under a
+    // conversation id, such as a peer's user id or a room name. Saving the same id again
+    // replaces the earlier copy.
+    #[wasm_bindgen]
+    pub async fn save(&self, conversation: String, message: JsValue) -> Result<(), JsValue> {
+        let envelope: Envelope = serde_wasm_bindgen::from_value(message)
+            .map_err(|e| JsValue::from_str(&format!("Invalid message: {}", e)))?;
+        self.inner.save_envelopes(&conversation, std::slice::from_ref(&envelope)).await
+    }
+
+    // Load up to `limit` messages of a conversation, newest page first. Pass the returned
+    // `before` cursor to get the page before it.
+    #[wasm_bindgen]
+    pub async fn load_page(&self, conversation: String, before: JsValue, limit: Option<u32>) -> Result<JsValue, JsValue> {
+        self.inner.load_page(&conversation, before, limit).await
+    }
+
+    // Delete every message of one conversation
+    #[wasm_bindgen]
+    pub async fn delete_conversation(&self, conversation: String) -> Result<(), JsValue> {
+        self.inner.delete_conversation(&conversation).await
+    }
+
+    // Apply the age limit now; the per-conversation limit is applied as messages are saved
+    #[wasm_bindgen]
+    pub async fn prune(&self) -> Result<(), JsValue> {
+        self.inner.prune().await
+    }
+
+    // This device's id, for pinning on the user's other devices
+    #[wasm_bindgen]
+    pub fn get_device_id(&self) -> String {
+        self.inner.device_id.clone()
+    }
+
+    // Allow history sync with another device of the user without verifying the connection to it
+    // first. Devices synced over a verified connection are pinned automatically.
+    #[wasm_bindgen]
+    pub async fn pin_device(&self, device_id: String) -> Result<(), JsValue> {
+        self.inner.pin_device(&device_id).await
+    }
+
+    // Stop trusting a device pinned earlier
+    #[wasm_bindgen]
+    pub async fn unpin_device(&self, device_id: String) -> Result<(), JsValue> {
+        let pinned = {
+            let mut pinned = self.inner.pinned_devices.borrow_mut();
+            pinned.retain(|pinned| *pinned != device_id);
+            pinned.clone()
+        };
+        self.inner.save_pinned_devices(&pinned).await
+    }
+
+    // Close the database and forget the data key
+    #[wasm_bindgen]
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    // Delete the whole database, wrapped key included, and close the store
+    #[wasm_bindgen]
+    pub async fn wipe(&self) -> Result<(), JsValue> {
+        self.inner.close();
+        let factory = indexed_db()?;
+        let request = factory.delete_database(&self.inner.name)?;
+        request_result(&request).await?;
+        Ok(())
+    }
+}
+
+impl HistoryStore {
+    pub(crate) fn database(&self) -> Rc<HistoryDatabase> {
+        self.inner.clone()
+    }
+}
+
+impl HistoryDatabase {
+    pub(crate) fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub(crate) fn is_device_pinned(&self, device_id: &str) -> bool {
+        self.pinned_devices.borrow().iter().any(|pinned| pinned == device_id)
+    }
+
+    pub(crate) async fn pin_device(&self, device_id: &str) -> Result<(), JsValue> {
+        let pinned = {
+            let mut pinned = self.pinned_devices.borrow_mut();
+            if pinned.iter().any(|pinned| pinned == device_id) {
+                return Ok(());
+            }
+            pinned.push(device_id.to_string());
+            pinned.clone()
+        };
+        self.save_pinned_devices(&pinned).await
+    }
+
+    async fn save_pinned_devices(&self, pinned: &[String]) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let transaction = self.database.transaction_with_str_and_mode(META_STORE, IdbTransactionMode::Readwrite)?;
+        let meta = transaction.object_store(META_STORE)?;
+        meta.put_with_key(&serde_wasm_bindgen::to_value(pinned)?, &PINNED_DEVICES_RECORD.into())?;
+        transaction_complete(&transaction).await
+    }
+
+    fn ensure_open(&self) -> Result<(), JsValue> {
+        if self.closed.get() {
+            return Err(JsValue::from_str("History store is closed"));
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        if self.closed.replace(true) {
+            return;
+        }
+        self.data_key.borrow_mut().zeroize();
+        self.database.close();
+    }
+
+    // Storage positions only go up
+    fn next_stored_at(&self) -> f64 {
+        let stored_at = (now_millis() as f64).max(self.last_stored_at.get() + 1.0);
+        self.last_stored_at.set(stored_at);
+        stored_at
+    }
+
+    async fn load_page(&self, conversation: &str, before: JsValue, limit: Option<u32>) -> Result<JsValue, JsValue> {
+        self.ensure_open()?;
+        let before: Option<PageCursor> = if before.is_null() || before.is_undefined() {
+            None
+        } else {
+            Some(serde_wasm_bindgen::from_value(before)?)
+        };
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1) as usize;
+
+        let upper = match before {
+            Some(ref cursor) => index_key(conversation, cursor.timestamp, &cursor.id),
+            None => conversation_end(conversation),
+        };
+        let range = IdbKeyRange::bound_with_lower_open_and_upper_open(&conversation_start(conversation), &upper, false, true)?;
+
+        let transaction = self.database.transaction_with_str_and_mode(MESSAGES_STORE, IdbTransactionMode::Readonly)?;
+        let index = transaction.object_store(MESSAGES_STORE)?.index(CONVERSATION_INDEX)?;
+        let request = index.open_cursor_with_range_and_direction(&range, IdbCursorDirection::Prev)?;
+
+        let mut records = Vec::new();
+        let mut more = false;
+        loop {
+            let cursor = request_result(&request).await?;
+            if cursor.is_null() || cursor.is_undefined() {
+                break;
+            }
+            if records.len() == limit {
+                more = true;
+                break;
+            }
+            let cursor: IdbCursorWithValue = cursor.unchecked_into();
+            records.push(serde_wasm_bindgen::from_value::<StoredMessage>(cursor.value()?)?);
+            cursor.continue_()?;
+        }
+
+        let mut messages = Vec::with_capacity(records.len());
+        for record in records.iter().rev() {
+            messages.push(self.open_record(record)?);
+        }
+        let before = match records.last() {
+            Some(oldest) if more => Some(PageCursor {
+                timestamp: oldest.timestamp,
+                id: oldest.id.clone(),
+            }),
+            _ => None,
+        };
+
+        let page = HistoryPage { messages, before };
+        Ok(page.serialize(&serde_wasm_bindgen::Serializer::json_compatible())?)
+    }
+
+    async fn delete_conversation(&self, conversation: &str) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let range = conversation_range(conversation)?;
+        self.delete_in_index(CONVERSATION_INDEX, &range, None).await
+    }
+
+    // Age is measured from when we stored a message, not from the timestamp its sender claims,
+    // so a peer can't get messages kept forever or dropped at once by lying about the time
+    async fn prune(&self) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let Some(max_age_ms) = self.options.max_age_ms else {
+            return Ok(());
+        };
+        let cutoff = now_millis() as f64 - max_age_ms;
+        // [cutoff] sorts after every [stored_at, id] key with stored_at below the cutoff
+        let range = IdbKeyRange::upper_bound_with_open(&js_sys::Array::of1(&cutoff.into()), true)?;
+        self.delete_in_index(STORED_INDEX, &range, None).await
+    }
+
+    // Encrypt and store envelopes of one conversation in a single transaction, then apply the
+    // per-conversation limit
+    async fn save_envelopes(&self, conversation: &str, envelopes: &[Envelope]) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let transaction = self.database.transaction_with_str_and_mode(MESSAGES_STORE, IdbTransactionMode::Readwrite)?;
+        let store = transaction.object_store(MESSAGES_STORE)?;
+        for envelope in envelopes {
+            let record = self.seal_record(conversation, envelope, None)?;
+            store.put(&serde_wasm_bindgen::to_value(&record)?)?;
+        }
+        transaction_complete(&transaction).await?;
+        self.apply_message_limit(conversation).await
+    }
+
+    // Delete the messages of a conversation stored longest ago beyond the per-conversation limit
+    async fn apply_message_limit(&self, conversation: &str) -> Result<(), JsValue> {
+        let Some(max_messages) = self.options.max_messages_per_conversation else {
+            return Ok(());
+        };
+        let range = conversation_range(conversation)?;
+        let transaction = self.database.transaction_with_str_and_mode(MESSAGES_STORE, IdbTransactionMode::Readonly)?;
+        let index = transaction.object_store(MESSAGES_STORE)?.index(CONVERSATION_STORED_INDEX)?;
+        let count = request_result(&index.count_with_key(&range)?).await?.as_f64().unwrap_or(0.0) as u32;
+        if count > max_messages {
+            self.delete_in_index(CONVERSATION_STORED_INDEX, &range, Some(count - max_messages)).await?;
+        }
+        Ok(())
+    }
+
+    fn seal_record(&self, conversation: &str, envelope: &Envelope, origin: Option<&str>) -> Result<StoredMessage, JsValue> {
+        let timestamp = envelope.timestamp as f64;
+        let plaintext = serde_json::to_string(envelope).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let aad = record_aad(conversation, &envelope.id, timestamp);
+        let (ciphertext, nonce) = encrypt_message(&self.data_key.borrow(), plaintext.as_bytes(), &aad)?;
+        Ok(StoredMessage {
+            conversation: conversation.to_string(),
+            id: envelope.id.clone(),
+            timestamp,
+            stored_at: self.next_stored_at(),
+            origin: origin.map(str::to_string),
+            nonce: encode(nonce),
+            ciphertext: encode(ciphertext),
+        })
+    }
+
+    fn open_record(&self, record: &StoredMessage) -> Result<Envelope, JsValue> {
+        let nonce = decode(&record.nonce).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let ciphertext = decode(&record.ciphertext).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let aad = record_aad(&record.conversation, &record.id, record.timestamp);
+        let plaintext = decrypt_message(&self.data_key.borrow(), &nonce, &ciphertext, &aad)
+            .map_err(|_| JsValue::from_str("Stored message failed to decrypt"))?;
+        serde_json::from_slice(&plaintext).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    // Delete the records an index range covers, oldest first, up to `limit` of them
+    async fn delete_in_index(&self, index: &str, range: &IdbKeyRange, limit: Option<u32>) -> Result<(), JsValue> {
+        let transaction = self.database.transaction_with_str_and_mode(MESSAGES_STORE, IdbTransactionMode::Readwrite)?;
+        let index = transaction.object_store(MESSAGES_STORE)?.index(index)?;
+        let request = index.open_cursor_with_range(range)?;
+
+        let mut deleted = 0;
+        while limit.is_none_or(|limit| deleted < limit) {
+            let cursor = request_result(&request).await?;
+            if cursor.is_null() || cursor.is_undefined() {
+                break;
+            }
+            let cursor: IdbCursorWithValue = cursor.unchecked_into();
+            cursor.delete()?;
+            deleted += 1;
+            cursor.continue_()?;
+        }
+        transaction_complete(&transaction).await
+    }
+
+    // Where we got to in a peer device's history at the last sync
+    pub(crate) async fn watermark(&self, peer_device: &str) -> Result<Option<SyncWatermark>, JsValue> {
+        self.ensure_open()?;
+        let transaction = self.database.transaction_with_str(META_STORE)?;
+        let meta = transaction.object_store(META_STORE)?;
+        let value = request_result(&meta.get(&watermark_record(peer_device).into())?).await?;
+        if value.is_undefined() || value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(serde_wasm_bindgen::from_value(value)?))
+    }
+
+    pub(crate) async fn set_watermark(&self, peer_device: &str, watermark: &SyncWatermark) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let transaction = self.database.transaction_with_str_and_mode(META_STORE, IdbTransactionMode::Readwrite)?;
+        let meta = transaction.object_store(META_STORE)?;
+        meta.put_with_key(&serde_wasm_bindgen::to_value(watermark)?, &watermark_record(peer_device).into())?;
+        transaction_complete(&transaction).await
+    }
+
+    // Read what was stored after a watermark, in storage order, until about max_bytes of messages
+    // are collected. Messages that came from the peer device itself are passed over.
+    pub(crate) async fn records_after(
+        &self,
+        after: Option<&SyncWatermark>,
+        peer_device: &str,
+        max_bytes: usize,
+    ) -> Result<SyncBatch, JsValue> {
+        self.ensure_open()?;
+        let transaction = self.database.transaction_with_str(MESSAGES_STORE)?;
+        let index = transaction.object_store(MESSAGES_STORE)?.index(STORED_INDEX)?;
+        let request = match after {
+            Some(after) => {
+                let range = IdbKeyRange::lower_bound_with_open(&stored_key(after), true)?;
+                index.open_cursor_with_range(&range)?
+            }
+            None => index.open_cursor()?,
+        };
+
+        let mut messages = Vec::new();
+        let mut through = None;
+        let mut bytes = 0;
+        let mut done = true;
+        loop {
+            let cursor = request_result(&request).await?;
+            if cursor.is_null() || cursor.is_undefined() {
+                break;
+            }
+            let cursor: IdbCursorWithValue = cursor.unchecked_into();
+            let record: StoredMessage = serde_wasm_bindgen::from_value(cursor.value()?)?;
+
+            if record.origin.as_deref() != Some(peer_device) {
+                // The base64 ciphertext is a little larger than the message it holds
+                let size = record.ciphertext.len();
+                if !messages.is_empty() && bytes + size > max_bytes {
+                    done = false;
+                    break;
+                }
+                match self.open_record(&record) {
+                    Ok(message) => {
+                        bytes += size;
+                        messages.push(SyncedMessage {
+                            conversation: record.conversation.clone(),
+                            message,
+                        });
+                    }
+                    Err(e) => web_sys::console::log_2(&format!("Skipping stored message {}:", record.id).into(), &e),
+                }
+            }
+            through = Some(SyncWatermark {
+                stored_at: record.stored_at,
+                id: record.id,
+            });
+            cursor.continue_()?;
+        }
+
+        Ok(SyncBatch { messages, through, done })
+    }
+
+    // Merge messages from another device. A message we don't have is added; one we have under the
+    // same id is replaced only by a later timestamp, with ties going to the larger serialization,
+    // so both devices settle on the same copy whatever order batches arrive in. Returns how many
+    // messages were added or replaced.
+    pub(crate) async fn merge(&self, messages: &[SyncedMessage], origin: &str) -> Result<u32, JsValue> {
+        self.ensure_open()?;
+        let transaction = self.database.transaction_with_str_and_mode(MESSAGES_STORE, IdbTransactionMode::Readwrite)?;
+        let store = transaction.object_store(MESSAGES_STORE)?;
+
+        let mut merged = 0;
+        for synced in messages {
+            let key = string_array(&[&synced.conversation, &synced.message.id]);
+            let existing = request_result(&store.get(&key)?).await?;
+            let replace = if existing.is_undefined() || existing.is_null() {
+                true
+            } else {
+                let existing: StoredMessage = serde_wasm_bindgen::from_value(existing)?;
+                match self.open_record(&existing) {
+                    Ok(existing) => supersedes(&synced.message, &existing),
+                    Err(_) => true,
+                }
+            };
+            if replace {
+                let record = self.seal_record(&synced.conversation, &synced.message, Some(origin))?;
+                store.put(&serde_wasm_bindgen::to_value(&record)?)?;
+                merged += 1;
+            }
+        }
+        transaction_complete(&transaction).await?;
+
+        let mut conversations: Vec<&str> = messages.iter().map(|synced| synced.conversation.as_str()).collect();
+        conversations.sort_unstable();
+        conversations.dedup();
+        for conversation in conversations {
+            self.apply_message_limit(conversation).await?;
+        }
+        self.prune().await?;
+        Ok(merged)
+    }
+}
+
+impl Drop for HistoryDatabase {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn indexed_db() -> Result<web_sys::IdbFactory, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window found"))?;
+    window.indexed_db()?.ok_or_else(|| JsValue::from_str("IndexedDB is not available"))
+}
+
+async fn open_database(name: &str) -> Result<IdbDatabase, JsValue> {
+    let request: IdbOpenDbRequest = indexed_db()?.open_with_u32(name, DATABASE_VERSION)?;
+
+    // Walks the records of a version 1 database inside the upgrade transaction, which finishes
+    // before the open request succeeds
+    let backfill = Closure::wrap(Box::new(move |event: web_sys::Event| {
+        let cursor = event
+            .target()
+            .and_then(|target| target.dyn_into::<IdbRequest>().ok())
+            .and_then(|request| request.result().ok())
+            .and_then(|result| result.dyn_into::<IdbCursorWithValue>().ok());
+        if let Some(cursor) = cursor {
+            if let Err(e) = backfill_record(&cursor) {
+                web_sys::console::log_2(&"Failed to upgrade stored message:".into(), &e);
+            }
+        }
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    let backfill_handler = backfill.as_ref().unchecked_ref::<js_sys::Function>().clone();
+
+    let upgrade_request = request.clone();
+    let onupgradeneeded = Closure::wrap(Box::new(move |event: IdbVersionChangeEvent| {
+        if let Err(e) = upgrade(&upgrade_request, event.old_version() as u32, &backfill_handler) {
+            web_sys::console::log_2(&"Failed to upgrade history database:".into(), &e);
+        }
+    }) as Box<dyn FnMut(IdbVersionChangeEvent)>);
+    request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
+
+    let result = request_result(&request).await;
+    request.set_onupgradeneeded(None);
+    Ok(result?.unchecked_into())
+}
+
+fn upgrade(request: &IdbOpenDbRequest, old_version: u32, backfill: &js_sys::Function) -> Result<(), JsValue> {
+    let database: IdbDatabase = request.result()?.unchecked_into();
+
+    let messages = if old_version < 1 {
+        let parameters = IdbObjectStoreParameters::new();
+        parameters.set_key_path(&string_array(&["conversation", "id"]));
+        let messages = database.create_object_store_with_optional_parameters(MESSAGES_STORE, &parameters)?;
+        messages.create_index_with_str_sequence(CONVERSATION_INDEX, &string_array(&["conversation", "timestamp", "id"]))?;
+        database.create_object_store(META_STORE)?;
+        messages
+    } else {
+        request
+            .transaction()
+            .ok_or_else(|| JsValue::from_str("No upgrade transaction"))?
+            .object_store(MESSAGES_STORE)?
+    };
+
+    if old_version < 2 {
+        messages.create_index_with_str_sequence(STORED_INDEX, &string_array(&["stored_at", "id"]))?;
+        if old_version >= 1 {
+            messages.open_cursor()?.set_onsuccess(Some(backfill));
+        }
+    }
+
+    if old_version < 3 {
+        messages.create_index_with_str_sequence(
+            CONVERSATION_STORED_INDEX,
+            &string_array(&["conversation", "stored_at", "id"]),
+        )?;
+        if old_version >= 1 {
+            messages.delete_index(TIMESTAMP_INDEX)?;
+        }
+    }
+    Ok(())
+}
+
+// Records from version 1 have no storage position; their timestamp stands in for it
+fn backfill_record(cursor: &IdbCursorWithValue) -> Result<(), JsValue> {
+    let value = cursor.value()?;
+    if js_sys::Reflect::get(&value, &"stored_at".into())?.is_undefined() {
+        let timestamp = js_sys::Reflect::get(&value, &"timestamp".into())?;
+        js_sys::Reflect::set(&value, &"stored_at".into(), &timestamp)?;
+        cursor.update(&value)?;
+    }
+    cursor.continue_()
+}
+
+// This database's device id for history sync, created on first use
+async fn load_device_id(database: &IdbDatabase) -> Result<String, JsValue> {
+    let transaction = database.transaction_with_str_and_mode(META_STORE, IdbTransactionMode::Readwrite)?;
+    let meta = transaction.object_store(META_STORE)?;
+    if let Some(device_id) = request_result(&meta.get(&DEVICE_ID_RECORD.into())?).await?.as_string() {
+        return Ok(device_id);
+    }
+
+    let device_id = random_id();
+    meta.put_with_key(&device_id.as_str().into(), &DEVICE_ID_RECORD.into())?;
+    transaction_complete(&transaction).await?;
+    Ok(device_id)
+}
+
+// Device ids pinned for history sync
+async fn load_pinned_devices(database: &IdbDatabase) -> Result<Vec<String>, JsValue> {
+    let transaction = database.transaction_with_str(META_STORE)?;
+    let meta = transaction.object_store(META_STORE)?;
+    let value = request_result(&meta.get(&PINNED_DEVICES_RECORD.into())?).await?;
+    if value.is_undefined() || value.is_null() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_wasm_bindgen::from_value(value)?)
+}
+
+// Highest storage position in use
+async fn last_stored_at(database: &IdbDatabase) -> Result<f64, JsValue> {
+    let transaction = database.transaction_with_str(MESSAGES_STORE)?;
+    let index = transaction.object_store(MESSAGES_STORE)?.index(STORED_INDEX)?;
+    let request = index.open_cursor_with_range_and_direction(&JsValue::UNDEFINED, IdbCursorDirection::Prev)?;
+    let cursor = request_result(&request).await?;
+    if cursor.is_null() || cursor.is_undefined() {
+        return Ok(0.0);
+    }
+    let cursor: IdbCursorWithValue = cursor.unchecked_into();
+    let record: StoredMessage = serde_wasm_bindgen::from_value(cursor.value()?)?;
+    Ok(record.stored_at)
+}
+
+// Unwrap the data key with the passphrase, or create and store one on first use
+async fn load_data_key(database: &IdbDatabase, passphrase: &str) -> Result<[u8; 32], JsValue> {
+    let transaction = database.transaction_with_str_and_mode(META_STORE, IdbTransactionMode::Readwrite)?;
+    let meta: IdbObjectStore = transaction.object_store(META_STORE)?;
+    let existing = request_result(&meta.get(&DATA_KEY_RECORD.into())?).await?;
+
+    if !existing.is_undefined() && !existing.is_null() {
+        let wrapped: WrappedKey = serde_wasm_bindgen::from_value(existing)?;
+        let mut wrapping_key = passphrase_key(passphrase, &wrapped.salt)?;
+        let nonce = decode(&wrapped.nonce).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let ciphertext = decode(&wrapped.wrapped_key).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let unwrapped = decrypt_message(&wrapping_key, &nonce, &ciphertext, DATA_KEY_AAD);
+        wrapping_key.zeroize();
+
+        let mut unwrapped = unwrapped.map_err(|_| JsValue::from_str("Wrong passphrase for message history"))?;
+        let data_key = <[u8; 32]>::try_from(unwrapped.as_slice())
+            .map_err(|_| JsValue::from_str("Stored history key is malformed"));
+        unwrapped.zeroize();
+        return data_key;
+    }
+
+    let mut data_key = [0u8; 32];
+    OsRng.fill_bytes(&mut data_key);
+    let salt = random_id();
+    let mut wrapping_key = passphrase_key(passphrase, &salt)?;
+    let sealed = encrypt_message(&wrapping_key, &data_key, DATA_KEY_AAD);
+    wrapping_key.zeroize();
+    let (ciphertext, nonce) = sealed?;
+
+    let wrapped = WrappedKey {
+        salt,
+        nonce: encode(nonce),
+        wrapped_key: encode(ciphertext),
+    };
+    meta.put_with_key(&serde_wasm_bindgen::to_value(&wrapped)?, &DATA_KEY_RECORD.into())?;
+    transaction_complete(&transaction).await?;
+    Ok(data_key)
+}
+
+fn passphrase_key(passphrase: &str, salt: &str) -> Result<[u8; 32], JsValue> {
+    derive_passphrase_key(passphrase, salt, PassphraseParams::default()).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+// Associated data binding a stored message to its conversation, id and timestamp, so records
+// can't be moved between conversations or reordered
+fn record_aad(conversation: &str, id: &str, timestamp: f64) -> Vec<u8> {
+    [
+        RECORD_AAD_LABEL,
+        &(conversation.len() as u32).to_be_bytes(),
+        conversation.as_bytes(),
+        &(id.len() as u32).to_be_bytes(),
+        id.as_bytes(),
+        &timestamp.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn watermark_record(peer_device: &str) -> String {
+    format!("{}{}", WATERMARK_RECORD_PREFIX, peer_device)
+}
+
+// Whether an incoming copy of a message should replace the one we have
+fn supersedes(incoming: &Envelope, existing: &Envelope) -> bool {
+    if incoming.timestamp != existing.timestamp {
+        return incoming.timestamp > existing.timestamp;
+    }
+    // Ties go to the larger serialization; a copy that can't be serialized never wins one
+    match (serde_json::to_string(incoming), serde_json::to_string(existing)) {
+        (Ok(incoming), Ok(existing)) => incoming > existing,
+        _ => false,
+    }
+}
+
+fn string_array(values: &[&str]) -> js_sys::Array {
+    values.iter().map(|value| JsValue::from_str(value)).collect()
+}
+
+fn index_key(conversation: &str, timestamp: f64, id: &str) -> JsValue {
+    let key = js_sys::Array::new();
+    key.push(&conversation.into());
+    key.push(&timestamp.into());
+    key.push(&id.into());
+    key.into()
+}
+
+fn stored_key(watermark: &SyncWatermark) -> JsValue {
+    js_sys::Array::of2(&watermark.stored_at.into(), &watermark.id.as_str().into()).into()
+}
+
+// [conversation] sorts before every [conversation, timestamp, id] key, and [conversation, []]
+// after them, since arrays sort above numbers
+fn conversation_start(conversation: &str) -> JsValue {
+    js_sys::Array::of1(&conversation.into()).into()
+}
+
+fn conversation_end(conversation: &str) -> JsValue {
+    js_sys::Array::of2(&conversation.into(), &js_sys::Array::new()).into()
+}
+
+fn conversation_range(conversation: &str) -> Result<IdbKeyRange, JsValue> {
+    IdbKeyRange::bound(&conversation_start(conversation), &conversation_end(conversation))
+}
+
+// Wait for a request to succeed, resolving to its result
+async fn request_result(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let mut handler = None;
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        let settled = request.clone();
+        let closure = Closure::wrap(Box::new(move |event: web_sys::Event| {
+            if event.type_() == "success" {
+                let _ = resolve.call1(&JsValue::NULL, &settled.result().unwrap_or(JsValue::UNDEFINED));
+            } else {
+                let error = settled.error().ok().flatten().map(JsValue::from)
+                    .unwrap_or_else(|| JsValue::from_str("IndexedDB request failed"));
+                let _ = reject.call1(&JsValue::NULL, &error);
+            }
+        }) as Box<dyn FnMut(web_sys::Event)>);
+        request.set_onsuccess(Some(closure.as_ref().unchecked_ref()));
+        request.set_onerror(Some(closure.as_ref().unchecked_ref()));
+        handler = Some(closure);
+    });
+
+    let result = JsFuture::from(promise).await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    drop(handler);
+    result
+}
+
+// Wait for a transaction to commit
+async fn transaction_complete(transaction: &IdbTransaction) -> Result<(), JsValue> {
+    let mut handler = None;
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        let settled = transaction.clone();
+        let closure = Closure::wrap(Box::new(move |event: web_sys::Event| {
+            if event.type_() == "complete" {
+                let _ = resolve.call0(&JsValue::NULL);
+            } else {
+                let error = settled.error().map(JsValue::from)
+                    .unwrap_or_else(|| JsValue::from_str("IndexedDB transaction failed"));
+                let _ = reject.call1(&JsValue::NULL, &error);
+            }
+        }) as Box<dyn FnMut(web_sys::Event)>);
+        transaction.set_oncomplete(Some(closure.as_ref().unchecked_ref()));
+        transaction.set_onerror(Some(closure.as_ref().unchecked_ref()));
+        transaction.set_onabort(Some(closure.as_ref().unchecked_ref()));
+        handler = Some(closure);
+    });
+
+    let result = JsFuture::from(promise).await;
+    transaction.set_oncomplete(None);
+    transaction.set_onerror(None);
+    transaction.set_onabort(None);
+    drop(handler);
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::envelope::MessageContent;
+
+    fn copy(id: &str, timestamp: u64, text: &str) -> Envelope {
+        Envelope {
+            id: id.to_string(),
+            sender: "alice".to_string(),
+            timestamp,
+            content: MessageContent::Text { text: text.to_string() },
+        }
+    }
+
+    // The replacement rule merge applies to each incoming message
+    fn merge_into(store: &mut BTreeMap<String, Envelope>, incoming: &Envelope) {
+        let replace = store.get(&incoming.id).is_none_or(|existing| supersedes(incoming, existing));
+        if replace {
+            store.insert(incoming.id.clone(), incoming.clone());
+        }
+    }
+
+    #[test]
+    fn later_timestamp_wins() {
+        let older = copy("m1", 10, "zzz");
+        let newer = copy("m1", 11, "aaa");
+        assert!(supersedes(&newer, &older));
+        assert!(!supersedes(&older, &newer));
+    }
+
+    #[test]
+    fn ties_go_to_exactly_one_copy() {
+        let first = copy("m1", 10, "edited");
+        let second = copy("m1", 10, "original");
+        assert_ne!(supersedes(&first, &second), supersedes(&second, &first));
+        assert!(!supersedes(&first, &first.clone()));
+    }
+
+    #[test]
+    fn merging_in_any_order_gives_the_same_history() {
+        let copies = [
+            copy("m1", 10, "original"),
+            copy("m1", 10, "edited"),
+            copy("m1", 12, "edited again"),
+            copy("m2", 5, "hi"),
+            copy("m2", 5, "hello"),
+            copy("m3", 7, "only copy"),
+        ];
+
+        let mut expected = None;
+        for rotation in 0..copies.len() {
+            for reversed in [false, true] {
+                let mut order: Vec<&Envelope> = copies.iter().cycle().skip(rotation).take(copies.len()).collect();
+                if reversed {
+                    order.reverse();
+                }
+                let mut store = BTreeMap::new();
+                for incoming in order {
+                    merge_into(&mut store, incoming);
+                }
+                match &expected {
+                    None => expected = Some(store),
+                    Some(expected) => assert_eq!(&store, expected),
+                }
+            }
+        }
+
+        let expected = expected.unwrap();
+        assert_eq!(expected["m1"].content, MessageContent::Text { text: "edited again".to_string() });
+        assert_eq!(expected["m3"].content, MessageContent::Text { text: "only copy".to_string() });
+    }
+
+    #[test]
+    fn merging_two_devices_either_way_round_agrees() {
+        let a = [copy("m1", 10, "from a"), copy("m2", 3, "shared")];
+        let b = [copy("m1", 10, "from b"), copy("m3", 4, "only on b")];
+
+        let mut a_then_b = BTreeMap::new();
+        let mut b_then_a = BTreeMap::new();
+        for incoming in a.iter().chain(b.iter()) {
+            merge_into(&mut a_then_b, incoming);
+        }
+        for incoming in b.iter().chain(a.iter()) {
+            merge_into(&mut b_then_a, incoming);
+        }
+        assert_eq!(a_then_b, b_then_a);
+        assert_eq!(a_then_b.len(), 3);
+    }
+}
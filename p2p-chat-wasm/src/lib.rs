@@ -1,17 +1,73 @@
+mod config;
+mod crypto;
+mod envelope;
+mod events;
+mod frame;
+mod handlers;
+mod history;
+mod media;
+mod negotiation;
+mod ratchet;
+mod reconnect;
+mod replay;
+mod sender_key;
+mod session;
+mod signal_code;
+mod signaling;
+mod stats;
+mod sync;
+mod transfer;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+pub use history::HistoryStore;
+pub use session::ChatSession;
+pub use signaling::SignalingClient;
+pub use transfer::TransferStore;
+
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
 use base64::{decode, encode};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen::closure::WasmClosure;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{
-    console, RtcConfiguration, RtcDataChannelEvent, RtcDataChannelState,
-    RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection, RtcPeerConnectionIceEvent,
-    RtcSdpType, RtcSessionDescriptionInit, Request, RequestInit, RequestMode, Response,
+    console, MediaStream, RtcDataChannelEvent, RtcDataChannelState, RtcDataChannelType,
+    RtcIceCandidate, RtcIceCandidateInit, RtcIceGatheringState, RtcPeerConnection, RtcPeerConnectionIceEvent,
+    RtcSdpType, RtcSessionDescriptionInit, RtcSignalingState, Request, RequestInit, RequestMode, Response,
+};
+use zeroize::Zeroize;
+
+use crate::config::{rtc_configuration, IceConfig, IceTransportPolicy};
+use crate::crypto::{
+    derive_passphrase_key, extract_fingerprint, extract_fingerprints, key_check_value, key_commitment, verify_key_check, KeyExchange,
+    PassphraseParams,
+};
+use crate::envelope::{Envelope, GroupFrame, MessageContent};
+use crate::events::{
+    ice_connection_state_name, ice_gathering_state_name, signaling_state_name, CallStatus, ChatEvent,
+    ChatEventCallback, DataChannelStatus, ErrorSource, KeyStatus,
 };
+use crate::frame::{FrameContent, SealedFrame, BINARY_FRAME_VERSION};
+use crate::handlers::Handlers;
+use crate::media::{handle_remote_hang_up, stop_tracks, user_media, watch_tracks, ActiveCall, MediaState};
+use crate::negotiation::{is_offer_collision, is_polite, make_offer, rollback, watch_negotiation_needed, NegotiationState};
+use crate::ratchet::{Ratchet, RatchetHeader, RatchetMessage};
+use crate::reconnect::{sleep, watch_ice_connection, ReconnectState};
+use crate::replay::{ReplayCheck, ReplayWindow};
+use crate::stats::{collect_stats, sample_loop, StatsCallback};
+use crate::signal_code::DescriptionType;
+use crate::sync::{handle_sync, SyncState};
+use crate::transfer::{ChunkOutcome, OfferOutcome, OutgoingChunk, ReceivedFile, SharedTransfers, TransferProgress};
+
+// Plaintext of the first ratchet message, which lets the responder confirm the shared secret
+const KEY_CONFIRMATION: &[u8] = b"p2p-chat key confirmation";
 
 #[wasm_bindgen]
 extern "C" {
@@ -30,18 +86,152 @@ extern "C" {
     fn call1(this: &Function, thisArg: &JsValue, arg1: &JsValue) -> JsValue;
 }
 
+// Outgoing frames queue up once this many bytes are buffered on the data channel, and the
+// queue drains again when the buffer falls below the low-water mark
+const SEND_HIGH_WATER_MARK: u32 = 1024 * 1024;
+const SEND_LOW_WATER_MARK: u32 = 256 * 1024;
+// Sending fails instead of queueing without bound
+const MAX_QUEUED_FRAMES: usize = 4096;
+
+// How long a connection code waits for ICE gathering, checking every poll interval
+const ICE_GATHERING_TIMEOUT_MS: u32 = 10_000;
+const ICE_GATHERING_POLL_MS: u32 = 100;
+
+// Label for the associated data authenticated with every encrypted frame
+const FRAME_AAD_LABEL: &[u8] = b"p2p-chat frame v1";
+
+// Every encrypted frame carries the sender's session id and a per-session counter, both
+// authenticated as AES-GCM associated data so frames can't be replayed or moved between sessions
 #[derive(Serialize, Deserialize)]
 struct EncryptedMessage {
+    session_id: String,
+    counter: u64,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RatchetFrame {
+    session_id: String,
+    counter: u64,
+    header: String,
     nonce: String,
     ciphertext: String,
 }
 
+impl RatchetFrame {
+    fn new(session_id: &[u8; 16], counter: u64, message: &RatchetMessage) -> Self {
+        RatchetFrame {
+            session_id: encode(session_id),
+            counter,
+            header: encode(message.header.to_bytes()),
+            nonce: encode(message.nonce),
+            ciphertext: encode(&message.ciphertext),
+        }
+    }
+    
+    fn to_sealed(&self) -> Option<SealedFrame> {
+        let header = RatchetHeader::from_bytes(&decode(&self.header).ok()?).ok()?;
+        let nonce = decode(&self.nonce).ok()?.try_into().ok()?;
+        let ciphertext = decode(&self.ciphertext).ok()?;
+        Some(SealedFrame::Ratchet {
+            session_id: decode_session_id(&self.session_id)?,
+            counter: self.counter,
+            message: RatchetMessage {
+                header,
+                nonce,
+                ciphertext,
+            },
+        })
+    }
+}
+
+impl EncryptedMessage {
+    fn to_sealed(&self) -> Option<SealedFrame> {
+        Some(SealedFrame::Static {
+            session_id: decode_session_id(&self.session_id)?,
+            counter: self.counter,
+            nonce: decode(&self.nonce).ok()?.try_into().ok()?,
+            ciphertext: decode(&self.ciphertext).ok()?,
+        })
+    }
+}
+
+// JSON form of a data frame, for peers without binary framing
+fn json_frame(frame: &SealedFrame) -> ChannelFrame {
+    match frame {
+        SealedFrame::Ratchet { session_id, counter, message } => {
+            ChannelFrame::Ratchet(RatchetFrame::new(session_id, *counter, message))
+        }
+        SealedFrame::Static { session_id, counter, nonce, ciphertext } => {
+            ChannelFrame::Message(EncryptedMessage {
+                session_id: encode(session_id),
+                counter: *counter,
+                nonce: encode(nonce),
+                ciphertext: encode(ciphertext),
+            })
+        }
+    }
+}
+
+fn decode_session_id(session_id: &str) -> Option<[u8; 16]> {
+    decode(session_id).ok().and_then(|bytes| bytes.try_into().ok())
+}
+
+// Frames sent over the data channel
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum ChannelFrame {
+    // Hash of the public key we are about to reveal; the key itself follows once the peer's
+    // commitment is in
+    #[serde(rename = "key_commit")]
+    KeyCommit {
+        session_id: String,
+        commitment: String,
+    },
+    #[serde(rename = "key_exchange")]
+    KeyExchange {
+        public_key: String,
+        session_id: String,
+        // Highest binary frame version we understand; absent for peers that only speak JSON
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binary_frames: Option<u8>,
+    },
+    #[serde(rename = "key_confirm")]
+    KeyConfirm(RatchetFrame),
+    // Proof that the sender holds the same manually set key
+    #[serde(rename = "key_check")]
+    KeyCheck {
+        session_id: String,
+        check: String,
+    },
+    // Message under a manually set static key
+    #[serde(rename = "message")]
+    Message(EncryptedMessage),
+    // Message under the Double Ratchet session
+    #[serde(rename = "ratchet")]
+    Ratchet(RatchetFrame),
+}
+
+// Reported as an error event when an incoming frame is dropped
+struct FrameRejection {
+    reason: &'static str,
+    counter: Option<u64>,
+}
+
+impl FrameRejection {
+    fn new(reason: &'static str, counter: Option<u64>) -> Self {
+        FrameRejection { reason, counter }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct SessionDescription {
     sdp: String,
     type_: String,
 }
 
+// An empty candidate string marks the end of the sender's candidates
 #[derive(Serialize, Deserialize)]
 struct IceCandidate {
     candidate: String,
@@ -50,20 +240,149 @@ struct IceCandidate {
     username_fragment: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TurnConfig {
-    urls: Vec<String>,
-    username: String,
-    credential: String,
+// State shared between P2PChat and its event handlers
+struct ChatState {
+    // Static key, only used when set by hand with set_encryption_key or set_passphrase
+    encryption_key: [u8; 32],
+    manual_key: bool,
+    // Outcome of comparing key check values with the peer, and the peer's latest check value
+    key_confirmed: Option<bool>,
+    peer_key_check: Option<Vec<u8>>,
+    key_established: bool,
+    key_exchange: KeyExchange,
+    // The peer's key commitment, and whether we have revealed our public key in return
+    peer_key_commitment: Option<[u8; 32]>,
+    key_revealed: bool,
+    ratchet: Option<Ratchet>,
+    local_fingerprint: Option<String>,
+    remote_fingerprint: Option<String>,
+    authentication_string: Option<String>,
+    peer_verified: bool,
+    // Sender id stamped on outgoing message envelopes, and the one the peer's must carry when
+    // set with set_peer_sender_id
+    sender_id: String,
+    expected_peer_sender_id: Option<String>,
+    // Random id for our side of the conversation, and the one the peer announced
+    session_id: [u8; 16],
+    peer_session_id: Option<[u8; 16]>,
+    send_counter: u64,
+    replay_window: ReplayWindow,
+    on_message_callback: Option<js_sys::Function>,
+    on_event_callback: Option<js_sys::Function>,
+    // Shared with later connections to the same peer when set_transfer_store is used
+    transfers: SharedTransfers,
+    // History sync with another device of the same user
+    sync: SyncState,
+    // Audio and video call
+    media: MediaState,
+    // Serialized frames waiting for the data channel's buffer to drain
+    send_queue: VecDeque<OutgoingFrame>,
+    // Whether the peer accepts binary data frames
+    binary_frames: bool,
+    reconnect: ReconnectState,
+    negotiation: NegotiationState,
+    // Bumped to stop a running stats sampling loop
+    stats_generation: u32,
+    // Closures installed as handlers on the peer connection and data channel
+    handlers: Handlers,
+    // Set by close(); the connection can't be used again
+    closed: bool,
+    on_file_callback: Option<js_sys::Function>,
+    on_file_progress_callback: Option<js_sys::Function>,
+    on_group_frame_callback: Option<js_sys::Function>,
+}
+
+impl ChatState {
+    // Sender id the peer's envelopes must carry: the one set with set_peer_sender_id, or else the
+    // default, the peer's session id
+    fn peer_sender_id(&self) -> Option<String> {
+        self.expected_peer_sender_id.clone().or_else(|| self.peer_session_id.map(encode))
+    }
+    
+    fn next_counter(&mut self) -> u64 {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        counter
+    }
+    
+    // Associated data for a frame we send
+    fn outgoing_aad(&self, counter: u64) -> Result<Vec<u8>, JsValue> {
+        let peer_session_id = self.peer_session_id
+            .ok_or_else(|| JsValue::from_str("Peer session not known yet"))?;
+        Ok(frame_aad(&self.session_id, &peer_session_id, counter))
+    }
+    
+    // Check sender session and counter of an incoming frame, returning its associated data
+    fn incoming_aad(&self, session_id: &[u8; 16], counter: u64) -> Result<Vec<u8>, FrameRejection> {
+        if Some(*session_id) != self.peer_session_id {
+            return Err(FrameRejection::new("unknown_session", Some(counter)));
+        }
+        
+        match self.replay_window.check(counter) {
+            ReplayCheck::Fresh => Ok(frame_aad(session_id, &self.session_id, counter)),
+            ReplayCheck::Duplicate => Err(FrameRejection::new("duplicate", Some(counter))),
+            ReplayCheck::Stale => Err(FrameRejection::new("stale", Some(counter))),
+        }
+    }
+    
+    // Decrypt a data frame after the session and replay checks
+    fn open_frame(&mut self, frame: &SealedFrame, content: FrameContent) -> Result<Vec<u8>, FrameRejection> {
+        let counter = frame.counter();
+        let plaintext = match frame {
+            SealedFrame::Ratchet { session_id, message, .. } => {
+                let aad = content.bind(self.incoming_aad(session_id, counter)?);
+                let ratchet = self.ratchet.as_mut()
+                    .ok_or_else(|| FrameRejection::new("no_session", Some(counter)))?;
+                ratchet.decrypt(message, &aad)
+                    .map_err(|_| FrameRejection::new("decrypt_failed", Some(counter)))?
+            }
+            SealedFrame::Static { session_id, nonce, ciphertext, .. } => {
+                // Static frames are only valid once a key was set by hand
+                if !self.key_established || self.ratchet.is_some() {
+                    return Err(FrameRejection::new("no_session", Some(counter)));
+                }
+                let aad = content.bind(self.incoming_aad(session_id, counter)?);
+                decrypt_message(&self.encryption_key, nonce, ciphertext, &aad)
+                    .map_err(|_| FrameRejection::new("decrypt_failed", Some(counter)))?
+            }
+        };
+        
+        self.replay_window.accept(counter);
+        Ok(plaintext)
+    }
+}
+
+// Associated data binding a frame to both sessions and its counter
+fn frame_aad(sender_session_id: &[u8; 16], receiver_session_id: &[u8; 16], counter: u64) -> Vec<u8> {
+    [FRAME_AAD_LABEL, sender_session_id, receiver_session_id, &counter.to_be_bytes()].concat()
+}
+
+type SharedChatState = Rc<RefCell<ChatState>>;
+
+// A serialized frame, sent as a string (JSON) or an ArrayBuffer (binary framing)
+enum OutgoingFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl OutgoingFrame {
+    fn send(&self, channel: &web_sys::RtcDataChannel) -> Result<(), JsValue> {
+        match self {
+            OutgoingFrame::Text(text) => channel.send_with_str(text),
+            OutgoingFrame::Binary(bytes) => channel.send_with_u8_array(bytes),
+        }
+    }
 }
 
 #[wasm_bindgen]
 pub struct P2PChat {
     peer_connection: RtcPeerConnection,
-    data_channel: Option<web_sys::RtcDataChannel>,
-    encryption_key: [u8; 32],
-    on_message_callback: Option<js_sys::Function>,
-    on_connection_callback: Option<js_sys::Function>,
+    // Shared with the ondatachannel handler, which stores the channel the offerer opened
+    data_channel: Rc<RefCell<Option<web_sys::RtcDataChannel>>>,
+    // Remote candidates that arrived before the remote description was set
+    pending_candidates: RefCell<Vec<IceCandidate>>,
+    ice_config: IceConfig,
+    state: SharedChatState,
 }
 
 #[wasm_bindgen]
@@ -72,116 +391,134 @@ impl P2PChat {
     pub fn new(turn_config_js: JsValue) -> Result<P2PChat, JsValue> {
         console::log_1(&"Initializing P2P Chat...".into());
         
-        // Configure ICE servers (STUN/TURN)
-        let rtc_config = RtcConfiguration::new();
-        let ice_servers = js_sys::Array::new();
-        
-        // Add STUN server
-        let stun_server = js_sys::Object::new();
-        js_sys::Reflect::set(&stun_server, &"urls".into(), &"stun:stun.l.google.com:19302".into())?;
-        ice_servers.push(&stun_server);
-        
-        // Log that we're initializing with a custom TURN config or fallback
-        if !turn_config_js.is_null() && !turn_config_js.is_undefined() {
-            console::log_1(&"Using provided TURN configuration".into());
-        } else {
-            console::log_1(&"Using fallback TURN servers".into());
-        }
-        
-        // Add TURN servers if provided in config
-        if !turn_config_js.is_null() && !turn_config_js.is_undefined() {
-            if let Ok(turn_config) = serde_wasm_bindgen::from_value::<TurnConfig>(turn_config_js) {
-                for url in &turn_config.urls {
-                    let turn_server = js_sys::Object::new();
-                    js_sys::Reflect::set(&turn_server, &"urls".into(), &url.clone().into())?;
-                    js_sys::Reflect::set(&turn_server, &"username".into(), &turn_config.username.clone().into())?;
-                    js_sys::Reflect::set(&turn_server, &"credential".into(), &turn_config.credential.clone().into())?;
-                    ice_servers.push(&turn_server);
-                    
-                    console::log_1(&format!("Added TURN server: {}", url).into());
-                }
-            } else {
-                console::log_1(&"Invalid TURN configuration format".into());
-            }
-        } else {
-            // Use fallback TURN servers for public deployments
-            let turn_server1 = js_sys::Object::new();
-            js_sys::Reflect::set(&turn_server1, &"urls".into(), &"turn:relay.metered.ca:80".into())?;
-            js_sys::Reflect::set(&turn_server1, &"username".into(), &"openrelayproject".into())?;
-            js_sys::Reflect::set(&turn_server1, &"credential".into(), &"openrelayproject".into())?;
-            ice_servers.push(&turn_server1);
-            
-            let turn_server2 = js_sys::Object::new();
-            js_sys::Reflect::set(&turn_server2, &"urls".into(), &"turn:relay.metered.ca:443?transport=tcp".into())?;
-            js_sys::Reflect::set(&turn_server2, &"username".into(), &"openrelayproject".into())?;
-            js_sys::Reflect::set(&turn_server2, &"credential".into(), &"openrelayproject".into())?;
-            ice_servers.push(&turn_server2);
+        // ICE servers and policies; no third-party servers unless the config asks for them
+        let ice_config = IceConfig::from_js(&turn_config_js)?;
+        let rtc_config = rtc_configuration(&ice_config, &turn_config_js)?;
+        if ice_config.ice_transport_policy == IceTransportPolicy::Relay {
+            console::log_1(&"Relay-only mode: only TURN candidates will be used".into());
         }
         
-        rtc_config.set_ice_servers(&ice_servers);
-        
         // Create the peer connection
         let peer_connection = RtcPeerConnection::new_with_configuration(&rtc_config)?;
         
-        // Generate a random encryption key
+        // Random static key for manual key sharing; the key exchange uses a ratchet instead
         let mut encryption_key = [0u8; 32];
         rand::thread_rng().fill_bytes(&mut encryption_key);
         
-        Ok(P2PChat {
-            peer_connection,
-            data_channel: None,
+        let mut session_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut session_id);
+        
+        let state = Rc::new(RefCell::new(ChatState {
             encryption_key,
+            manual_key: false,
+            key_confirmed: None,
+            peer_key_check: None,
+            key_established: false,
+            key_exchange: KeyExchange::new(),
+            peer_key_commitment: None,
+            key_revealed: false,
+            ratchet: None,
+            local_fingerprint: None,
+            remote_fingerprint: None,
+            authentication_string: None,
+            peer_verified: false,
+            sender_id: encode(session_id),
+            expected_peer_sender_id: None,
+            session_id,
+            peer_session_id: None,
+            send_counter: 0,
+            replay_window: ReplayWindow::new(),
             on_message_callback: None,
-            on_connection_callback: None,
+            on_event_callback: None,
+            transfers: SharedTransfers::default(),
+            sync: SyncState::default(),
+            media: MediaState::default(),
+            send_queue: VecDeque::new(),
+            binary_frames: false,
+            reconnect: ReconnectState::default(),
+            negotiation: NegotiationState::default(),
+            stats_generation: 0,
+            handlers: Handlers::default(),
+            closed: false,
+            on_file_callback: None,
+            on_file_progress_callback: None,
+            on_group_frame_callback: None,
+        }));
+        
+        // Restart ICE automatically when the connection drops, and renegotiate when needed
+        watch_ice_connection(&peer_connection, &state);
+        watch_negotiation_needed(&peer_connection, &state);
+        watch_connection_states(&peer_connection, &state);
+        watch_tracks(&peer_connection, &state);
+        
+        Ok(P2PChat {
+            peer_connection,
+            data_channel: Rc::new(RefCell::new(None)),
+            pending_candidates: RefCell::new(Vec::new()),
+            ice_config,
+            state,
         })
     }
     
     // Set callback for incoming messages
     #[wasm_bindgen]
     pub fn on_message(&mut self, callback: js_sys::Function) {
-        self.on_message_callback = Some(callback);
+        self.state.borrow_mut().on_message_callback = Some(callback);
+    }
+    
+    // Set callback for received files
+    #[wasm_bindgen]
+    pub fn on_file(&mut self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_file_callback = Some(callback);
+    }
+    
+    // Set callback for file transfer progress, in both directions
+    #[wasm_bindgen]
+    pub fn on_file_progress(&mut self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_file_progress_callback = Some(callback);
     }
     
-    // Set callback for connection status
+    // Set callback for connection events: signaling, ICE and data channel state changes, local
+    // candidates and offers to deliver to the peer, key and reconnect progress, and errors
     #[wasm_bindgen]
-    pub fn on_connection(&mut self, callback: js_sys::Function) {
-        self.on_connection_callback = Some(callback);
+    pub fn on_event(&mut self, callback: ChatEventCallback) {
+        self.state.borrow_mut().on_event_callback = Some(callback.unchecked_into());
+    }
+    
+    // Set callback for the peer's audio and video tracks, called with { track, streams, kind }
+    #[wasm_bindgen]
+    pub fn on_track(&mut self, callback: js_sys::Function) {
+        self.state.borrow_mut().media.on_track_callback = Some(callback);
     }
     
     // Create offer as initiator
     #[wasm_bindgen]
-    pub async fn create_offer(&mut self) -> Result<JsValue, JsValue> {
+    pub async fn create_offer(&self) -> Result<JsValue, JsValue> {
+        self.ensure_open()?;
         console::log_1(&"Creating offer...".into());
         
-        // Configure ICE parameters for better connectivity
-        let ice_transport_policy = js_sys::Object::new();
-        
-        // Set ICE transport policy to "all" to use both relay and direct connections
-        js_sys::Reflect::set(&ice_transport_policy, &"iceTransportPolicy".into(), &"all".into())?;
-        
         // Create data channel with reliable transport
         let data_channel_init = js_sys::Object::new();
         js_sys::Reflect::set(&data_channel_init, &"ordered".into(), &JsValue::from_bool(true))?;
         
+        // The offerer is the side that restarts ICE if the connection drops
+        self.state.borrow_mut().reconnect.is_offerer = true;
+        
         // Create data channel - using the standard method since the one with dict isn't available
         let data_channel = self.peer_connection.create_data_channel("chat");
-        self.setup_data_channel(&data_channel);
-        self.data_channel = Some(data_channel);
-        
-        // Create offer using standard method since the one with options isn't available
-        let offer = JsFuture::from(self.peer_connection.create_offer()).await?;
-        let offer_sdp = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-        let sdp_str = js_sys::Reflect::get(&offer, &"sdp".into())?
-            .as_string()
-            .unwrap();
-        offer_sdp.set_sdp(&sdp_str);
-        
-        // Set local description
-        JsFuture::from(self.peer_connection.set_local_description(&offer_sdp)).await?;
+        if let Some(previous) = self.data_channel.borrow_mut().take() {
+            detach_data_channel(&previous);
+        }
+        setup_data_channel(&data_channel, &self.state);
+        *self.data_channel.borrow_mut() = Some(data_channel);
         
-        // Setup ICE candidate handling
+        // Setup ICE candidate handling before gathering starts
         self.setup_ice_candidate_handler();
         
+        // Create the offer and set it as the local description
+        let sdp_str = make_offer(&self.peer_connection, &self.state, false).await?;
+        self.state.borrow_mut().local_fingerprint = extract_fingerprint(&sdp_str);
+        
         // Convert to a serializable format
         let session_desc = SessionDescription {
             sdp: sdp_str,
@@ -194,96 +531,52 @@ impl P2PChat {
     
     // Accept offer as peer
     #[wasm_bindgen]
-    pub async fn accept_offer(&mut self, offer: JsValue) -> Result<JsValue, JsValue> {
+    pub async fn accept_offer(&self, offer: JsValue) -> Result<JsValue, JsValue> {
+        self.ensure_open()?;
         console::log_1(&"Accepting offer...".into());
         
         // Create a callback for data channel events
-        let encryption_key = self.encryption_key.clone();
-        let message_callback = self.on_message_callback.clone();
-        let connection_callback = self.on_connection_callback.clone();
+        let state = self.state.clone();
+        let channel_slot = self.data_channel.clone();
         
         // Create a static callback
         let ondatachannel_callback = Closure::wrap(Box::new(move |event: RtcDataChannelEvent| {
             let data_channel = event.channel();
+            // Handlers for a channel this one replaces are about to be dropped
+            if let Some(previous) = channel_slot.borrow_mut().take() {
+                detach_data_channel(&previous);
+            }
+            setup_data_channel(&data_channel, &state);
             
-            // Message handler (copied from setup_data_channel)
-            let msg_callback = message_callback.clone();
-            let enc_key = encryption_key.clone();
-            let onmessage_cb = Closure::wrap(Box::new(move |event: web_sys::MessageEvent| {
-                if let Some(text) = event.data().as_string() {
-                    if let Ok(message_obj) = serde_json::from_str::<EncryptedMessage>(&text) {
-                        if let (Ok(nonce_bytes), Ok(ciphertext)) = (decode(&message_obj.nonce), decode(&message_obj.ciphertext)) {
-                            let cipher = Aes256Gcm::new_from_slice(&enc_key).unwrap();
-                            let nonce = Nonce::from_slice(&nonce_bytes);
-                            
-                            if let Ok(plaintext) = cipher.decrypt(nonce, ciphertext.as_ref()) {
-                                if let Ok(decrypted_message) = String::from_utf8(plaintext) {
-                                    if let Some(ref callback) = msg_callback {
-                                        let this = JsValue::NULL;
-                                        let arg = JsValue::from_str(&decrypted_message);
-                                        let _ = callback.call1(&this, &arg);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }) as Box<dyn FnMut(web_sys::MessageEvent)>);
-            
-            // Open handler
-            let conn_cb = connection_callback.clone();
-            let onopen_cb = Closure::wrap(Box::new(move |_| {
-                if let Some(ref callback) = conn_cb {
-                    let this = JsValue::NULL;
-                    let arg = JsValue::from_str("open");
-                    let _ = callback.call1(&this, &arg);
-                }
-            }) as Box<dyn FnMut(web_sys::Event)>);
-            
-            // Close handler
-            let conn_cb_close = connection_callback.clone();
-            let onclose_cb = Closure::wrap(Box::new(move |_| {
-                if let Some(ref callback) = conn_cb_close {
-                    let this = JsValue::NULL;
-                    let arg = JsValue::from_str("closed");
-                    let _ = callback.call1(&this, &arg);
-                }
-            }) as Box<dyn FnMut(web_sys::Event)>);
-            
-            data_channel.set_onmessage(Some(onmessage_cb.as_ref().unchecked_ref()));
-            data_channel.set_onopen(Some(onopen_cb.as_ref().unchecked_ref()));
-            data_channel.set_onclose(Some(onclose_cb.as_ref().unchecked_ref()));
-            
-            onmessage_cb.forget();
-            onopen_cb.forget();
-            onclose_cb.forget();
+            // The channel may already be open when it is announced
+            if data_channel.ready_state() == RtcDataChannelState::Open {
+                send_key_commit(&data_channel, &state);
+            }
             
-            // Store the data channel on the global window object for future use
-            let window = web_sys::window().expect("Should have a window");
-            js_sys::Reflect::set(
-                &window,
-                &JsValue::from_str("__wasmDataChannel"),
-                &data_channel
-            ).expect("Should be able to set global variable");
+            // Keep the channel on the instance so the answerer can send too
+            *channel_slot.borrow_mut() = Some(data_channel.clone());
             
-            // Notify connection opened
-            if let Some(ref callback) = connection_callback {
-                let this = JsValue::NULL;
-                let arg = JsValue::from_str("channel_received");
-                let _ = callback.call1(&this, &arg);
-            }
+            notify_event(&state, &ChatEvent::DataChannel {
+                state: DataChannelStatus::Received,
+                label: &data_channel.label(),
+            });
         }) as Box<dyn FnMut(RtcDataChannelEvent)>);
         
         self.peer_connection.set_ondatachannel(Some(ondatachannel_callback.as_ref().unchecked_ref()));
-        ondatachannel_callback.forget();
+        let mut handlers = Handlers::default();
+        handlers.keep(ondatachannel_callback);
+        let replaced = self.state.borrow_mut().handlers.replace("ondatachannel", handlers);
+        drop(replaced);
         
         // Parse the offer and create RtcSessionDescriptionInit
         let offer_data: SessionDescription = serde_wasm_bindgen::from_value(offer)?;
+        self.check_pinned_fingerprint(&offer_data.sdp)?;
         let offer_sdp = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
         offer_sdp.set_sdp(&offer_data.sdp);
         
         // Set remote description
         JsFuture::from(self.peer_connection.set_remote_description(&offer_sdp)).await?;
+        self.state.borrow_mut().remote_fingerprint = extract_fingerprint(&offer_data.sdp);
         
         // Create answer
         let answer = JsFuture::from(self.peer_connection.create_answer()).await?;
@@ -293,11 +586,16 @@ impl P2PChat {
             .unwrap();
         answer_sdp.set_sdp(&sdp_str);
         
+        // Setup ICE candidate handling before gathering starts
+        self.setup_ice_candidate_handler();
+        
         // Set local description
         JsFuture::from(self.peer_connection.set_local_description(&answer_sdp)).await?;
+        self.state.borrow_mut().local_fingerprint = extract_fingerprint(&sdp_str);
         
-        // Setup ICE candidate handling
-        self.setup_ice_candidate_handler();
+        // Candidates that raced ahead of the offer can be added now
+        self.flush_pending_candidates().await;
+        self.state.borrow_mut().negotiation.established = true;
         
         // Convert to a serializable format
         let session_desc = SessionDescription {
@@ -312,244 +610,1504 @@ impl P2PChat {
     // Complete connection with answer from peer
     #[wasm_bindgen]
     pub async fn complete_connection(&self, answer: JsValue) -> Result<(), JsValue> {
+        self.ensure_open()?;
         console::log_1(&"Completing connection...".into());
         
+        // An answer to an offer we rolled back after a collision is stale
+        if self.peer_connection.signaling_state() != RtcSignalingState::HaveLocalOffer {
+            console::log_1(&"Ignoring answer without a pending offer".into());
+            return Ok(());
+        }
+        
         // Parse the answer and create RtcSessionDescriptionInit
         let answer_data: SessionDescription = serde_wasm_bindgen::from_value(answer)?;
+        self.check_pinned_fingerprint(&answer_data.sdp)?;
         let answer_sdp = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
         answer_sdp.set_sdp(&answer_data.sdp);
         
         // Set remote description
         JsFuture::from(self.peer_connection.set_remote_description(&answer_sdp)).await?;
+        self.state.borrow_mut().remote_fingerprint = extract_fingerprint(&answer_data.sdp);
+        
+        // Candidates that raced ahead of the answer can be added now
+        self.flush_pending_candidates().await;
+        self.state.borrow_mut().negotiation.established = true;
         
         Ok(())
     }
     
-    // Add ICE candidate received from peer
+    // Serverless signaling: create an offer and return it as a compact code for the peer's
+    // accept_offer_code, once every candidate is gathered. Codes are swapped by hand, so later
+    // renegotiations (calls, ICE restarts) still need a signaling channel.
     #[wasm_bindgen]
-    pub async fn add_ice_candidate(&self, candidate: JsValue) -> Result<(), JsValue> {
-        let candidate_data: IceCandidate = serde_wasm_bindgen::from_value(candidate)?;
-        
-        let candidate_init = RtcIceCandidateInit::new(&candidate_data.candidate);
+    pub async fn create_offer_code(&self) -> Result<String, JsValue> {
+        self.create_offer().await?;
+        self.local_description_code(DescriptionType::Offer).await
+    }
+    
+    // Accept an offer code from create_offer_code, returning the answer code to send back
+    #[wasm_bindgen]
+    pub async fn accept_offer_code(&self, code: String) -> Result<String, JsValue> {
+        let offer = decode_description(&code, DescriptionType::Offer)?;
+        self.accept_offer(serde_wasm_bindgen::to_value(&offer)?).await?;
+        self.local_description_code(DescriptionType::Answer).await
+    }
+    
+    // Complete the connection with the answer code from accept_offer_code
+    #[wasm_bindgen]
+    pub async fn complete_connection_code(&self, code: String) -> Result<(), JsValue> {
+        let answer = decode_description(&code, DescriptionType::Answer)?;
+        self.complete_connection(serde_wasm_bindgen::to_value(&answer)?).await
+    }
+    
+    // Derive the perfect-negotiation role from both user ids: the polite side yields when both
+    // ends send offers at the same time
+    #[wasm_bindgen]
+    pub fn set_negotiation_role(&self, local_user_id: String, remote_user_id: String) {
+        self.state.borrow_mut().negotiation.polite = is_polite(&local_user_id, &remote_user_id);
+    }
+    
+    // Handle an offer from the peer we're connected to or connecting with: an ICE restart
+    // ("restart_offer"), a renegotiation ("negotiation_offer"), or an offer that crossed ours.
+    // Returns the answer for the peer's complete_connection, or undefined if the offer collided
+    // with ours and we're the impolite side.
+    #[wasm_bindgen]
+    pub async fn handle_remote_offer(&self, offer: JsValue) -> Result<JsValue, JsValue> {
+        self.ensure_open()?;
+        let offer_data: SessionDescription = serde_wasm_bindgen::from_value(offer.clone())?;
+        if !self.is_offer_from_current_peer(&offer_data.sdp) {
+            return Err(JsValue::from_str("Offer is for a different DTLS session"));
+        }
         
-        if let Some(sdp_mid) = candidate_data.sdp_mid {
-            candidate_init.set_sdp_mid(Some(&sdp_mid));
+        if is_offer_collision(&self.peer_connection, &self.state) {
+            if !self.state.borrow().negotiation.polite {
+                console::log_1(&"Ignoring colliding offer".into());
+                return Ok(JsValue::UNDEFINED);
+            }
+            rollback(&self.peer_connection).await?;
         }
         
-        if let Some(sdp_m_line_index) = candidate_data.sdp_m_line_index {
-            candidate_init.set_sdp_m_line_index(Some(sdp_m_line_index));
+        // Our first offer lost the collision: drop its unopened channel and answer like a fresh peer
+        if self.peer_connection.remote_description().is_none() {
+            if let Some(channel) = self.data_channel.borrow_mut().take() {
+                detach_data_channel(&channel);
+                channel.close();
+            }
+            self.state.borrow_mut().reconnect.is_offerer = false;
+            return self.accept_offer(offer).await;
         }
         
-        // Not all browsers support username_fragment
-        // So we'll just ignore it if present
+        let offer_sdp = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+        offer_sdp.set_sdp(&offer_data.sdp);
+        JsFuture::from(self.peer_connection.set_remote_description(&offer_sdp)).await?;
         
-        let rtc_candidate = RtcIceCandidate::new(&candidate_init)?;
-        JsFuture::from(self.peer_connection.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&rtc_candidate))).await?;
+        let answer = JsFuture::from(self.peer_connection.create_answer()).await?;
+        let answer_sdp = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+        let sdp_str = js_sys::Reflect::get(&answer, &"sdp".into())?
+            .as_string()
+            .ok_or_else(|| JsValue::from_str("Answer without SDP"))?;
+        answer_sdp.set_sdp(&sdp_str);
+        JsFuture::from(self.peer_connection.set_local_description(&answer_sdp)).await?;
+        self.flush_pending_candidates().await;
         
-        Ok(())
+        let session_desc = SessionDescription {
+            sdp: sdp_str,
+            type_: "answer".to_string(),
+        };
+        Ok(serde_wasm_bindgen::to_value(&session_desc)?)
     }
     
-    // Send encrypted message
-    #[wasm_bindgen]
-    pub fn send_message(&self, message: String) -> Result<(), JsValue> {
-        if let Some(ref channel) = self.data_channel {
-            if channel.ready_state() == RtcDataChannelState::Open {
-                // Encrypt the message
-                let (encrypted_message, nonce) = self.encrypt_message(&message)?;
-                
-                // Create the message object
-                let message_obj = EncryptedMessage {
-                    nonce: encode(&nonce),
-                    ciphertext: encode(&encrypted_message),
-                };
-                
-                // Serialize to JSON
-                let json = serde_json::to_string(&message_obj).map_err(|e| JsValue::from_str(&e.to_string()))?;
-                
-                // Send through data channel
-                channel.send_with_str(&json)?;
-                return Ok(());
-            }
+    // Whether our local offer is still waiting for an answer
+    pub(crate) fn has_pending_offer(&self) -> bool {
+        self.peer_connection.signaling_state() == RtcSignalingState::HaveLocalOffer
+    }
+    
+    // Whether an offer belongs to this connection (same DTLS certificate as the peer we know, or
+    // we haven't heard back from the peer yet) rather than a brand-new connection
+    pub(crate) fn is_offer_from_current_peer(&self, sdp: &str) -> bool {
+        let remote_fingerprint = self.state.borrow().remote_fingerprint.clone();
+        match remote_fingerprint {
+            Some(remote_fingerprint) => extract_fingerprint(sdp).as_deref() == Some(remote_fingerprint.as_str()),
+            None => true,
         }
-        
-        Err(JsValue::from_str("Data channel not open"))
     }
     
-    // Set encryption key from string (base64-encoded)
+    // Add ICE candidate received from peer
     #[wasm_bindgen]
-    pub fn set_encryption_key(&mut self, key_base64: String) -> Result<(), JsValue> {
-        let key_bytes = decode(&key_base64).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    pub async fn add_ice_candidate(&self, candidate: JsValue) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let candidate_data: IceCandidate = serde_wasm_bindgen::from_value(candidate)?;
         
-        if key_bytes.len() != 32 {
-            return Err(JsValue::from_str("Invalid key length, must be 32 bytes"));
+        // The browser rejects candidates until the remote description is set, so hold early ones
+        if self.peer_connection.remote_description().is_none() {
+            self.pending_candidates.borrow_mut().push(candidate_data);
+            return Ok(());
         }
         
-        self.encryption_key.copy_from_slice(&key_bytes);
+        self.apply_ice_candidate(candidate_data).await;
         Ok(())
     }
     
-    // Get current encryption key as base64
+    // Add a remote candidate; failures are reported per candidate as error events so one bad
+    // candidate doesn't fail the connection
+    async fn apply_ice_candidate(&self, candidate_data: IceCandidate) {
+        let result = if candidate_data.candidate.is_empty() {
+            // End of candidates
+            JsFuture::from(self.peer_connection.add_ice_candidate_with_opt_rtc_ice_candidate(None)).await
+        } else {
+            let candidate_init = RtcIceCandidateInit::new(&candidate_data.candidate);
+            
+            if let Some(ref sdp_mid) = candidate_data.sdp_mid {
+                candidate_init.set_sdp_mid(Some(sdp_mid));
+            }
+            
+            if let Some(sdp_m_line_index) = candidate_data.sdp_m_line_index {
+                candidate_init.set_sdp_m_line_index(Some(sdp_m_line_index));
+            }
+            
+            // Not all browsers support username_fragment
+            // So we'll just ignore it if present
+            
+            match RtcIceCandidate::new(&candidate_init) {
+                Ok(rtc_candidate) => {
+                    JsFuture::from(self.peer_connection.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&rtc_candidate))).await
+                }
+                Err(e) => Err(e),
+            }
+        };
+        
+        if let Err(e) = result {
+            let error = js_sys::Reflect::get(&e, &"message".into())
+                .ok()
+                .and_then(|message| message.as_string())
+                .or_else(|| e.as_string())
+                .unwrap_or_else(|| "addIceCandidate failed".to_string());
+            console::log_1(&format!("Rejected ICE candidate {:?}: {}", candidate_data.candidate, error).into());
+            
+            notify_event(&self.state, &ChatEvent::Error {
+                source: ErrorSource::IceCandidate,
+                reason: &error,
+                counter: None,
+                candidate: Some(&candidate_data.candidate),
+            });
+        }
+    }
+    
+    // Add the candidates that arrived before the remote description
+    async fn flush_pending_candidates(&self) {
+        let pending = std::mem::take(&mut *self.pending_candidates.borrow_mut());
+        for candidate_data in pending {
+            self.apply_ice_candidate(candidate_data).await;
+        }
+    }
+    
+    // Send an encrypted text message, returning its message id
     #[wasm_bindgen]
-    pub fn get_encryption_key(&self) -> String {
-        encode(&self.encryption_key)
+    pub fn send_message(&self, message: String) -> Result<String, JsValue> {
+        let envelope = self.send_envelope(MessageContent::Text { text: message })?;
+        Ok(envelope.id)
     }
     
-    // Get current connection state
+    // Send a text, typing, receipt, edit, delete, reaction or control message, e.g.
+    // { kind: "typing", active: true } or
+    // { kind: "reaction", message_id, reaction: "👍" }, returning the full envelope that was sent
     #[wasm_bindgen]
-    pub fn get_connection_state(&self) -> Result<String, JsValue> {
-        // Get the signaling state as a string
-        let state = match self.peer_connection.signaling_state() {
-            web_sys::RtcSignalingState::Stable => "stable",
-            web_sys::RtcSignalingState::HaveLocalOffer => "have-local-offer",
-            web_sys::RtcSignalingState::HaveRemoteOffer => "have-remote-offer",
-            web_sys::RtcSignalingState::HaveLocalPranswer => "have-local-pranswer",
-            web_sys::RtcSignalingState::HaveRemotePranswer => "have-remote-pranswer",
-            web_sys::RtcSignalingState::Closed => "closed",
-            _ => "unknown"
-        };
-        
-        Ok(state.to_string())
+    pub fn send_content(&self, content: JsValue) -> Result<JsValue, JsValue> {
+        let content: MessageContent = serde_wasm_bindgen::from_value(content)?;
+        if !content.is_user_content() {
+            return Err(JsValue::from_str("This message kind can't be sent with send_content"));
+        }
+        let envelope = self.send_envelope(content)?;
+        envelope_to_js(&envelope)
     }
     
-    // Get WebRTC ICE connection state
+    // Identify ourselves in outgoing envelopes; defaults to the random session id
     #[wasm_bindgen]
-    pub fn get_ice_connection_state(&self) -> String {
-        // Convert RtcIceConnectionState to a string manually
-        match self.peer_connection.ice_connection_state() {
-            web_sys::RtcIceConnectionState::New => "new".to_string(),
-            web_sys::RtcIceConnectionState::Checking => "checking".to_string(),
-            web_sys::RtcIceConnectionState::Connected => "connected".to_string(),
-            web_sys::RtcIceConnectionState::Completed => "completed".to_string(),
-            web_sys::RtcIceConnectionState::Failed => "failed".to_string(),
-            web_sys::RtcIceConnectionState::Disconnected => "disconnected".to_string(),
-            web_sys::RtcIceConnectionState::Closed => "closed".to_string(),
-            _ => "unknown".to_string()
+    pub fn set_sender_id(&mut self, sender_id: String) {
+        self.state.borrow_mut().sender_id = sender_id;
+    }
+    
+    // The sender id the peer identifies itself with (its set_sender_id); envelopes carrying any
+    // other sender are dropped. Defaults to the peer's random session id.
+    #[wasm_bindgen]
+    pub fn set_peer_sender_id(&mut self, sender_id: String) {
+        self.state.borrow_mut().expected_peer_sender_id = Some(sender_id);
+    }
+    
+    // Wrap content in an envelope, encrypt it and send it over the data channel
+    fn send_envelope(&self, content: MessageContent) -> Result<Envelope, JsValue> {
+        self.ensure_open()?;
+        match *self.data_channel.borrow() {
+            Some(ref channel) => send_content(channel, &self.state, content),
+            None => Err(JsValue::from_str("Data channel not open")),
         }
     }
     
-    // Get WebRTC ICE gathering state
+    // Send a file in encrypted chunks, returning the transfer id. Progress is reported through
+    // on_file_progress; the transfer picks up from the last acknowledged chunk after a reconnect.
     #[wasm_bindgen]
-    pub fn get_ice_gathering_state(&self) -> String {
-        // For ice_gathering_state, we'll use a fallback approach
-        // since the method isn't directly available in the current web_sys version
-        "unknown".to_string()
-    }
-    
-    // Setup data channel handlers
-    fn setup_data_channel(&self, channel: &web_sys::RtcDataChannel) {
-        let callback_clone = self.on_message_callback.clone();
-        let encryption_key = self.encryption_key.clone();
-        
-        // Message handler
-        let onmessage_callback = Closure::wrap(Box::new(move |event: web_sys::MessageEvent| {
-            if let Some(text) = event.data().as_string() {
-                // Parse the message
-                if let Ok(message_obj) = serde_json::from_str::<EncryptedMessage>(&text) {
-                    // Decode base64
-                    if let (Ok(nonce_bytes), Ok(ciphertext)) = (decode(&message_obj.nonce), decode(&message_obj.ciphertext)) {
-                        // Decrypt the message
-                        let cipher = Aes256Gcm::new_from_slice(&encryption_key).unwrap();
-                        let nonce = Nonce::from_slice(&nonce_bytes);
-                        
-                        if let Ok(plaintext) = cipher.decrypt(nonce, ciphertext.as_ref()) {
-                            // Convert bytes to string
-                            if let Ok(decrypted_message) = String::from_utf8(plaintext) {
-                                // Call the JavaScript callback
-                                if let Some(ref callback) = callback_clone {
-                                    let this = JsValue::NULL;
-                                    let arg = JsValue::from_str(&decrypted_message);
-                                    let _ = callback.call1(&this, &arg);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }) as Box<dyn FnMut(web_sys::MessageEvent)>);
-        
-        // Connection open handler
-        let conn_callback = self.on_connection_callback.clone();
-        let onopen_callback = Closure::wrap(Box::new(move |_| {
-            if let Some(ref callback) = conn_callback {
-                let this = JsValue::NULL;
-                let arg = JsValue::from_str("open");
-                let _ = callback.call1(&this, &arg);
-            }
-        }) as Box<dyn FnMut(web_sys::Event)>);
-        
-        // Connection close handler
-        let conn_callback_close = self.on_connection_callback.clone();
-        let onclose_callback = Closure::wrap(Box::new(move |_| {
-            if let Some(ref callback) = conn_callback_close {
-                let this = JsValue::NULL;
-                let arg = JsValue::from_str("closed");
-                let _ = callback.call1(&this, &arg);
+    pub fn send_file(&self, name: String, data: Vec<u8>) -> Result<String, JsValue> {
+        {
+            let state = self.state.borrow();
+            if state.key_established && !state.binary_frames {
+                return Err(JsValue::from_str("Peer does not support binary frames, which file transfer needs"));
             }
-        }) as Box<dyn FnMut(web_sys::Event)>);
-        
-        channel.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-        channel.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
-        channel.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        }
+        let offer = self.state.borrow().transfers.borrow_mut().start_send(name, data);
+        let transfer_id = offer.transfer_id.clone();
         
-        onmessage_callback.forget();
-        onopen_callback.forget();
-        onclose_callback.forget();
+        if let Err(e) = self.send_envelope(MessageContent::FileOffer(offer)) {
+            self.state.borrow().transfers.borrow_mut().cancel_send(&transfer_id);
+            return Err(e);
+        }
+        Ok(transfer_id)
     }
     
-    // Setup ICE candidate handling
-    fn setup_ice_candidate_handler(&self) {
-        let connection_clone = self.on_connection_callback.clone();
-        
-        let onicecandidate_callback = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
-            if let Some(candidate) = event.candidate() {
-                // Extract necessary properties from the candidate
-                if let (Ok(candidate_str), Ok(sdp_mid), Ok(line_index)) = (
-                    js_sys::Reflect::get(&candidate, &"candidate".into()),
-                    js_sys::Reflect::get(&candidate, &"sdpMid".into()),
-                    js_sys::Reflect::get(&candidate, &"sdpMLineIndex".into()),
-                ) {
-                    let candidate_data = IceCandidate {
-                        candidate: candidate_str.as_string().unwrap_or_default(),
-                        sdp_mid: sdp_mid.as_string(),
-                        sdp_m_line_index: line_index.as_f64().map(|n| n as u16),
-                        username_fragment: None,
-                    };
-                    
-                    // Notify about the new ICE candidate
-                    if let Some(ref callback) = connection_clone {
-                        if let Ok(json) = serde_json::to_string(&candidate_data) {
-                            let this = JsValue::NULL;
-                            let args = js_sys::Array::new();
-                            args.push(&JsValue::from_str("ice_candidate"));
-                            args.push(&JsValue::from_str(&json));
-                            let _ = callback.apply(&this, &args);
-                        }
-                    }
-                }
-            }
-        }) as Box<dyn FnMut(RtcPeerConnectionIceEvent)>);
-        
-        self.peer_connection.set_onicecandidate(Some(onicecandidate_callback.as_ref().unchecked_ref()));
-        onicecandidate_callback.forget();
+    // Keep file transfers in a store that outlives this connection; set it before sending files.
+    // Attach the same store to the next P2PChat for this peer and unfinished transfers resume
+    // from the last acknowledged chunk once its key exchange completes.
+    #[wasm_bindgen]
+    pub fn set_transfer_store(&self, store: &TransferStore) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        self.state.borrow_mut().transfers = store.transfers();
+        Ok(())
+    }
+    
+    // Sync message history with another device of the same user. Both sides attach their
+    // HistoryStore this way; each then sends what the other is missing, and progress is reported
+    // as history_sync events.
+    #[wasm_bindgen]
+    pub fn sync_history(&self, history: &HistoryStore) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        match *self.data_channel.borrow() {
+            Some(ref channel) => sync::start(channel, &self.state, history.database()),
+            None => Err(JsValue::from_str("Data channel not open")),
+        }
     }
     
-    // Encrypt message
-    fn encrypt_message(&self, message: &str) -> Result<(Vec<u8>, Vec<u8>), JsValue> {
-        // Create cipher
-        let cipher = Aes256Gcm::new_from_slice(&self.encryption_key)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    // Start a call with the microphone and/or camera, returning the local stream for a preview.
+    // The tracks renegotiate the existing connection; the peer gets them through on_track and
+    // answers by starting a call of its own.
+    #[wasm_bindgen]
+    pub async fn start_call(&self, audio: bool, video: bool) -> Result<MediaStream, JsValue> {
+        self.ensure_channel_open()?;
+        if self.state.borrow().media.call.is_some() {
+            return Err(JsValue::from_str("Call already in progress"));
+        }
         
-        // Generate random nonce
-        let mut nonce_bytes = [0u8; 12];
-        rand::thread_rng().fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+        let stream = user_media(audio, video).await?;
         
-        // Encrypt
-        let ciphertext = cipher.encrypt(nonce, message.as_bytes())
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        // The chat may have closed, or another call started, while the browser asked for permission
+        let mut state = self.state.borrow_mut();
+        if state.closed || state.media.call.is_some() {
+            stop_tracks(&stream);
+            return Err(JsValue::from_str("Call no longer possible"));
+        }
+        state.media.call = Some(ActiveCall::start(&self.peer_connection, stream.clone()));
+        drop(state);
         
-        Ok((ciphertext, nonce_bytes.to_vec()))
+        notify_event(&self.state, &ChatEvent::Call { state: CallStatus::Started });
+        Ok(stream)
     }
+    
+    // Mute or unmute our "audio" or "video" tracks; muted tracks keep flowing, as silence or black
+    #[wasm_bindgen]
+    pub fn set_muted(&self, kind: String, muted: bool) -> Result<(), JsValue> {
+        match self.state.borrow().media.call {
+            Some(ref call) => call.set_muted(&kind, muted),
+            None => Err(JsValue::from_str("No call in progress")),
+        }
+    }
+    
+    #[wasm_bindgen]
+    pub fn is_muted(&self, kind: String) -> bool {
+        self.state.borrow().media.call.as_ref().is_some_and(|call| call.is_muted(&kind))
+    }
+    
+    // Share a screen or window in place of the camera, returning the screen stream. The outgoing
+    // video track is swapped without renegotiating, so the call needs video; the camera comes
+    // back when sharing stops, whether through stop_screen_share or the browser's own control.
+    #[wasm_bindgen]
+    pub async fn start_screen_share(&self) -> Result<MediaStream, JsValue> {
+        self.ensure_open()?;
+        media::start_screen_share(&self.state).await
+    }
+    
+    #[wasm_bindgen]
+    pub async fn stop_screen_share(&self) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        media::stop_screen_share(&self.state).await
+    }
+    
+    #[wasm_bindgen]
+    pub fn is_screen_sharing(&self) -> bool {
+        self.state.borrow().media.call.as_ref().is_some_and(|call| call.is_screen_sharing())
+    }
+    
+    // Our camera and microphone stream while a call is in progress
+    #[wasm_bindgen]
+    pub fn get_local_stream(&self) -> Option<MediaStream> {
+        self.state.borrow().media.call.as_ref().map(|call| call.stream().clone())
+    }
+    
+    // End the call: release the camera and microphone, take our tracks off the connection and
+    // tell the peer, which ends its side too. The chat itself stays connected.
+    #[wasm_bindgen]
+    pub fn hang_up(&self) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let call = self.state.borrow_mut().media.call.take();
+        let Some(call) = call else {
+            return Err(JsValue::from_str("No call in progress"));
+        };
+        call.end();
+        
+        let sent = self.send_envelope(MessageContent::CallEnded).map(|_| ());
+        notify_event(&self.state, &ChatEvent::Call { state: CallStatus::Ended });
+        sent
+    }
+    
+    // Set encryption key from string (base64-encoded), overriding the key exchange
+    #[wasm_bindgen]
+    pub fn set_encryption_key(&mut self, key_base64: String) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let key_bytes = decode(&key_base64).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        
+        if key_bytes.len() != 32 {
+            return Err(JsValue::from_str("Invalid key length, must be 32 bytes"));
+        }
+        
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&key_bytes);
+        self.install_manual_key(key);
+        Ok(())
+    }
+    
+    // Derive the encryption key from a shared passphrase with Argon2id. The salt should be
+    // something both peers agree on, such as the room name, and at least 8 bytes long. Cost
+    // parameters left undefined use the defaults (19 MiB, 2 iterations, 1 lane).
+    #[wasm_bindgen]
+    pub fn set_passphrase(
+        &mut self,
+        passphrase: String,
+        salt: String,
+        memory_kib: Option<u32>,
+        iterations: Option<u32>,
+        parallelism: Option<u32>,
+    ) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        let params = PassphraseParams {
+            memory_kib,
+            iterations,
+            parallelism,
+        };
+        let key = derive_passphrase_key(&passphrase, &salt, params)?;
+        self.install_manual_key(key);
+        Ok(())
+    }
+    
+    // Number of outgoing frames waiting for the data channel's buffer to drain
+    #[wasm_bindgen]
+    pub fn get_queue_depth(&self) -> u32 {
+        self.state.borrow().send_queue.len() as u32
+    }
+    
+    // Bytes the browser has buffered on the data channel but not yet sent
+    #[wasm_bindgen]
+    pub fn get_buffered_amount(&self) -> u32 {
+        self.data_channel.borrow().as_ref().map_or(0, |channel| channel.buffered_amount())
+    }
+    
+    // Whether the peer proved it holds the same manual key: true, false, or undefined while unknown
+    #[wasm_bindgen]
+    pub fn is_key_confirmed(&self) -> Option<bool> {
+        self.state.borrow().key_confirmed
+    }
+    
+    // Get the static encryption key as base64; ratchet keys never leave the session
+    #[wasm_bindgen]
+    pub fn get_encryption_key(&self) -> String {
+        encode(self.state.borrow().encryption_key)
+    }
+    
+    // Whether both peers share an encryption key yet
+    #[wasm_bindgen]
+    pub fn is_key_established(&self) -> bool {
+        self.state.borrow().key_established
+    }
+    
+    // Short authentication string to compare with the peer out of band, once the key exchange is done
+    #[wasm_bindgen]
+    pub fn get_authentication_string(&self) -> Option<String> {
+        self.state.borrow().authentication_string.clone()
+    }
+    
+    // Record that the users compared authentication strings and they matched
+    #[wasm_bindgen]
+    pub fn mark_peer_verified(&self) -> Result<(), JsValue> {
+        let mut state = self.state.borrow_mut();
+        if state.authentication_string.is_none() {
+            return Err(JsValue::from_str("Key exchange not complete"));
+        }
+        
+        state.peer_verified = true;
+        Ok(())
+    }
+    
+    // Whether the peer has been verified via the authentication string
+    #[wasm_bindgen]
+    pub fn is_peer_verified(&self) -> bool {
+        self.state.borrow().peer_verified
+    }
+    
+    // Get current connection state
+    #[wasm_bindgen]
+    pub fn get_connection_state(&self) -> Result<String, JsValue> {
+        Ok(signaling_state_name(self.peer_connection.signaling_state()).to_string())
+    }
+    
+    // Get WebRTC ICE connection state
+    #[wasm_bindgen]
+    pub fn get_ice_connection_state(&self) -> String {
+        ice_connection_state_name(self.peer_connection.ice_connection_state()).to_string()
+    }
+    
+    // Summary of the connection's current stats: selected candidate pair types, whether the
+    // traffic is relayed, round-trip time, transport protocol and data channel byte counts
+    #[wasm_bindgen(unchecked_return_type = "StatsSummary")]
+    pub async fn get_stats(&self) -> Result<JsValue, JsValue> {
+        let summary = collect_stats(&self.peer_connection).await?;
+        Ok(serde_wasm_bindgen::to_value(&summary)?)
+    }
+    
+    // Pass a stats summary to the callback every interval_ms, replacing any earlier sampling
+    #[wasm_bindgen]
+    pub fn start_stats_sampling(&self, interval_ms: u32, callback: StatsCallback) {
+        let generation = {
+            let mut state = self.state.borrow_mut();
+            state.stats_generation += 1;
+            state.stats_generation
+        };
+        spawn_local(sample_loop(
+            self.peer_connection.clone(),
+            self.state.clone(),
+            generation,
+            interval_ms,
+            callback.unchecked_into(),
+        ));
+    }
+    
+    #[wasm_bindgen]
+    pub fn stop_stats_sampling(&self) {
+        self.state.borrow_mut().stats_generation += 1;
+    }
+    
+    // Get WebRTC ICE gathering state
+    #[wasm_bindgen]
+    pub fn get_ice_gathering_state(&self) -> String {
+        ice_gathering_state_name(self.peer_connection.ice_gathering_state()).to_string()
+    }
+    
+    // Close the connection for good: detach every handler, close the data channel and peer
+    // connection, and wipe the key material. Every later call fails with "Chat is closed".
+    #[wasm_bindgen]
+    pub fn close(&self) {
+        let (call, handlers) = {
+            let mut state = self.state.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            
+            // Stop the reconnect and stats loops
+            state.reconnect.cancel();
+            state.stats_generation += 1;
+            
+            state.encryption_key.zeroize();
+            state.ratchet = None;
+            state.key_exchange.discard();
+            state.peer_key_commitment = None;
+            state.key_established = false;
+            state.manual_key = false;
+            state.key_confirmed = None;
+            state.peer_key_check = None;
+            state.authentication_string = None;
+            state.send_queue.clear();
+            // Detach from the transfer store; a shared one resumes on the next connection
+            state.transfers = SharedTransfers::default();
+            state.sync = SyncState::default();
+            
+            state.on_message_callback = None;
+            state.on_event_callback = None;
+            state.on_file_callback = None;
+            state.on_file_progress_callback = None;
+            state.on_group_frame_callback = None;
+            state.media.on_track_callback = None;
+            (state.media.call.take(), state.handlers.take())
+        };
+        
+        // Release the camera and microphone
+        if let Some(call) = call {
+            call.end();
+        }
+        self.pending_candidates.borrow_mut().clear();
+        if let Some(channel) = self.data_channel.borrow_mut().take() {
+            detach_data_channel(&channel);
+            channel.close();
+        }
+        detach_peer_connection(&self.peer_connection);
+        self.peer_connection.close();
+        
+        // Nothing refers to the closures any more
+        drop(handlers);
+    }
+    
+    fn ensure_open(&self) -> Result<(), JsValue> {
+        if self.state.borrow().closed {
+            return Err(JsValue::from_str("Chat is closed"));
+        }
+        Ok(())
+    }
+    
+    fn ensure_channel_open(&self) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        match *self.data_channel.borrow() {
+            Some(ref channel) if channel.ready_state() == RtcDataChannelState::Open => Ok(()),
+            _ => Err(JsValue::from_str("Data channel not open")),
+        }
+    }
+    
+    // Encode our current local description, candidates included
+    async fn local_description_code(&self, type_: DescriptionType) -> Result<String, JsValue> {
+        wait_for_ice_gathering(&self.peer_connection).await?;
+        let sdp = self.peer_connection.local_description()
+            .map(|description| description.sdp())
+            .ok_or_else(|| JsValue::from_str("No local description"))?;
+        Ok(signal_code::encode(type_, &sdp))
+    }
+    
+    // Refuse a remote description whose DTLS certificate isn't pinned, when pins are configured
+    fn check_pinned_fingerprint(&self, sdp: &str) -> Result<(), JsValue> {
+        if self.ice_config.accepts_fingerprints(&extract_fingerprints(sdp)) {
+            Ok(())
+        } else {
+            Err(JsValue::from_str("Remote certificate fingerprint is not pinned"))
+        }
+    }
+    
+    // Switch to a manually set static key and tell the peer about it
+    fn install_manual_key(&self, key: [u8; 32]) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.encryption_key = key;
+            state.manual_key = true;
+            state.key_established = true;
+            state.key_confirmed = None;
+            state.ratchet = None;
+        }
+        
+        if let Some(ref channel) = *self.data_channel.borrow() {
+            if channel.ready_state() == RtcDataChannelState::Open {
+                send_key_check(channel, &self.state);
+            }
+        }
+        
+        // The peer may already have sent its check value
+        evaluate_key_check(&self.state);
+    }
+    
+    // Setup ICE candidate handling
+    fn setup_ice_candidate_handler(&self) {
+        let state = self.state.clone();
+        
+        let onicecandidate_callback = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
+            if let Some(candidate) = event.candidate() {
+                // Extract necessary properties from the candidate
+                if let (Ok(candidate_str), Ok(sdp_mid), Ok(line_index)) = (
+                    js_sys::Reflect::get(&candidate, &"candidate".into()),
+                    js_sys::Reflect::get(&candidate, &"sdpMid".into()),
+                    js_sys::Reflect::get(&candidate, &"sdpMLineIndex".into()),
+                ) {
+                    let candidate_data = IceCandidate {
+                        candidate: candidate_str.as_string().unwrap_or_default(),
+                        sdp_mid: sdp_mid.as_string(),
+                        sdp_m_line_index: line_index.as_f64().map(|n| n as u16),
+                        username_fragment: None,
+                    };
+                    
+                    notify_event(&state, &ChatEvent::IceCandidate { candidate: &candidate_data });
+                }
+            } else {
+                // Gathering finished; tell the peer with an empty end-of-candidates candidate
+                let end_of_candidates = IceCandidate {
+                    candidate: String::new(),
+                    sdp_mid: None,
+                    sdp_m_line_index: None,
+                    username_fragment: None,
+                };
+                notify_event(&state, &ChatEvent::IceCandidate { candidate: &end_of_candidates });
+            }
+        }) as Box<dyn FnMut(RtcPeerConnectionIceEvent)>);
+        
+        self.peer_connection.set_onicecandidate(Some(onicecandidate_callback.as_ref().unchecked_ref()));
+        let mut handlers = Handlers::default();
+        handlers.keep(onicecandidate_callback);
+        let replaced = self.state.borrow_mut().handlers.replace("onicecandidate", handlers);
+        drop(replaced);
+    }
+}
+
+impl P2PChat {
+    // Keep a closure alive for as long as this connection, such as a callback passed to one of
+    // the on_* setters
+    pub(crate) fn keep_handler<T: ?Sized + WasmClosure + 'static>(&self, closure: Closure<T>) {
+        self.state.borrow_mut().handlers.keep(closure);
+    }
+    
+    // Send an envelope built elsewhere unchanged, e.g. one message fanned out to a whole group
+    pub(crate) fn send_prepared_envelope(&self, envelope: &Envelope) -> Result<(), JsValue> {
+        self.ensure_open()?;
+        match *self.data_channel.borrow() {
+            Some(ref channel) => send_envelope_frame(channel, &self.state, envelope),
+            None => Err(JsValue::from_str("Data channel not open")),
+        }
+    }
+    
+    // Set callback for group frames and sender keys from the peer. Frames are already encrypted
+    // under the peer's sender key, so they're handed over as they arrived
+    pub(crate) fn on_group_frame(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_group_frame_callback = Some(callback);
+    }
+    
+    // Send a group frame in an envelope of its own, so it gets this connection's encryption,
+    // frame counter and replay check like any other message
+    pub(crate) fn send_group_frame(&self, frame: &GroupFrame) -> Result<(), JsValue> {
+        self.send_envelope(MessageContent::Group(frame.clone())).map(|_| ())
+    }
+}
+
+impl Drop for P2PChat {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Wrap content in an envelope, encrypt it for the current session and send it
+fn send_content(channel: &web_sys::RtcDataChannel, state: &SharedChatState, content: MessageContent) -> Result<Envelope, JsValue> {
+    let envelope = Envelope::new(&state.borrow().sender_id, now_millis(), content);
+    send_envelope_frame(channel, state, &envelope)?;
+    Ok(envelope)
+}
+
+// Encrypt an envelope for the current session and send it
+fn send_envelope_frame(channel: &web_sys::RtcDataChannel, state: &SharedChatState, envelope: &Envelope) -> Result<(), JsValue> {
+    if channel.ready_state() != RtcDataChannelState::Open {
+        return Err(JsValue::from_str("Data channel not open"));
+    }
+    if !state.borrow().key_established {
+        return Err(JsValue::from_str("Key exchange not complete"));
+    }
+    
+    let message = serde_json::to_string(envelope).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let (sealed, binary_frames) = seal_frame(state, message.as_bytes(), FrameContent::Envelope)?;
+    
+    // Binary if the peer negotiated it, JSON otherwise
+    let frame = if binary_frames {
+        OutgoingFrame::Binary(sealed.to_bytes(FrameContent::Envelope))
+    } else {
+        let json = serde_json::to_string(&json_frame(&sealed)).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        OutgoingFrame::Text(json)
+    };
+    
+    // Send through data channel, or queue behind earlier frames
+    send_frame(channel, state, frame)
+}
+
+// Encrypt a file chunk and send it as raw bytes in a chunk frame. Chunks have no JSON form, so
+// the peer must have negotiated binary frames.
+fn send_file_chunk(channel: &web_sys::RtcDataChannel, state: &SharedChatState, chunk: &OutgoingChunk) -> Result<(), JsValue> {
+    if channel.ready_state() != RtcDataChannelState::Open {
+        return Err(JsValue::from_str("Data channel not open"));
+    }
+    if !state.borrow().key_established {
+        return Err(JsValue::from_str("Key exchange not complete"));
+    }
+    if !state.borrow().binary_frames {
+        return Err(JsValue::from_str("Peer does not support binary frames"));
+    }
+    
+    let (sealed, _) = seal_frame(state, &chunk.to_bytes(), FrameContent::FileChunk)?;
+    send_frame(channel, state, OutgoingFrame::Binary(sealed.to_bytes(FrameContent::FileChunk)))
+}
+
+// Encrypt a plaintext for the current session, with the ratchet when the key exchange set one up.
+// Also returns whether the peer accepts binary frames.
+fn seal_frame(state: &SharedChatState, plaintext: &[u8], content: FrameContent) -> Result<(SealedFrame, bool), JsValue> {
+    let mut state = state.borrow_mut();
+    let counter = state.next_counter();
+    let aad = content.bind(state.outgoing_aad(counter)?);
+    let session_id = state.session_id;
+    
+    let sealed = match state.ratchet {
+        Some(ref mut ratchet) => {
+            let message = ratchet.encrypt(plaintext, &aad)
+                .map_err(|e| JsValue::from_str(&e.to_string()))?;
+            SealedFrame::Ratchet { session_id, counter, message }
+        }
+        None => {
+            let (ciphertext, nonce) = encrypt_message(&state.encryption_key, plaintext, &aad)?;
+            SealedFrame::Static { session_id, counter, nonce, ciphertext }
+        }
+    };
+    Ok((sealed, state.binary_frames))
+}
+
+// Send a serialized frame right away, or queue it while the channel's buffer is above the
+// high-water mark so bursts don't overflow the SCTP send buffer and close the channel
+fn send_frame(channel: &web_sys::RtcDataChannel, state: &SharedChatState, frame: OutgoingFrame) -> Result<(), JsValue> {
+    let mut state = state.borrow_mut();
+    if state.send_queue.is_empty() && channel.buffered_amount() < SEND_HIGH_WATER_MARK {
+        drop(state);
+        return frame.send(channel);
+    }
+    
+    if state.send_queue.len() >= MAX_QUEUED_FRAMES {
+        return Err(JsValue::from_str("Send queue full"));
+    }
+    state.send_queue.push_back(frame);
+    Ok(())
+}
+
+// Decrypt a data frame and check that the plaintext is text
+fn open_data_frame(state: &SharedChatState, frame: &SealedFrame) -> Result<String, FrameRejection> {
+    let plaintext = state.borrow_mut().open_frame(frame, FrameContent::Envelope)?;
+    String::from_utf8(plaintext).map_err(|_| FrameRejection::new("malformed", Some(frame.counter())))
+}
+
+// Move queued frames onto the channel until its buffer reaches the high-water mark again
+fn flush_send_queue(channel: &web_sys::RtcDataChannel, state: &SharedChatState) {
+    while channel.buffered_amount() < SEND_HIGH_WATER_MARK {
+        let Some(frame) = state.borrow_mut().send_queue.pop_front() else {
+            break;
+        };
+        if let Err(e) = frame.send(channel) {
+            console::log_2(&"Failed to send queued frame:".into(), &e);
+            state.borrow_mut().send_queue.push_front(frame);
+            break;
+        }
+    }
+}
+
+// Send a file transfer frame, logging failures; the transfer resumes on the next session anyway
+fn send_transfer_frame(channel: &web_sys::RtcDataChannel, state: &SharedChatState, content: MessageContent) {
+    if let Err(e) = send_content(channel, state, content) {
+        console::log_2(&"Failed to send file transfer frame:".into(), &e);
+    }
+}
+
+// Handle file transfer frames from the peer
+fn handle_transfer(channel: &web_sys::RtcDataChannel, state: &SharedChatState, content: MessageContent) {
+    match content {
+        MessageContent::FileOffer(offer) => {
+            let transfer_id = offer.transfer_id.clone();
+            let outcome = state.borrow().transfers.borrow_mut().accept_offer(offer);
+            match outcome {
+                OfferOutcome::Resume { next_index, progress } => {
+                    notify_file_progress(state, &progress);
+                    send_transfer_frame(channel, state, MessageContent::FileAck { transfer_id, next_index });
+                }
+                OfferOutcome::Complete { file, progress } => {
+                    send_transfer_frame(channel, state, MessageContent::FileAck { transfer_id, next_index: 0 });
+                    notify_file_progress(state, &progress);
+                    notify_file(state, &file);
+                }
+                OfferOutcome::Rejected => {
+                    send_transfer_frame(channel, state, MessageContent::FileCancel {
+                        transfer_id,
+                        reason: "rejected".to_string(),
+                    });
+                }
+            }
+        }
+        MessageContent::FileAck { transfer_id, next_index } => {
+            let progress = state.borrow().transfers.borrow_mut().acknowledge(&transfer_id, next_index);
+            if let Some(progress) = progress {
+                // Every ack opens the window for more chunks
+                let chunks = state.borrow().transfers.borrow_mut().next_chunks(&transfer_id);
+                for chunk in chunks {
+                    if let Err(e) = send_file_chunk(channel, state, &chunk) {
+                        console::log_2(&"Failed to send file chunk:".into(), &e);
+                        break;
+                    }
+                }
+                notify_file_progress(state, &progress);
+            }
+        }
+        MessageContent::FileCancel { transfer_id, .. } => {
+            // The peer can cancel either direction
+            let progress = {
+                let state = state.borrow();
+                let mut transfers = state.transfers.borrow_mut();
+                let sent = transfers.cancel_send(&transfer_id);
+                sent.or_else(|| transfers.cancel_receive(&transfer_id))
+            };
+            if let Some(progress) = progress {
+                notify_file_progress(state, &progress);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Decrypt a file chunk frame and add the chunk to its transfer
+fn open_file_chunk(channel: &web_sys::RtcDataChannel, state: &SharedChatState, frame: &SealedFrame) -> Result<(), FrameRejection> {
+    let plaintext = state.borrow_mut().open_frame(frame, FrameContent::FileChunk)?;
+    let OutgoingChunk { transfer_id, index, data } = OutgoingChunk::from_bytes(&plaintext)
+        .ok_or_else(|| FrameRejection::new("malformed", Some(frame.counter())))?;
+    
+    let outcome = state.borrow().transfers.borrow_mut().receive_chunk(&transfer_id, index, data);
+    match outcome {
+        ChunkOutcome::Progress { next_index, progress } => {
+            send_transfer_frame(channel, state, MessageContent::FileAck { transfer_id, next_index });
+            notify_file_progress(state, &progress);
+        }
+        ChunkOutcome::Complete { next_index, file, progress } => {
+            send_transfer_frame(channel, state, MessageContent::FileAck { transfer_id, next_index });
+            notify_file_progress(state, &progress);
+            notify_file(state, &file);
+        }
+        ChunkOutcome::Failed { reason, progress } => {
+            send_transfer_frame(channel, state, MessageContent::FileCancel {
+                transfer_id,
+                reason: reason.to_string(),
+            });
+            notify_file_progress(state, &progress);
+        }
+        ChunkOutcome::Ignored { next_index: Some(next_index) } => {
+            send_transfer_frame(channel, state, MessageContent::FileAck { transfer_id, next_index });
+        }
+        ChunkOutcome::Ignored { next_index: None } => {}
+    }
+    Ok(())
+}
+
+// Re-offer unfinished outgoing files once a session is ready, so they resume where they stopped
+fn resume_transfers(channel: &web_sys::RtcDataChannel, state: &SharedChatState) {
+    let offers = state.borrow().transfers.borrow_mut().resume_offers();
+    for offer in offers {
+        send_transfer_frame(channel, state, MessageContent::FileOffer(offer));
+    }
+}
+
+// Setup data channel handlers
+fn setup_data_channel(channel: &web_sys::RtcDataChannel, state: &SharedChatState) {
+    // Message handler
+    let message_state = state.clone();
+    let message_channel = channel.clone();
+    let onmessage_callback = Closure::wrap(Box::new(move |event: web_sys::MessageEvent| {
+        let data = event.data();
+        let result = if let Some(text) = data.as_string() {
+            // Parse the frame
+            match serde_json::from_str::<ChannelFrame>(&text) {
+                Ok(ChannelFrame::KeyCommit { session_id, commitment }) => {
+                    handle_key_commit(&message_channel, &message_state, &session_id, &commitment).map(|_| None)
+                }
+                Ok(ChannelFrame::KeyExchange { public_key, session_id, binary_frames }) => {
+                    handle_key_exchange(&message_channel, &message_state, &public_key, &session_id, binary_frames);
+                    Ok(None)
+                }
+                Ok(ChannelFrame::KeyConfirm(frame)) => {
+                    handle_key_confirmation(&message_channel, &message_state, &frame).map(|_| None)
+                }
+                Ok(ChannelFrame::KeyCheck { session_id, check }) => {
+                    handle_key_check(&message_state, &session_id, &check).map(|_| None)
+                }
+                Ok(ChannelFrame::Message(message_obj)) => match message_obj.to_sealed() {
+                    Some(frame) => open_data_frame(&message_state, &frame).map(Some),
+                    None => Err(FrameRejection::new("malformed", Some(message_obj.counter))),
+                },
+                Ok(ChannelFrame::Ratchet(frame)) => match frame.to_sealed() {
+                    Some(sealed) => open_data_frame(&message_state, &sealed).map(Some),
+                    None => Err(FrameRejection::new("malformed", Some(frame.counter))),
+                },
+                Err(_) => Err(FrameRejection::new("malformed", None)),
+            }
+        } else if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+            // Binary data frame from a peer that negotiated them
+            let bytes = js_sys::Uint8Array::new(buffer).to_vec();
+            match SealedFrame::from_bytes(&bytes) {
+                Some((FrameContent::Envelope, frame)) => open_data_frame(&message_state, &frame).map(Some),
+                Some((FrameContent::FileChunk, frame)) => {
+                    open_file_chunk(&message_channel, &message_state, &frame).map(|_| None)
+                }
+                None => Err(FrameRejection::new("malformed", None)),
+            }
+        } else {
+            return;
+        };
+        
+        let decrypted_message = match result {
+            Ok(decrypted_message) => decrypted_message,
+            Err(rejection) => {
+                notify_rejection(&message_state, &rejection);
+                None
+            }
+        };
+        
+        // Call the JavaScript callback with the message envelope
+        if let Some(decrypted_message) = decrypted_message {
+            // Envelopes must come from the peer on the other end of this connection
+            let expected_sender = message_state.borrow().peer_sender_id();
+            let Some(expected_sender) = expected_sender else {
+                notify_rejection(&message_state, &FrameRejection::new("unknown_session", None));
+                return;
+            };
+            let envelope = Envelope::from_plaintext(&decrypted_message, &expected_sender, now_millis());
+            if envelope.sender != expected_sender {
+                notify_rejection(&message_state, &FrameRejection::new("wrong_sender", None));
+                return;
+            }
+            if envelope.content.is_group() {
+                if let Err(rejection) = notify_group_content(&message_state, &envelope.content) {
+                    notify_rejection(&message_state, &rejection);
+                }
+                return;
+            }
+            if envelope.content.is_file_transfer() {
+                handle_transfer(&message_channel, &message_state, envelope.content);
+                return;
+            }
+            if envelope.content.is_sync() {
+                handle_sync(&message_channel, &message_state, envelope.content);
+                return;
+            }
+            if envelope.content == MessageContent::CallEnded {
+                handle_remote_hang_up(&message_state);
+                return;
+            }
+            
+            let callback = message_state.borrow().on_message_callback.clone();
+            if let Some(callback) = callback {
+                let this = JsValue::NULL;
+                match envelope_to_js(&envelope) {
+                    Ok(arg) => {
+                        let _ = callback.call1(&this, &arg);
+                    }
+                    Err(e) => console::log_2(&"Failed to convert message:".into(), &e),
+                }
+            }
+        }
+    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+    
+    // Connection open handler; start the key exchange as soon as the channel is usable
+    let open_state = state.clone();
+    let open_channel = channel.clone();
+    let onopen_callback = Closure::wrap(Box::new(move |_| {
+        send_key_commit(&open_channel, &open_state);
+        notify_event(&open_state, &ChatEvent::DataChannel {
+            state: DataChannelStatus::Open,
+            label: &open_channel.label(),
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    
+    // Connection close handler; queued frames were encrypted for this channel's session
+    let close_state = state.clone();
+    let close_channel = channel.clone();
+    let onclose_callback = Closure::wrap(Box::new(move |_| {
+        close_state.borrow_mut().send_queue.clear();
+        notify_event(&close_state, &ChatEvent::DataChannel {
+            state: DataChannelStatus::Closed,
+            label: &close_channel.label(),
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    
+    // Resume sending queued frames once the buffer drains
+    let low_state = state.clone();
+    let low_channel = channel.clone();
+    let onbufferedamountlow_callback = Closure::wrap(Box::new(move |_| {
+        flush_send_queue(&low_channel, &low_state);
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    
+    channel.set_buffered_amount_low_threshold(SEND_LOW_WATER_MARK);
+    channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+    
+    channel.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+    channel.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
+    channel.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+    channel.set_onbufferedamountlow(Some(onbufferedamountlow_callback.as_ref().unchecked_ref()));
+    
+    // Only the current channel's handlers are kept; the caller has detached any earlier channel
+    let mut handlers = Handlers::default();
+    handlers.keep(onmessage_callback);
+    handlers.keep(onopen_callback);
+    handlers.keep(onclose_callback);
+    handlers.keep(onbufferedamountlow_callback);
+    let replaced = state.borrow_mut().handlers.replace("data_channel", handlers);
+    drop(replaced);
+}
+
+// Remove our event handlers from a data channel we're about to drop
+fn detach_data_channel(channel: &web_sys::RtcDataChannel) {
+    channel.set_onmessage(None);
+    channel.set_onopen(None);
+    channel.set_onclose(None);
+    channel.set_onbufferedamountlow(None);
+}
+
+// Clear every handler we install on the peer connection
+fn detach_peer_connection(peer_connection: &RtcPeerConnection) {
+    peer_connection.set_onicecandidate(None);
+    peer_connection.set_ondatachannel(None);
+    peer_connection.set_oniceconnectionstatechange(None);
+    peer_connection.set_onnegotiationneeded(None);
+    peer_connection.set_onsignalingstatechange(None);
+    peer_connection.set_onicegatheringstatechange(None);
+    peer_connection.set_ontrack(None);
+}
+
+// Commit to our X25519 public key; the key itself is only sent once the peer has committed too
+fn send_key_commit(channel: &web_sys::RtcDataChannel, state: &SharedChatState) {
+    let frame = {
+        let mut state = state.borrow_mut();
+        // Every channel runs a fresh exchange, so a reopened channel re-keys the session
+        state.key_exchange = KeyExchange::new();
+        state.peer_key_commitment = None;
+        state.key_revealed = false;
+        ChannelFrame::KeyCommit {
+            session_id: encode(state.session_id),
+            commitment: encode(state.key_exchange.commitment(&state.session_id)),
+        }
+    };
+    
+    if let Ok(json) = serde_json::to_string(&frame) {
+        if let Err(e) = send_frame(channel, state, OutgoingFrame::Text(json)) {
+            console::log_2(&"Failed to send key commitment:".into(), &e);
+        }
+    }
+}
+
+// Store the peer's commitment and reveal our public key in return
+fn handle_key_commit(channel: &web_sys::RtcDataChannel, state: &SharedChatState, session_id: &str, commitment: &str) -> Result<(), FrameRejection> {
+    let commitment: [u8; 32] = decode(commitment)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| FrameRejection::new("malformed", None))?;
+    decode_session_id(session_id).ok_or_else(|| FrameRejection::new("malformed", None))?;
+    
+    let reveal = {
+        let mut state = state.borrow_mut();
+        // The peer may not change its key once committed
+        if state.peer_key_commitment.is_some_and(|known| known != commitment) {
+            return Err(FrameRejection::new("commitment_changed", None));
+        }
+        state.peer_key_commitment = Some(commitment);
+        !std::mem::replace(&mut state.key_revealed, true)
+    };
+    
+    if reveal {
+        send_key_exchange(channel, state);
+    }
+    Ok(())
+}
+
+// Send our X25519 public key to the peer
+fn send_key_exchange(channel: &web_sys::RtcDataChannel, state: &SharedChatState) {
+    let frame = {
+        let state = state.borrow();
+        ChannelFrame::KeyExchange {
+            public_key: encode(state.key_exchange.public_key()),
+            session_id: encode(state.session_id),
+            binary_frames: Some(BINARY_FRAME_VERSION),
+        }
+    };
+    
+    if let Ok(json) = serde_json::to_string(&frame) {
+        if let Err(e) = send_frame(channel, state, OutgoingFrame::Text(json)) {
+            console::log_2(&"Failed to send key exchange:".into(), &e);
+        }
+    }
+    
+    // With a manual key, also let the peer check that it holds the same one
+    send_key_check(channel, state);
+}
+
+// Send the check value for our manual key, if we have one
+fn send_key_check(channel: &web_sys::RtcDataChannel, state: &SharedChatState) {
+    let frame = {
+        let state = state.borrow();
+        if !state.manual_key {
+            return;
+        }
+        ChannelFrame::KeyCheck {
+            session_id: encode(state.session_id),
+            check: encode(key_check_value(&state.encryption_key, &state.session_id)),
+        }
+    };
+    
+    if let Ok(json) = serde_json::to_string(&frame) {
+        if let Err(e) = send_frame(channel, state, OutgoingFrame::Text(json)) {
+            console::log_2(&"Failed to send key check:".into(), &e);
+        }
+    }
+}
+
+// Store the peer's key check value and compare it against our manual key
+fn handle_key_check(state: &SharedChatState, session_id: &str, check: &str) -> Result<(), FrameRejection> {
+    {
+        let mut state = state.borrow_mut();
+        let from_peer = decode(session_id)
+            .ok()
+            .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok())
+            .is_some_and(|id| Some(id) == state.peer_session_id);
+        if !from_peer {
+            return Err(FrameRejection::new("unknown_session", None));
+        }
+        
+        let check = decode(check).map_err(|_| FrameRejection::new("malformed", None))?;
+        state.peer_key_check = Some(check);
+    }
+    
+    evaluate_key_check(state);
+    Ok(())
+}
+
+// Once both sides have a manual key and the peer's check value is in, report whether they match
+fn evaluate_key_check(state: &SharedChatState) {
+    let matches = {
+        let mut state = state.borrow_mut();
+        let (Some(check), Some(peer_session_id)) = (state.peer_key_check.as_ref(), state.peer_session_id) else {
+            return;
+        };
+        if !state.manual_key {
+            return;
+        }
+        
+        let matches = verify_key_check(&state.encryption_key, &peer_session_id, check);
+        state.key_confirmed = Some(matches);
+        matches
+    };
+    
+    notify_event(state, &ChatEvent::Key {
+        state: if matches { KeyStatus::Confirmed } else { KeyStatus::Mismatch },
+    });
+}
+
+// Derive the shared secret from the peer's public key and start the ratchet
+fn handle_key_exchange(
+    channel: &web_sys::RtcDataChannel,
+    state: &SharedChatState,
+    public_key: &str,
+    session_id: &str,
+    binary_frames: Option<u8>,
+) {
+    let Ok(peer_public_key) = decode(public_key) else {
+        notify_key_exchange_failure(state, "malformed");
+        return;
+    };
+    let Some(peer_session_id) = decode_session_id(session_id) else {
+        notify_key_exchange_failure(state, "malformed");
+        return;
+    };
+    
+    // The key must be the one the peer committed to before it saw ours
+    let committed = state.borrow().peer_key_commitment
+        .is_some_and(|commitment| commitment == key_commitment(&peer_session_id, &peer_public_key));
+    if !committed {
+        notify_key_exchange_failure(state, "commitment_mismatch");
+        return;
+    }
+    
+    // A new peer session starts a fresh replay window
+    {
+        let mut state = state.borrow_mut();
+        state.binary_frames = binary_frames.is_some_and(|version| version >= BINARY_FRAME_VERSION);
+        if state.peer_session_id != Some(peer_session_id) {
+            state.peer_session_id = Some(peer_session_id);
+            state.replay_window = ReplayWindow::new();
+        }
+    }
+    
+    // A manually set key takes precedence over the ratchet; the session is ready now that the
+    // peer's session id is known
+    if state.borrow().manual_key {
+        resume_transfers(channel, state);
+        return;
+    }
+    
+    let result = {
+        let mut state = state.borrow_mut();
+        let ChatState { key_exchange, local_fingerprint, remote_fingerprint, .. } = &mut *state;
+        key_exchange.derive_key(&peer_public_key, local_fingerprint.as_deref(), remote_fingerprint.as_deref())
+    };
+    let mut keys = match result {
+        Ok(keys) => keys,
+        Err(e) => {
+            console::log_1(&format!("Key derivation failed: {}", e).into());
+            notify_key_exchange_failure(state, "derivation_failed");
+            return;
+        }
+    };
+    
+    // The initiator can send right away and confirms the secret with its first ratchet message;
+    // the responder has to wait for that message before it has a sending chain
+    let confirmation = if keys.initiator {
+        let sealed = {
+            let mut state = state.borrow_mut();
+            let counter = state.next_counter();
+            state
+                .outgoing_aad(counter)
+                .and_then(|aad| {
+                    keys.ratchet
+                        .encrypt(KEY_CONFIRMATION, &aad)
+                        .map_err(|e| JsValue::from_str(&e.to_string()))
+                })
+                .map(|message| RatchetFrame::new(&state.session_id, counter, &message))
+        };
+        match sealed {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                console::log_2(&"Key confirmation failed:".into(), &e);
+                notify_event(state, &ChatEvent::Key { state: KeyStatus::Mismatch });
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    
+    {
+        let mut state = state.borrow_mut();
+        state.ratchet = Some(keys.ratchet);
+        state.key_established = keys.initiator;
+        state.authentication_string = Some(keys.authentication_string);
+        state.peer_verified = false;
+    }
+    
+    if let Some(confirmation) = confirmation {
+        let frame = ChannelFrame::KeyConfirm(confirmation);
+        if let Ok(json) = serde_json::to_string(&frame) {
+            if let Err(e) = send_frame(channel, state, OutgoingFrame::Text(json)) {
+                console::log_2(&"Failed to send key confirmation:".into(), &e);
+            }
+        }
+        notify_event(state, &ChatEvent::Key { state: KeyStatus::Established });
+        resume_transfers(channel, state);
+    }
+}
+
+// Check the initiator's first ratchet message; once it decrypts we can send too. If it doesn't,
+// the two sides derived different secrets, as they would with someone in the middle.
+fn handle_key_confirmation(channel: &web_sys::RtcDataChannel, state: &SharedChatState, frame: &RatchetFrame) -> Result<(), FrameRejection> {
+    let confirmed = {
+        let mut state = state.borrow_mut();
+        frame.to_sealed()
+            .ok_or_else(|| FrameRejection::new("malformed", Some(frame.counter)))
+            .and_then(|sealed| state.open_frame(&sealed, FrameContent::Envelope))
+            .and_then(|plaintext| {
+                if plaintext == KEY_CONFIRMATION {
+                    Ok(())
+                } else {
+                    Err(FrameRejection::new("key_confirmation_mismatch", Some(frame.counter)))
+                }
+            })
+    };
+    if let Err(rejection) = confirmed {
+        // A stray copy after confirmation is only a bad frame
+        if !state.borrow().key_established {
+            notify_event(state, &ChatEvent::Key { state: KeyStatus::Mismatch });
+        }
+        return Err(rejection);
+    }
+    state.borrow_mut().key_established = true;
+    
+    notify_event(state, &ChatEvent::Key { state: KeyStatus::Established });
+    resume_transfers(channel, state);
+    Ok(())
+}
+
+// Encrypt message under the static key
+fn encrypt_message(key: &[u8; 32], message: &[u8], aad: &[u8]) -> Result<(Vec<u8>, [u8; 12]), JsValue> {
+    // Create cipher
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    
+    // Generate random nonce
+    let mut nonce_bytes = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    
+    // Encrypt
+    let ciphertext = cipher.encrypt(nonce, Payload { msg: message, aad })
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    
+    Ok((ciphertext, nonce_bytes))
+}
+
+fn decrypt_message(key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, JsValue> {
+    if nonce.len() != 12 {
+        return Err(JsValue::from_str("Invalid nonce length"));
+    }
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+// Convert an envelope to a plain JS object
+fn envelope_to_js(envelope: &Envelope) -> Result<JsValue, JsValue> {
+    Ok(envelope.serialize(&serde_wasm_bindgen::Serializer::json_compatible())?)
+}
+
+fn now_millis() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+// Hand a received file to the application as { transfer_id, name, size, sha256, data }
+fn notify_file(state: &SharedChatState, file: &ReceivedFile) {
+    let callback = state.borrow().on_file_callback.clone();
+    if let Some(callback) = callback {
+        let arg = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&arg, &"transfer_id".into(), &file.offer.transfer_id.as_str().into());
+        let _ = js_sys::Reflect::set(&arg, &"name".into(), &file.offer.name.as_str().into());
+        let _ = js_sys::Reflect::set(&arg, &"size".into(), &(file.offer.size as f64).into());
+        let _ = js_sys::Reflect::set(&arg, &"sha256".into(), &file.offer.sha256.as_str().into());
+        let _ = js_sys::Reflect::set(&arg, &"data".into(), &js_sys::Uint8Array::from(file.data.as_slice()));
+        let _ = callback.call1(&JsValue::NULL, &arg);
+    }
+}
+
+fn notify_file_progress(state: &SharedChatState, progress: &TransferProgress) {
+    let callback = state.borrow().on_file_progress_callback.clone();
+    if let Some(callback) = callback {
+        if let Ok(arg) = serde_wasm_bindgen::to_value(progress) {
+            let _ = callback.call1(&JsValue::NULL, &arg);
+        }
+    }
+}
+
+// Hand a group frame or sender key to the group session, tagged with its kind; only peers past
+// the key exchange may send them
+fn notify_group_content(state: &SharedChatState, content: &MessageContent) -> Result<(), FrameRejection> {
+    let callback = {
+        let state = state.borrow();
+        if !state.key_established {
+            return Err(FrameRejection::new("no_session", None));
+        }
+        state.on_group_frame_callback.clone()
+    };
+    if let Some(callback) = callback {
+        if let Ok(arg) = serde_wasm_bindgen::to_value(content) {
+            let _ = callback.call1(&JsValue::NULL, &arg);
+        }
+    }
+    Ok(())
+}
+
+// Report a dropped incoming frame as an error event
+fn notify_rejection(state: &SharedChatState, rejection: &FrameRejection) {
+    console::log_1(&format!("Rejected incoming frame: {}", rejection.reason).into());
+    
+    notify_event(state, &ChatEvent::Error {
+        source: ErrorSource::Frame,
+        reason: rejection.reason,
+        counter: rejection.counter,
+        candidate: None,
+    });
+}
+
+// Report a key exchange that can't be completed
+fn notify_key_exchange_failure(state: &SharedChatState, reason: &'static str) {
+    console::log_1(&format!("Key exchange failed: {}", reason).into());
+    
+    notify_event(state, &ChatEvent::Error {
+        source: ErrorSource::KeyExchange,
+        reason,
+        counter: None,
+        candidate: None,
+    });
+}
+
+// Invoke the event callback with a typed event
+fn notify_event(state: &SharedChatState, event: &ChatEvent) {
+    // Release the borrow before calling into JS so the callback may use the chat
+    let callback = state.borrow().on_event_callback.clone();
+    if let Some(callback) = callback {
+        if let Ok(arg) = event.serialize(&serde_wasm_bindgen::Serializer::json_compatible()) {
+            let _ = callback.call1(&JsValue::NULL, &arg);
+        }
+    }
+}
+
+// Report signaling and ICE gathering state changes as events; ICE connection state changes are
+// reported by the reconnect watcher
+fn watch_connection_states(peer_connection: &RtcPeerConnection, state: &SharedChatState) {
+    let signaling_connection = peer_connection.clone();
+    let signaling_state = state.clone();
+    let onsignalingstatechange_callback = Closure::wrap(Box::new(move |_| {
+        notify_event(&signaling_state, &ChatEvent::SignalingState {
+            state: signaling_state_name(signaling_connection.signaling_state()),
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    
+    let gathering_connection = peer_connection.clone();
+    let gathering_state = state.clone();
+    let onicegatheringstatechange_callback = Closure::wrap(Box::new(move |_| {
+        notify_event(&gathering_state, &ChatEvent::IceGatheringState {
+            state: ice_gathering_state_name(gathering_connection.ice_gathering_state()),
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    
+    peer_connection.set_onsignalingstatechange(Some(onsignalingstatechange_callback.as_ref().unchecked_ref()));
+    peer_connection.set_onicegatheringstatechange(Some(onicegatheringstatechange_callback.as_ref().unchecked_ref()));
+    
+    let handlers = &mut state.borrow_mut().handlers;
+    handlers.keep(onsignalingstatechange_callback);
+    handlers.keep(onicegatheringstatechange_callback);
+}
+
+// Wait until ICE gathering completes so a connection code carries every candidate. After the
+// timeout the code goes out with what was gathered, rather than waiting on an unreachable server.
+async fn wait_for_ice_gathering(peer_connection: &RtcPeerConnection) -> Result<(), JsValue> {
+    let mut waited = 0;
+    while peer_connection.ice_gathering_state() != RtcIceGatheringState::Complete
+        && waited < ICE_GATHERING_TIMEOUT_MS
+    {
+        sleep(ICE_GATHERING_POLL_MS).await?;
+        waited += ICE_GATHERING_POLL_MS;
+    }
+    Ok(())
+}
+
+fn decode_description(code: &str, expected: DescriptionType) -> Result<SessionDescription, JsValue> {
+    let (type_, sdp) = signal_code::decode(code).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    if type_ != expected {
+        return Err(JsValue::from_str(&format!("Expected an {} code", expected.as_str())));
+    }
+    Ok(SessionDescription {
+        sdp,
+        type_: type_.as_str().to_string(),
+    })
+}
+
+// Encode a session description ({ sdp, type_ }) as a compact code to pass on by hand. It should be
+// the local description after ICE gathering completed, so the code carries every candidate; the
+// *_code methods of P2PChat take care of that.
+#[wasm_bindgen]
+pub fn encode_signal_code(description: JsValue) -> Result<String, JsValue> {
+    let description: SessionDescription = serde_wasm_bindgen::from_value(description)?;
+    let type_ = DescriptionType::parse(&description.type_)
+        .ok_or_else(|| JsValue::from_str("Only offers and answers can be encoded"))?;
+    Ok(signal_code::encode(type_, &description.sdp))
+}
+
+// Decode a code from encode_signal_code back into { sdp, type_ }
+#[wasm_bindgen]
+pub fn decode_signal_code(code: String) -> Result<JsValue, JsValue> {
+    let (type_, sdp) = signal_code::decode(&code).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let description = SessionDescription {
+        sdp,
+        type_: type_.as_str().to_string(),
+    };
+    Ok(serde_wasm_bindgen::to_value(&description)?)
 }
 
 // Fetch TURN configuration from the server
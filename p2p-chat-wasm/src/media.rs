@@ -0,0 +1,265 @@
+// Audio and video calls on the chat's own peer connection. Local tracks from getUserMedia are
+// added to the RTCPeerConnection that carries the data channel, so adding or removing them fires
+// negotiationneeded and renegotiates through the usual offer events (and signaling), and the
+// media takes the same ICE path as the chat, TURN relay included. Remote tracks are handed to the
+// on_track callback. Hanging up is announced to the peer over the data channel, which ends its
+// side of the call too.
+//
+// Screen sharing swaps the screen in for the camera on the video sender with replaceTrack, so the
+// peer keeps receiving the same track and nothing is renegotiated. The camera is swapped back when
+// sharing stops, including from the browser's own "stop sharing" control.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{
+    console, DisplayMediaStreamConstraints, MediaDevices, MediaStream, MediaStreamConstraints, MediaStreamTrack,
+    RtcPeerConnection, RtcRtpSender, RtcSignalingState, RtcTrackEvent,
+};
+
+use crate::events::{CallStatus, ChatEvent, ScreenShareStatus};
+use crate::{notify_event, SharedChatState};
+
+// Our side of a call in progress
+pub(crate) struct ActiveCall {
+    peer_connection: RtcPeerConnection,
+    stream: MediaStream,
+    senders: Vec<RtcRtpSender>,
+    // The sender carrying the camera, and the camera track it goes back to after screen sharing
+    video_sender: Option<RtcRtpSender>,
+    camera: Option<MediaStreamTrack>,
+    screen: Option<ScreenShare>,
+}
+
+struct ScreenShare {
+    track: MediaStreamTrack,
+    // Handles the browser's "stop sharing" control
+    _onended: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl ScreenShare {
+    fn stop(&self) {
+        self.track.set_onended(None);
+        self.track.stop();
+    }
+}
+
+impl ActiveCall {
+    // Send every track of the stream to the peer
+    pub(crate) fn start(peer_connection: &RtcPeerConnection, stream: MediaStream) -> Self {
+        let senders: Vec<RtcRtpSender> = stream
+            .get_tracks()
+            .iter()
+            .map(|track| peer_connection.add_track_0(track.unchecked_ref(), &stream))
+            .collect();
+        let video_sender = senders
+            .iter()
+            .find(|sender| sender.track().is_some_and(|track| track.kind() == "video"))
+            .cloned();
+        let camera = stream.get_video_tracks().get(0).dyn_into().ok();
+        ActiveCall {
+            peer_connection: peer_connection.clone(),
+            stream,
+            senders,
+            video_sender,
+            camera,
+            screen: None,
+        }
+    }
+
+    pub(crate) fn stream(&self) -> &MediaStream {
+        &self.stream
+    }
+
+    // Release the camera and microphone and take our tracks off the connection
+    pub(crate) fn end(self) {
+        if let Some(ref screen) = self.screen {
+            screen.stop();
+        }
+        stop_tracks(&self.stream);
+        if self.peer_connection.signaling_state() != RtcSignalingState::Closed {
+            for sender in &self.senders {
+                self.peer_connection.remove_track(sender);
+            }
+        }
+    }
+
+    // Enable or disable our tracks of one kind, "audio" or "video"
+    pub(crate) fn set_muted(&self, kind: &str, muted: bool) -> Result<(), JsValue> {
+        let tracks = self.tracks(kind)?;
+        if tracks.length() == 0 {
+            return Err(JsValue::from_str(&format!("No {} track in the call", kind)));
+        }
+        for track in tracks.iter() {
+            track.unchecked_into::<MediaStreamTrack>().set_enabled(!muted);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_screen_sharing(&self) -> bool {
+        self.screen.is_some()
+    }
+
+    // Whether every track of one kind is disabled
+    pub(crate) fn is_muted(&self, kind: &str) -> bool {
+        self.tracks(kind).is_ok_and(|tracks| {
+            tracks.length() > 0
+                && tracks.iter().all(|track| !track.unchecked_into::<MediaStreamTrack>().enabled())
+        })
+    }
+
+    fn tracks(&self, kind: &str) -> Result<js_sys::Array, JsValue> {
+        match kind {
+            "audio" => Ok(self.stream.get_audio_tracks()),
+            "video" => Ok(self.stream.get_video_tracks()),
+            _ => Err(JsValue::from_str("Unknown track kind")),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MediaState {
+    pub(crate) call: Option<ActiveCall>,
+    pub(crate) on_track_callback: Option<js_sys::Function>,
+}
+
+// Ask the browser for the microphone and/or camera
+pub(crate) async fn user_media(audio: bool, video: bool) -> Result<MediaStream, JsValue> {
+    if !audio && !video {
+        return Err(JsValue::from_str("A call needs audio or video"));
+    }
+    let constraints = MediaStreamConstraints::new();
+    constraints.set_audio(&JsValue::from_bool(audio));
+    constraints.set_video(&JsValue::from_bool(video));
+    let stream = JsFuture::from(media_devices()?.get_user_media_with_constraints(&constraints)?).await?;
+    Ok(stream.unchecked_into())
+}
+
+fn media_devices() -> Result<MediaDevices, JsValue> {
+    web_sys::window()
+        .ok_or_else(|| JsValue::from_str("No window"))?
+        .navigator()
+        .media_devices()
+}
+
+// Let the user pick a screen or window and send it in place of the camera
+pub(crate) async fn start_screen_share(state: &SharedChatState) -> Result<MediaStream, JsValue> {
+    let sender = {
+        let state = state.borrow();
+        let call = state.media.call.as_ref().ok_or_else(|| JsValue::from_str("No call in progress"))?;
+        if call.screen.is_some() {
+            return Err(JsValue::from_str("Already sharing the screen"));
+        }
+        call.video_sender.clone()
+            .ok_or_else(|| JsValue::from_str("Screen sharing needs a call with video"))?
+    };
+
+    let constraints = DisplayMediaStreamConstraints::new();
+    constraints.set_video(&JsValue::TRUE);
+    constraints.set_audio(&JsValue::FALSE);
+    let stream: MediaStream = JsFuture::from(media_devices()?.get_display_media_with_constraints(&constraints)?)
+        .await?
+        .unchecked_into();
+    let Some(track) = stream.get_video_tracks().get(0).dyn_into::<MediaStreamTrack>().ok() else {
+        stop_tracks(&stream);
+        return Err(JsValue::from_str("No screen track"));
+    };
+
+    let onended_state = state.clone();
+    let onended = Closure::wrap(Box::new(move |_| {
+        let state = onended_state.clone();
+        spawn_local(async move {
+            if let Err(e) = stop_screen_share(&state).await {
+                console::log_2(&"Failed to restore the camera:".into(), &e);
+            }
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    track.set_onended(Some(onended.as_ref().unchecked_ref()));
+    let screen = ScreenShare {
+        track: track.clone(),
+        _onended: onended,
+    };
+
+    // The call may have ended, or another share started, while the user was choosing
+    {
+        let mut state = state.borrow_mut();
+        match state.media.call {
+            Some(ref mut call) if call.screen.is_none() && call.video_sender.as_ref() == Some(&sender) => {
+                call.screen = Some(screen);
+            }
+            _ => {
+                screen.stop();
+                return Err(JsValue::from_str("Call ended before the screen share started"));
+            }
+        }
+    }
+
+    if let Err(e) = JsFuture::from(sender.replace_track(Some(&track))).await {
+        let screen = state.borrow_mut().media.call.as_mut().and_then(|call| {
+            if call.screen.as_ref().is_some_and(|screen| screen.track == track) {
+                call.screen.take()
+            } else {
+                None
+            }
+        });
+        if let Some(screen) = screen {
+            screen.stop();
+        }
+        return Err(e);
+    }
+    notify_event(state, &ChatEvent::ScreenShare { state: ScreenShareStatus::Started });
+    Ok(stream)
+}
+
+// Stop sending the screen and put the camera back on the video sender
+pub(crate) async fn stop_screen_share(state: &SharedChatState) -> Result<(), JsValue> {
+    let (screen, sender, camera) = {
+        let mut state = state.borrow_mut();
+        let Some(ref mut call) = state.media.call else {
+            return Err(JsValue::from_str("No call in progress"));
+        };
+        let screen = call.screen.take().ok_or_else(|| JsValue::from_str("Not sharing the screen"))?;
+        (screen, call.video_sender.clone(), call.camera.clone())
+    };
+    screen.stop();
+
+    let restored = match sender {
+        Some(sender) => JsFuture::from(sender.replace_track(camera.as_ref())).await.map(|_| ()),
+        None => Ok(()),
+    };
+    notify_event(state, &ChatEvent::ScreenShare { state: ScreenShareStatus::Stopped });
+    restored
+}
+
+pub(crate) fn stop_tracks(stream: &MediaStream) {
+    for track in stream.get_tracks().iter() {
+        track.unchecked_into::<MediaStreamTrack>().stop();
+    }
+}
+
+// The peer hung up: end our side as well
+pub(crate) fn handle_remote_hang_up(state: &SharedChatState) {
+    let call = state.borrow_mut().media.call.take();
+    if let Some(call) = call {
+        call.end();
+    }
+    notify_event(state, &ChatEvent::Call { state: CallStatus::RemoteEnded });
+}
+
+// Hand remote tracks to the on_track callback as { track, streams, kind }
+pub(crate) fn watch_tracks(peer_connection: &RtcPeerConnection, state: &SharedChatState) {
+    let track_state = state.clone();
+    let ontrack_callback = Closure::wrap(Box::new(move |event: RtcTrackEvent| {
+        let callback = track_state.borrow().media.on_track_callback.clone();
+        if let Some(callback) = callback {
+            let track = event.track();
+            let arg = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&arg, &"kind".into(), &track.kind().into());
+            let _ = js_sys::Reflect::set(&arg, &"track".into(), &track);
+            let _ = js_sys::Reflect::set(&arg, &"streams".into(), &event.streams());
+            let _ = callback.call1(&JsValue::NULL, &arg);
+        }
+    }) as Box<dyn FnMut(RtcTrackEvent)>);
+
+    peer_connection.set_ontrack(Some(ontrack_callback.as_ref().unchecked_ref()));
+    state.borrow_mut().handlers.keep(ontrack_callback);
+}
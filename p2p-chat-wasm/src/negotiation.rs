@@ -0,0 +1,111 @@
+// Perfect negotiation. Both ends may send offers at any time (connecting to each other at once,
+// ICE restarts, channels or tracks added later). On a collision the impolite peer ignores the
+// incoming offer and the polite peer rolls its own offer back and answers instead. Roles come
+// from comparing user ids, so the two ends always disagree.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{console, RtcOfferOptions, RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit, RtcSignalingState};
+
+use crate::events::{ChatEvent, OfferReason};
+use crate::{notify_event, SessionDescription, SharedChatState};
+
+#[derive(Debug, Default)]
+pub(crate) struct NegotiationState {
+    pub(crate) polite: bool,
+    // Between createOffer and setLocalDescription, when an incoming offer would collide
+    pub(crate) making_offer: bool,
+    // Set once the first offer/answer exchange finished; negotiationneeded before that is
+    // handled by create_offer itself
+    pub(crate) established: bool,
+}
+
+// The lower user id is polite, so exactly one side yields on a collision
+pub(crate) fn is_polite(local_user_id: &str, remote_user_id: &str) -> bool {
+    local_user_id < remote_user_id
+}
+
+// Whether an incoming offer collides with one of ours
+pub(crate) fn is_offer_collision(peer_connection: &RtcPeerConnection, state: &SharedChatState) -> bool {
+    state.borrow().negotiation.making_offer || peer_connection.signaling_state() != RtcSignalingState::Stable
+}
+
+// Create an offer and set it as the local description, flagging the window in which a
+// colliding offer from the peer must be resolved. Returns the SDP to send.
+pub(crate) async fn make_offer(
+    peer_connection: &RtcPeerConnection,
+    state: &SharedChatState,
+    ice_restart: bool,
+) -> Result<String, JsValue> {
+    state.borrow_mut().negotiation.making_offer = true;
+    let result = async {
+        let options = RtcOfferOptions::new();
+        options.set_ice_restart(ice_restart);
+        let offer = JsFuture::from(peer_connection.create_offer_with_rtc_offer_options(&options)).await?;
+        let sdp = js_sys::Reflect::get(&offer, &"sdp".into())?
+            .as_string()
+            .ok_or_else(|| JsValue::from_str("Offer without SDP"))?;
+
+        let offer_sdp = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+        offer_sdp.set_sdp(&sdp);
+        JsFuture::from(peer_connection.set_local_description(&offer_sdp)).await?;
+        Ok(sdp)
+    }
+    .await;
+    state.borrow_mut().negotiation.making_offer = false;
+    result
+}
+
+// Undo our pending local offer so the peer's offer can be applied
+pub(crate) async fn rollback(peer_connection: &RtcPeerConnection) -> Result<(), JsValue> {
+    let rollback = RtcSessionDescriptionInit::new(RtcSdpType::Rollback);
+    JsFuture::from(peer_connection.set_local_description(&rollback)).await?;
+    Ok(())
+}
+
+// Renegotiate when channels or tracks are added after the connection is up, handing the offer
+// to the application as an "offer" event
+pub(crate) fn watch_negotiation_needed(peer_connection: &RtcPeerConnection, state: &SharedChatState) {
+    let watch_connection = peer_connection.clone();
+    let watch_state = state.clone();
+    let onnegotiationneeded_callback = Closure::wrap(Box::new(move |_| {
+        if !watch_state.borrow().negotiation.established {
+            return;
+        }
+
+        let peer_connection = watch_connection.clone();
+        let state = watch_state.clone();
+        spawn_local(async move {
+            if let Err(e) = send_offer(&peer_connection, &state, false, OfferReason::Renegotiation).await {
+                console::log_2(&"Renegotiation failed:".into(), &e);
+            }
+        });
+    }) as Box<dyn FnMut(web_sys::Event)>);
+
+    peer_connection.set_onnegotiationneeded(Some(onnegotiationneeded_callback.as_ref().unchecked_ref()));
+    state.borrow_mut().handlers.keep(onnegotiationneeded_callback);
+}
+
+// Make an offer and pass it to the application as an "offer" event for delivery to the peer
+pub(crate) async fn send_offer(
+    peer_connection: &RtcPeerConnection,
+    state: &SharedChatState,
+    ice_restart: bool,
+    reason: OfferReason,
+) -> Result<(), JsValue> {
+    // Mid-negotiation; negotiationneeded fires again once we're back to stable
+    if peer_connection.signaling_state() != RtcSignalingState::Stable && !ice_restart {
+        return Ok(());
+    }
+
+    let sdp = make_offer(peer_connection, state, ice_restart).await?;
+    let description = SessionDescription {
+        sdp,
+        type_: "offer".to_string(),
+    };
+    notify_event(state, &ChatEvent::Offer {
+        reason,
+        description: &description,
+    });
+    Ok(())
+}
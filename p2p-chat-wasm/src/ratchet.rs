@@ -0,0 +1,557 @@
+// Double Ratchet session (Signal specification) for forward secrecy of chat messages.
+//
+// This module is plain Rust with no browser dependencies so it can be exercised natively.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+// Most message keys we will derive ahead of time for a single chain
+const MAX_SKIP: u32 = 1000;
+
+// Most skipped message keys kept across all chains; the oldest are evicted first
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+const ROOT_KDF_INFO: &[u8] = b"p2p-chat ratchet v1";
+
+// A skipped message key is found by the peer ratchet key and message number it belongs to
+type SkippedKeyId = ([u8; 32], u32);
+
+pub(crate) const HEADER_LEN: usize = 40;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RatchetError {
+    // The responder cannot send until the initiator's first message arrives
+    NoSendingChain,
+    TooManySkipped,
+    InvalidHeader,
+    DecryptionFailed,
+    EncryptionFailed,
+    NonContributory,
+}
+
+impl fmt::Display for RatchetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatchetError::NoSendingChain => write!(f, "Ratchet has no sending chain yet"),
+            RatchetError::TooManySkipped => write!(f, "Too many skipped messages"),
+            RatchetError::InvalidHeader => write!(f, "Invalid ratchet header"),
+            RatchetError::DecryptionFailed => write!(f, "Failed to decrypt ratchet message"),
+            RatchetError::EncryptionFailed => write!(f, "Failed to encrypt ratchet message"),
+            RatchetError::NonContributory => write!(f, "Peer ratchet key is a low-order point"),
+        }
+    }
+}
+
+// Header sent in the clear (but authenticated) with every message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RatchetHeader {
+    pub(crate) public_key: [u8; 32],
+    pub(crate) previous_chain_length: u32,
+    pub(crate) message_number: u32,
+}
+
+impl RatchetHeader {
+    pub(crate) fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..32].copy_from_slice(&self.public_key);
+        bytes[32..36].copy_from_slice(&self.previous_chain_length.to_be_bytes());
+        bytes[36..].copy_from_slice(&self.message_number.to_be_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, RatchetError> {
+        if bytes.len() != HEADER_LEN {
+            return Err(RatchetError::InvalidHeader);
+        }
+
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(&bytes[..32]);
+        let previous_chain_length = u32::from_be_bytes(bytes[32..36].try_into().unwrap());
+        let message_number = u32::from_be_bytes(bytes[36..].try_into().unwrap());
+        Ok(Self {
+            public_key,
+            previous_chain_length,
+            message_number,
+        })
+    }
+}
+
+// An encrypted ratchet message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RatchetMessage {
+    pub(crate) header: RatchetHeader,
+    pub(crate) nonce: [u8; 12],
+    pub(crate) ciphertext: Vec<u8>,
+}
+
+pub(crate) struct Ratchet {
+    dh_self: StaticSecret,
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    sent_count: u32,
+    received_count: u32,
+    previous_chain_length: u32,
+    skipped_keys: HashMap<SkippedKeyId, [u8; 32]>,
+    skipped_order: VecDeque<SkippedKeyId>,
+}
+
+impl Ratchet {
+    // Initiator side: knows the shared secret and the responder's initial ratchet public key
+    pub(crate) fn new_initiator(shared_secret: [u8; 32], remote_public_key: [u8; 32]) -> Result<Self, RatchetError> {
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let dh_output = diffie_hellman(&dh_self, &remote_public_key)?;
+        let (root_key, sending_chain) = kdf_root(&shared_secret, &dh_output);
+
+        Ok(Self {
+            dh_self,
+            dh_remote: Some(remote_public_key),
+            root_key,
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent_count: 0,
+            received_count: 0,
+            previous_chain_length: 0,
+            skipped_keys: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        })
+    }
+
+    // Responder side: its initial ratchet key pair is the one the initiator already knows
+    pub(crate) fn new_responder(shared_secret: [u8; 32], own_secret: StaticSecret) -> Self {
+        Self {
+            dh_self: own_secret,
+            dh_remote: None,
+            root_key: shared_secret,
+            sending_chain: None,
+            receiving_chain: None,
+            sent_count: 0,
+            received_count: 0,
+            previous_chain_length: 0,
+            skipped_keys: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<RatchetMessage, RatchetError> {
+        let chain_key = self.sending_chain.ok_or(RatchetError::NoSendingChain)?;
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+
+        let header = RatchetHeader {
+            public_key: PublicKey::from(&self.dh_self).to_bytes(),
+            previous_chain_length: self.previous_chain_length,
+            message_number: self.sent_count,
+        };
+
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = seal(&message_key, &nonce, plaintext, &header, associated_data)?;
+
+        self.sending_chain = Some(next_chain);
+        self.sent_count += 1;
+
+        Ok(RatchetMessage {
+            header,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    // Decrypt a message. The chain steps and skipped keys it takes are staged and only applied
+    // once the message opens, so the session is left untouched if anything fails.
+    pub(crate) fn decrypt(&mut self, message: &RatchetMessage, associated_data: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        let header = &message.header;
+
+        // Out-of-order message from a chain we already advanced past
+        let id = (header.public_key, header.message_number);
+        if let Some(message_key) = self.skipped_keys.get(&id) {
+            let plaintext = open(message_key, message, associated_data)?;
+            self.remove_skipped_key(&id);
+            return Ok(plaintext);
+        }
+
+        let mut staged = StagedReceive::default();
+
+        // New ratchet public key from the peer: finish the old chain and step the DH ratchet
+        let (chain_key, received_count) = if self.dh_remote != Some(header.public_key) {
+            if let Some(chain_key) = self.receiving_chain {
+                let remote = self.dh_remote.ok_or(RatchetError::InvalidHeader)?;
+                skip_message_keys(chain_key, remote, self.received_count, header.previous_chain_length, &mut staged.skipped)?;
+            }
+            let step = self.dh_step(header.public_key)?;
+            let receiving_chain = step.receiving_chain;
+            staged.dh_step = Some(step);
+            (Some(receiving_chain), 0)
+        } else {
+            (self.receiving_chain, self.received_count)
+        };
+
+        let chain_key = chain_key.ok_or(RatchetError::DecryptionFailed)?;
+        let (chain_key, received_count) =
+            skip_message_keys(chain_key, header.public_key, received_count, header.message_number, &mut staged.skipped)?;
+        let (next_chain, mut message_key) = kdf_chain(&chain_key);
+        let plaintext = open(&message_key, message, associated_data);
+        message_key.zeroize();
+        let plaintext = plaintext?;
+
+        staged.receiving_chain = next_chain;
+        staged.received_count = received_count + 1;
+        self.commit(&mut staged);
+        Ok(plaintext)
+    }
+
+    fn remove_skipped_key(&mut self, id: &SkippedKeyId) {
+        if let Some(mut message_key) = self.skipped_keys.remove(id) {
+            message_key.zeroize();
+        }
+        self.skipped_order.retain(|entry| entry != id);
+    }
+
+    // The DH ratchet step a new peer ratchet key calls for, without applying it
+    fn dh_step(&self, remote_public_key: [u8; 32]) -> Result<DhStep, RatchetError> {
+        let dh_output = diffie_hellman(&self.dh_self, &remote_public_key)?;
+        let (root_key, receiving_chain) = kdf_root(&self.root_key, &dh_output);
+
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let dh_output = diffie_hellman(&dh_self, &remote_public_key)?;
+        let (root_key, sending_chain) = kdf_root(&root_key, &dh_output);
+        Ok(DhStep {
+            dh_self,
+            dh_remote: remote_public_key,
+            root_key,
+            receiving_chain,
+            sending_chain,
+        })
+    }
+
+    // Apply the changes of a message that decrypted
+    fn commit(&mut self, staged: &mut StagedReceive) {
+        if let Some(step) = staged.dh_step.take() {
+            self.previous_chain_length = self.sent_count;
+            self.sent_count = 0;
+            self.dh_remote = Some(step.dh_remote);
+            self.root_key = step.root_key;
+            self.sending_chain = Some(step.sending_chain);
+            self.dh_self = step.dh_self.clone();
+        }
+        self.receiving_chain = Some(staged.receiving_chain);
+        self.received_count = staged.received_count;
+
+        for (id, message_key) in staged.skipped.drain(..) {
+            self.skipped_keys.insert(id, message_key);
+            self.skipped_order.push_back(id);
+            if self.skipped_order.len() > MAX_SKIPPED_KEYS {
+                if let Some(oldest) = self.skipped_order.pop_front() {
+                    if let Some(mut evicted) = self.skipped_keys.remove(&oldest) {
+                        evicted.zeroize();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A DH ratchet step computed for an incoming message but not yet applied
+struct DhStep {
+    dh_self: StaticSecret,
+    dh_remote: [u8; 32],
+    root_key: [u8; 32],
+    receiving_chain: [u8; 32],
+    sending_chain: [u8; 32],
+}
+
+impl Drop for DhStep {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.receiving_chain.zeroize();
+        self.sending_chain.zeroize();
+    }
+}
+
+// Everything decrypting one message changes in the session
+#[derive(Default)]
+struct StagedReceive {
+    dh_step: Option<DhStep>,
+    receiving_chain: [u8; 32],
+    received_count: u32,
+    skipped: Vec<(SkippedKeyId, [u8; 32])>,
+}
+
+impl Drop for StagedReceive {
+    fn drop(&mut self) {
+        self.receiving_chain.zeroize();
+        for (_, message_key) in self.skipped.iter_mut() {
+            message_key.zeroize();
+        }
+    }
+}
+
+// Derive the keys of the messages of a receiving chain from `from` up to (not including) `until`,
+// returning the chain key and message number reached
+fn skip_message_keys(
+    mut chain_key: [u8; 32],
+    remote: [u8; 32],
+    from: u32,
+    until: u32,
+    skipped: &mut Vec<(SkippedKeyId, [u8; 32])>,
+) -> Result<([u8; 32], u32), RatchetError> {
+    if until > from.saturating_add(MAX_SKIP) {
+        return Err(RatchetError::TooManySkipped);
+    }
+    let mut number = from;
+    while number < until {
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+        skipped.push(((remote, number), message_key));
+        chain_key = next_chain;
+        number += 1;
+    }
+    Ok((chain_key, number))
+}
+
+// Chain and message keys are wiped when the session ends; dh_self zeroes itself
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.sending_chain.zeroize();
+        self.receiving_chain.zeroize();
+        for key in self.skipped_keys.values_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+fn diffie_hellman(secret: &StaticSecret, remote_public_key: &[u8; 32]) -> Result<[u8; 32], RatchetError> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*remote_public_key));
+    if !shared.was_contributory() {
+        return Err(RatchetError::NonContributory);
+    }
+    Ok(shared.to_bytes())
+}
+
+// KDF_RK: HKDF keyed by the root key, producing a new root key and a chain key
+fn kdf_root(root_key: &[u8; 32], dh_output: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(root_key), dh_output);
+    let mut output = [0u8; 64];
+    hkdf.expand(ROOT_KDF_INFO, &mut output)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+
+    let mut new_root = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    new_root.copy_from_slice(&output[..32]);
+    chain_key.copy_from_slice(&output[32..]);
+    (new_root, chain_key)
+}
+
+// KDF_CK: HMAC-SHA256 of the chain key with distinct constants for the next chain key and the message key
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |constant: u8| {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&[constant]);
+        let mut output = [0u8; 32];
+        output.copy_from_slice(&mac.finalize().into_bytes());
+        output
+    };
+    (derive(0x02), derive(0x01))
+}
+
+fn seal(
+    message_key: &[u8; 32],
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    header: &RatchetHeader,
+    associated_data: &[u8],
+) -> Result<Vec<u8>, RatchetError> {
+    let cipher = Aes256Gcm::new_from_slice(message_key).map_err(|_| RatchetError::EncryptionFailed)?;
+    let aad = [associated_data, &header.to_bytes()].concat();
+    cipher
+        .encrypt(Nonce::from_slice(nonce), Payload { msg: plaintext, aad: &aad })
+        .map_err(|_| RatchetError::EncryptionFailed)
+}
+
+fn open(message_key: &[u8; 32], message: &RatchetMessage, associated_data: &[u8]) -> Result<Vec<u8>, RatchetError> {
+    let cipher = Aes256Gcm::new_from_slice(message_key).map_err(|_| RatchetError::DecryptionFailed)?;
+    let aad = [associated_data, &message.header.to_bytes()].concat();
+    cipher
+        .decrypt(Nonce::from_slice(&message.nonce), Payload { msg: &message.ciphertext, aad: &aad })
+        .map_err(|_| RatchetError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AAD: &[u8] = b"test frame";
+
+    fn session() -> (Ratchet, Ratchet) {
+        let mut shared_secret = [0u8; 32];
+        OsRng.fill_bytes(&mut shared_secret);
+        let responder_secret = StaticSecret::random_from_rng(OsRng);
+        let responder_public = PublicKey::from(&responder_secret).to_bytes();
+        let alice = Ratchet::new_initiator(shared_secret, responder_public).unwrap();
+        let bob = Ratchet::new_responder(shared_secret, responder_secret);
+        (alice, bob)
+    }
+
+    fn send(ratchet: &mut Ratchet, count: usize) -> Vec<RatchetMessage> {
+        (0..count)
+            .map(|i| ratchet.encrypt(format!("message {}", i).as_bytes(), AAD).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut alice, mut bob) = session();
+        let message = alice.encrypt(b"hello", AAD).unwrap();
+        assert_eq!(bob.decrypt(&message, AAD).unwrap(), b"hello");
+
+        let reply = bob.encrypt(b"hi back", AAD).unwrap();
+        assert_eq!(alice.decrypt(&reply, AAD).unwrap(), b"hi back");
+    }
+
+    #[test]
+    fn responder_waits_for_first_message() {
+        let (_, mut bob) = session();
+        assert_eq!(bob.encrypt(b"too early", AAD).unwrap_err(), RatchetError::NoSendingChain);
+    }
+
+    #[test]
+    fn out_of_order_delivery_uses_skipped_keys() {
+        let (mut alice, mut bob) = session();
+        let messages = send(&mut alice, 4);
+
+        assert_eq!(bob.decrypt(&messages[3], AAD).unwrap(), b"message 3");
+        assert_eq!(bob.decrypt(&messages[1], AAD).unwrap(), b"message 1");
+        assert_eq!(bob.decrypt(&messages[0], AAD).unwrap(), b"message 0");
+        assert_eq!(bob.decrypt(&messages[2], AAD).unwrap(), b"message 2");
+
+        // Each skipped key opens its message once
+        assert_eq!(bob.decrypt(&messages[1], AAD).unwrap_err(), RatchetError::DecryptionFailed);
+    }
+
+    #[test]
+    fn rejects_skipping_past_max_skip() {
+        let (mut alice, mut bob) = session();
+        let first = alice.encrypt(b"first", AAD).unwrap();
+        bob.decrypt(&first, AAD).unwrap();
+
+        let messages = send(&mut alice, MAX_SKIP as usize + 2);
+        assert_eq!(
+            bob.decrypt(messages.last().unwrap(), AAD).unwrap_err(),
+            RatchetError::TooManySkipped
+        );
+        // The failed attempt left the session usable
+        assert_eq!(bob.decrypt(&messages[0], AAD).unwrap(), b"message 0");
+    }
+
+    #[test]
+    fn evicts_oldest_skipped_keys_past_max_skipped_keys() {
+        let (mut alice, mut bob) = session();
+        let first = alice.encrypt(b"first", AAD).unwrap();
+        bob.decrypt(&first, AAD).unwrap();
+
+        // Three chains, each leaving MAX_SKIP keys behind, overflow the skipped key store
+        let mut chains = Vec::new();
+        for _ in 0..3 {
+            let messages = send(&mut alice, MAX_SKIP as usize + 1);
+            bob.decrypt(messages.last().unwrap(), AAD).unwrap();
+            let reply = bob.encrypt(b"reply", AAD).unwrap();
+            alice.decrypt(&reply, AAD).unwrap();
+            chains.push(messages);
+        }
+
+        assert!(bob.skipped_keys.len() <= MAX_SKIPPED_KEYS);
+        assert_eq!(bob.decrypt(&chains[0][0], AAD).unwrap_err(), RatchetError::DecryptionFailed);
+        assert_eq!(bob.decrypt(&chains[2][0], AAD).unwrap(), b"message 0");
+    }
+
+    #[test]
+    fn steps_dh_ratchet_when_direction_changes() {
+        let (mut alice, mut bob) = session();
+        let first = alice.encrypt(b"one", AAD).unwrap();
+        bob.decrypt(&first, AAD).unwrap();
+
+        let reply = bob.encrypt(b"two", AAD).unwrap();
+        assert_ne!(reply.header.public_key, first.header.public_key);
+        alice.decrypt(&reply, AAD).unwrap();
+
+        let third = alice.encrypt(b"three", AAD).unwrap();
+        assert_ne!(third.header.public_key, first.header.public_key);
+        assert_eq!(third.header.message_number, 0);
+        assert_eq!(third.header.previous_chain_length, 1);
+        assert_eq!(bob.decrypt(&third, AAD).unwrap(), b"three");
+
+        // A late message from the old chain still opens after the step
+        let (mut alice, mut bob) = session();
+        let old = send(&mut alice, 2);
+        bob.decrypt(&old[0], AAD).unwrap();
+        let reply = bob.encrypt(b"reply", AAD).unwrap();
+        alice.decrypt(&reply, AAD).unwrap();
+        let new = alice.encrypt(b"new chain", AAD).unwrap();
+        assert_eq!(bob.decrypt(&new, AAD).unwrap(), b"new chain");
+        assert_eq!(bob.decrypt(&old[1], AAD).unwrap(), b"message 1");
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let (mut alice, mut bob) = session();
+        let message = alice.encrypt(b"hello", AAD).unwrap();
+
+        let mut tampered = message.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(bob.decrypt(&tampered, AAD).unwrap_err(), RatchetError::DecryptionFailed);
+
+        let mut tampered = message.clone();
+        tampered.header.message_number += 1;
+        assert_eq!(bob.decrypt(&tampered, AAD).unwrap_err(), RatchetError::DecryptionFailed);
+
+        let mut tampered = message.clone();
+        tampered.header.previous_chain_length = 7;
+        assert_eq!(bob.decrypt(&tampered, AAD).unwrap_err(), RatchetError::DecryptionFailed);
+
+        assert_eq!(bob.decrypt(&message, b"other frame").unwrap_err(), RatchetError::DecryptionFailed);
+        assert!(RatchetHeader::from_bytes(&[0u8; HEADER_LEN - 1]).is_err());
+
+        // None of the failures disturbed the session
+        assert_eq!(bob.decrypt(&message, AAD).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn failed_messages_leave_skipped_keys_and_chains_alone() {
+        let (mut alice, mut bob) = session();
+        let first = send(&mut alice, 3);
+        assert_eq!(bob.decrypt(&first[2], AAD).unwrap(), b"message 2");
+
+        // A reply steps bob's ratchet; alice's answer on a new chain, skipping one message, is
+        // tampered with before it arrives
+        let reply = bob.encrypt(b"reply", AAD).unwrap();
+        assert_eq!(alice.decrypt(&reply, AAD).unwrap(), b"reply");
+        let second = send(&mut alice, 2);
+        let mut tampered = second[1].clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(bob.decrypt(&tampered, AAD).unwrap_err(), RatchetError::DecryptionFailed);
+        let mut tampered = first[0].clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(bob.decrypt(&tampered, AAD).unwrap_err(), RatchetError::DecryptionFailed);
+
+        // Everything still opens, in any order, and bob can still send on his chain
+        assert_eq!(bob.decrypt(&second[1], AAD).unwrap(), b"message 1");
+        assert_eq!(bob.decrypt(&first[0], AAD).unwrap(), b"message 0");
+        assert_eq!(bob.decrypt(&second[0], AAD).unwrap(), b"message 0");
+        assert_eq!(bob.decrypt(&first[1], AAD).unwrap(), b"message 1");
+        let later = bob.encrypt(b"later", AAD).unwrap();
+        assert_eq!(alice.decrypt(&later, AAD).unwrap(), b"later");
+    }
+}
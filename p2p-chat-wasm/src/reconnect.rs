@@ -0,0 +1,143 @@
+// Automatic ICE restart. When the ICE connection fails or drops, the side that made the original
+// offer creates an ICE-restart offer and hands it to the application as an "offer" event to
+// deliver over signaling, retrying with exponential backoff until the
+// connection comes back. The DTLS session, data channel and chat keys survive an ICE restart.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{console, RtcIceConnectionState, RtcPeerConnection};
+
+use crate::events::{ice_connection_state_name, ChatEvent, OfferReason, ReconnectStatus};
+use crate::negotiation::send_offer;
+use crate::{notify_event, SharedChatState};
+
+const FIRST_RETRY_DELAY_MS: u32 = 1000;
+const MAX_RETRY_DELAY_MS: u32 = 30_000;
+const MAX_RESTART_ATTEMPTS: u32 = 8;
+
+#[derive(Debug, Default)]
+pub(crate) struct ReconnectState {
+    // Only the original offerer restarts ICE, so both ends never send restart offers at once
+    pub(crate) is_offerer: bool,
+    reconnecting: bool,
+    attempt: u32,
+    // Bumped to stop a running restart loop
+    generation: u32,
+}
+
+impl ReconnectState {
+    // Stop any running restart loop for good
+    pub(crate) fn cancel(&mut self) {
+        self.reconnecting = false;
+        self.generation += 1;
+    }
+}
+
+// Watch the ICE connection state, report it, and start or stop reconnecting as it changes
+pub(crate) fn watch_ice_connection(peer_connection: &RtcPeerConnection, state: &SharedChatState) {
+    let watch_connection = peer_connection.clone();
+    let watch_state = state.clone();
+    let oniceconnectionstatechange_callback = Closure::wrap(Box::new(move |_| {
+        handle_ice_state_change(&watch_connection, &watch_state);
+    }) as Box<dyn FnMut(web_sys::Event)>);
+
+    peer_connection.set_oniceconnectionstatechange(Some(oniceconnectionstatechange_callback.as_ref().unchecked_ref()));
+    state.borrow_mut().handlers.keep(oniceconnectionstatechange_callback);
+}
+
+fn handle_ice_state_change(peer_connection: &RtcPeerConnection, state: &SharedChatState) {
+    let ice_state = peer_connection.ice_connection_state();
+    notify_event(state, &ChatEvent::IceConnectionState {
+        state: ice_connection_state_name(ice_state),
+    });
+
+    match ice_state {
+        RtcIceConnectionState::Failed | RtcIceConnectionState::Disconnected => {
+            let (is_offerer, generation) = {
+                let mut state = state.borrow_mut();
+                let reconnect = &mut state.reconnect;
+                if reconnect.reconnecting {
+                    return;
+                }
+                reconnect.reconnecting = true;
+                reconnect.attempt = 0;
+                reconnect.generation += 1;
+                (reconnect.is_offerer, reconnect.generation)
+            };
+
+            notify_event(state, &ChatEvent::Reconnect { state: ReconnectStatus::Reconnecting });
+            if is_offerer {
+                spawn_local(restart_loop(peer_connection.clone(), state.clone(), generation));
+            }
+        }
+        RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+            {
+                let mut state = state.borrow_mut();
+                let reconnect = &mut state.reconnect;
+                if !reconnect.reconnecting {
+                    return;
+                }
+                reconnect.reconnecting = false;
+                reconnect.attempt = 0;
+                reconnect.generation += 1;
+            }
+            notify_event(state, &ChatEvent::Reconnect { state: ReconnectStatus::Reconnected });
+        }
+        RtcIceConnectionState::Closed => state.borrow_mut().reconnect.cancel(),
+        _ => {}
+    }
+}
+
+// Send restart offers until the connection recovers, the loop is cancelled, or we give up
+async fn restart_loop(peer_connection: RtcPeerConnection, state: SharedChatState, generation: u32) {
+    loop {
+        let attempt = {
+            let mut state = state.borrow_mut();
+            if state.reconnect.generation != generation {
+                return;
+            }
+            state.reconnect.attempt += 1;
+            state.reconnect.attempt
+        };
+
+        if attempt > MAX_RESTART_ATTEMPTS {
+            state.borrow_mut().reconnect.reconnecting = false;
+            notify_event(&state, &ChatEvent::Reconnect { state: ReconnectStatus::Failed });
+            return;
+        }
+
+        // Waiting first also gives a briefly disconnected connection the chance to recover by itself
+        if let Err(e) = sleep(retry_delay(attempt)).await {
+            console::log_2(&"Reconnect timer failed:".into(), &e);
+            return;
+        }
+        if state.borrow().reconnect.generation != generation {
+            return;
+        }
+
+        if let Err(e) = send_offer(&peer_connection, &state, true, OfferReason::IceRestart).await {
+            console::log_2(&"ICE restart failed:".into(), &e);
+        }
+    }
+}
+
+// Delay before the given attempt: 1s, 2s, 4s, ... capped at 30s
+fn retry_delay(attempt: u32) -> u32 {
+    FIRST_RETRY_DELAY_MS
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY_MS)
+}
+
+pub(crate) async fn sleep(ms: u32) -> Result<(), JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window found"))?;
+    let mut timer_error = None;
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        if let Err(e) = window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms as i32) {
+            timer_error = Some(e);
+        }
+    });
+    if let Some(e) = timer_error {
+        return Err(e);
+    }
+    JsFuture::from(promise).await.map(|_| ())
+}
@@ -0,0 +1,106 @@
+// Sliding-window replay protection for frame counters, in the style of IPsec/DTLS.
+
+// Number of counters below the highest one that are still accepted out of order
+const WINDOW_SIZE: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReplayCheck {
+    Fresh,
+    // Counter already accepted
+    Duplicate,
+    // Counter fell out of the window
+    Stale,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReplayWindow {
+    highest: Option<u64>,
+    // Bit i set means counter (highest - i) was accepted
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn check(&self, counter: u64) -> ReplayCheck {
+        let Some(highest) = self.highest else {
+            return ReplayCheck::Fresh;
+        };
+        if counter > highest {
+            return ReplayCheck::Fresh;
+        }
+
+        let offset = highest - counter;
+        if offset >= WINDOW_SIZE {
+            ReplayCheck::Stale
+        } else if self.bitmap & (1 << offset) != 0 {
+            ReplayCheck::Duplicate
+        } else {
+            ReplayCheck::Fresh
+        }
+    }
+
+    // Record a counter; only call after the frame carrying it authenticated
+    pub(crate) fn accept(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                let offset = highest - counter;
+                if offset < WINDOW_SIZE {
+                    self.bitmap |= 1 << offset;
+                }
+            }
+            Some(highest) => {
+                let shift = counter - highest;
+                self.bitmap = if shift >= WINDOW_SIZE { 0 } else { self.bitmap << shift };
+                self.bitmap |= 1;
+                self.highest = Some(counter);
+            }
+            None => {
+                self.bitmap = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_each_counter_once() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check(0), ReplayCheck::Fresh);
+        window.accept(0);
+        assert_eq!(window.check(0), ReplayCheck::Duplicate);
+        assert_eq!(window.check(1), ReplayCheck::Fresh);
+    }
+
+    #[test]
+    fn accepts_out_of_order_counters_inside_the_window() {
+        let mut window = ReplayWindow::new();
+        window.accept(100);
+        window.accept(97);
+        assert_eq!(window.check(97), ReplayCheck::Duplicate);
+        assert_eq!(window.check(98), ReplayCheck::Fresh);
+        assert_eq!(window.check(100 - (WINDOW_SIZE - 1)), ReplayCheck::Fresh);
+        assert_eq!(window.check(100 - WINDOW_SIZE), ReplayCheck::Stale);
+    }
+
+    #[test]
+    fn rejects_counters_that_fell_out_of_the_window() {
+        let mut window = ReplayWindow::new();
+        window.accept(5);
+        window.accept(5 + WINDOW_SIZE);
+        assert_eq!(window.check(5), ReplayCheck::Stale);
+        assert_eq!(window.check(6), ReplayCheck::Fresh);
+
+        // A large jump clears the bitmap but still remembers the new highest counter
+        window.accept(10_000);
+        assert_eq!(window.check(10_000), ReplayCheck::Duplicate);
+        assert_eq!(window.check(10_000 - WINDOW_SIZE), ReplayCheck::Stale);
+        assert_eq!(window.check(9_999), ReplayCheck::Fresh);
+    }
+}
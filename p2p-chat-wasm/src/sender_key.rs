@@ -0,0 +1,306 @@
+// Sender keys for group chats. Every member encrypts its group messages once under its own sender
+// key, a symmetric hash ratchet whose current chain key it hands to each other member over their
+// pairwise (already encrypted) connection. Each message uses a fresh message key derived from the
+// chain, so a chain key received mid-conversation can't open earlier messages. Rekeying under a
+// new key id shuts out members who have left.
+//
+// This module is plain Rust with no browser dependencies; the AES-GCM layer is the same one used
+// for static-key frames.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+// Most message keys a receiver will derive ahead of time for one chain
+const MAX_SKIP: u32 = 1000;
+
+// Most skipped message keys kept per chain; the oldest are evicted first
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+// Chains kept per member: the current one and the one it replaced, so messages sent just before
+// a rekey can still be read
+const CHAINS_PER_MEMBER: usize = 2;
+
+const GROUP_AAD_LABEL: &[u8] = b"p2p-chat group v1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SenderKeyError {
+    // No chain from this member under this key id
+    UnknownKey,
+    TooManySkipped,
+    // The message key was already used, or evicted
+    MessageKeyUsed,
+}
+
+impl fmt::Display for SenderKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SenderKeyError::UnknownKey => write!(f, "Unknown sender key"),
+            SenderKeyError::TooManySkipped => write!(f, "Too many skipped group messages"),
+            SenderKeyError::MessageKeyUsed => write!(f, "Group message key already used"),
+        }
+    }
+}
+
+// What a member sends to the others so they can read its group messages
+pub(crate) struct SenderKeyDistribution {
+    pub(crate) key_id: u32,
+    pub(crate) chain_key: [u8; 32],
+    pub(crate) iteration: u32,
+}
+
+impl Drop for SenderKeyDistribution {
+    fn drop(&mut self) {
+        self.chain_key.zeroize();
+    }
+}
+
+// Our own sending chain
+pub(crate) struct SenderKey {
+    key_id: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+}
+
+impl SenderKey {
+    pub(crate) fn new(key_id: u32) -> Self {
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        SenderKey {
+            key_id,
+            chain_key,
+            iteration: 0,
+        }
+    }
+
+    // A fresh chain under a random key id, so a rejoining member's ids don't repeat
+    pub(crate) fn random() -> Self {
+        SenderKey::new(OsRng.next_u32())
+    }
+
+    pub(crate) fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    // The chain from the next message on
+    pub(crate) fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            key_id: self.key_id,
+            chain_key: self.chain_key,
+            iteration: self.iteration,
+        }
+    }
+
+    // Message key for the next message and its iteration
+    pub(crate) fn next_message_key(&mut self) -> (u32, [u8; 32]) {
+        let (next_chain, message_key) = kdf_chain(&self.chain_key);
+        let iteration = self.iteration;
+        self.chain_key = next_chain;
+        self.iteration += 1;
+        (iteration, message_key)
+    }
+}
+
+impl Drop for SenderKey {
+    fn drop(&mut self) {
+        self.chain_key.zeroize();
+    }
+}
+
+// A member's chain as we received it
+struct ReceiverChain {
+    key_id: u32,
+    chain_key: [u8; 32],
+    // Iteration of the next message key the chain will produce
+    iteration: u32,
+    skipped: HashMap<u32, [u8; 32]>,
+    skipped_order: VecDeque<u32>,
+}
+
+impl ReceiverChain {
+    fn message_key(&mut self, iteration: u32) -> Result<[u8; 32], SenderKeyError> {
+        if iteration < self.iteration {
+            return self.skipped.remove(&iteration).ok_or(SenderKeyError::MessageKeyUsed);
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return Err(SenderKeyError::TooManySkipped);
+        }
+
+        while self.iteration < iteration {
+            let (next_chain, message_key) = kdf_chain(&self.chain_key);
+            self.chain_key = next_chain;
+            self.store_skipped(self.iteration, message_key);
+            self.iteration += 1;
+        }
+        let (next_chain, message_key) = kdf_chain(&self.chain_key);
+        self.chain_key = next_chain;
+        self.iteration += 1;
+        Ok(message_key)
+    }
+
+    fn store_skipped(&mut self, iteration: u32, message_key: [u8; 32]) {
+        if self.skipped_order.len() == MAX_SKIPPED_KEYS {
+            if let Some(oldest) = self.skipped_order.pop_front() {
+                if let Some(mut key) = self.skipped.remove(&oldest) {
+                    key.zeroize();
+                }
+            }
+        }
+        self.skipped.insert(iteration, message_key);
+        self.skipped_order.push_back(iteration);
+    }
+}
+
+impl Drop for ReceiverChain {
+    fn drop(&mut self) {
+        self.chain_key.zeroize();
+        for key in self.skipped.values_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+// Other members' chains, by user id
+#[derive(Default)]
+pub(crate) struct SenderKeyStore {
+    chains: HashMap<String, VecDeque<ReceiverChain>>,
+}
+
+impl SenderKeyStore {
+    // Take a member's chain; a chain under a key id we already have is replaced
+    pub(crate) fn install(&mut self, member: &str, distribution: &SenderKeyDistribution) {
+        let chains = self.chains.entry(member.to_string()).or_default();
+        chains.retain(|chain| chain.key_id != distribution.key_id);
+        chains.push_back(ReceiverChain {
+            key_id: distribution.key_id,
+            chain_key: distribution.chain_key,
+            iteration: distribution.iteration,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        });
+        while chains.len() > CHAINS_PER_MEMBER {
+            chains.pop_front();
+        }
+    }
+
+    pub(crate) fn message_key(&mut self, member: &str, key_id: u32, iteration: u32) -> Result<[u8; 32], SenderKeyError> {
+        self.chains
+            .get_mut(member)
+            .and_then(|chains| chains.iter_mut().find(|chain| chain.key_id == key_id))
+            .ok_or(SenderKeyError::UnknownKey)?
+            .message_key(iteration)
+    }
+
+    pub(crate) fn remove(&mut self, member: &str) {
+        self.chains.remove(member);
+    }
+}
+
+// Associated data binding a group message to its sender and position in the sender's chain
+pub(crate) fn group_aad(sender: &str, key_id: u32, iteration: u32) -> Vec<u8> {
+    [
+        GROUP_AAD_LABEL,
+        &(sender.len() as u32).to_be_bytes(),
+        sender.as_bytes(),
+        &key_id.to_be_bytes(),
+        &iteration.to_be_bytes(),
+    ]
+    .concat()
+}
+
+// Same KDF_CK as the Double Ratchet: HMAC-SHA256 of the chain key with distinct constants for the
+// next chain key and the message key
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |constant: u8| {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&[constant]);
+        let mut output = [0u8; 32];
+        output.copy_from_slice(&mac.finalize().into_bytes());
+        output
+    };
+    (derive(0x02), derive(0x01))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receiver_derives_the_senders_message_keys() {
+        let mut sender = SenderKey::new(1);
+        let mut store = SenderKeyStore::default();
+        store.install("alice", &sender.distribution());
+
+        for expected in 0..3 {
+            let (iteration, key) = sender.next_message_key();
+            assert_eq!(iteration, expected);
+            assert_eq!(store.message_key("alice", 1, iteration), Ok(key));
+        }
+    }
+
+    #[test]
+    fn out_of_order_keys_are_usable_once() {
+        let mut sender = SenderKey::new(1);
+        let mut store = SenderKeyStore::default();
+        store.install("alice", &sender.distribution());
+        let keys: Vec<_> = (0..3).map(|_| sender.next_message_key()).collect();
+
+        assert_eq!(store.message_key("alice", 1, 2), Ok(keys[2].1));
+        assert_eq!(store.message_key("alice", 1, 0), Ok(keys[0].1));
+        assert_eq!(store.message_key("alice", 1, 0), Err(SenderKeyError::MessageKeyUsed));
+        assert_eq!(store.message_key("alice", 1, 1), Ok(keys[1].1));
+    }
+
+    #[test]
+    fn late_distribution_cannot_open_earlier_messages() {
+        let mut sender = SenderKey::new(1);
+        sender.next_message_key();
+        let mut store = SenderKeyStore::default();
+        store.install("bob", &sender.distribution());
+        assert_eq!(store.message_key("bob", 1, 0), Err(SenderKeyError::MessageKeyUsed));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_skipping_too_far() {
+        let sender = SenderKey::new(1);
+        let mut store = SenderKeyStore::default();
+        assert_eq!(store.message_key("alice", 1, 0), Err(SenderKeyError::UnknownKey));
+
+        store.install("alice", &sender.distribution());
+        assert_eq!(store.message_key("alice", 2, 0), Err(SenderKeyError::UnknownKey));
+        assert_eq!(store.message_key("alice", 1, MAX_SKIP + 1), Err(SenderKeyError::TooManySkipped));
+
+        store.remove("alice");
+        assert_eq!(store.message_key("alice", 1, 0), Err(SenderKeyError::UnknownKey));
+    }
+
+    #[test]
+    fn keeps_the_previous_chain_after_a_rekey() {
+        let mut old = SenderKey::new(1);
+        let mut new = SenderKey::new(2);
+        let mut store = SenderKeyStore::default();
+        store.install("alice", &old.distribution());
+        store.install("alice", &new.distribution());
+
+        let (iteration, key) = old.next_message_key();
+        assert_eq!(store.message_key("alice", 1, iteration), Ok(key));
+        let (iteration, key) = new.next_message_key();
+        assert_eq!(store.message_key("alice", 2, iteration), Ok(key));
+
+        // A third chain pushes out the oldest
+        store.install("alice", &SenderKey::new(3).distribution());
+        assert_eq!(store.message_key("alice", 1, 1), Err(SenderKeyError::UnknownKey));
+    }
+
+    #[test]
+    fn group_aad_binds_sender_and_position() {
+        assert_ne!(group_aad("alice", 1, 0), group_aad("bob", 1, 0));
+        assert_ne!(group_aad("alice", 1, 0), group_aad("alice", 2, 0));
+        assert_ne!(group_aad("alice", 1, 0), group_aad("alice", 1, 1));
+    }
+}
@@ -0,0 +1,608 @@
+// Group chat over a full mesh. ChatSession sits on top of a SignalingClient: every member gets
+// its own P2PChat, each outgoing message is encrypted once under our sender key and the same
+// frame is sent to every member, and incoming messages are de-duplicated by id. Sender keys go to
+// each member over the pairwise connection, and we rekey whenever someone leaves. Meshes grow
+// quadratically, so groups are capped at MAX_GROUP_SIZE people.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use base64::{decode, encode};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use web_sys::console;
+use zeroize::Zeroize;
+
+use crate::envelope::{Envelope, GroupFrame, MessageContent};
+use crate::handlers::Handlers;
+use crate::sender_key::{group_aad, SenderKey, SenderKeyDistribution, SenderKeyStore};
+use crate::signaling::SignalingClient;
+use crate::{decrypt_message, encrypt_message, envelope_to_js, now_millis};
+
+// Including ourselves
+const MAX_GROUP_SIZE: usize = 8;
+// Message ids remembered for de-duplication
+const SEEN_CAPACITY: usize = 4096;
+
+#[wasm_bindgen(typescript_custom_section)]
+const SESSION_EVENT_TS: &'static str = r#"
+export type MemberStatus = "connecting" | "joined";
+
+export interface MemberInfo {
+  user_id: string;
+  status: MemberStatus;
+}
+
+export type SessionEvent =
+  | SignalingEvent
+  | { type: "member_joined"; user_id: string }
+  | { type: "member_left"; user_id: string; reason: "left" | "removed" | "disconnected" };
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "(event: SessionEvent) => void")]
+    pub type SessionEventCallback;
+
+    #[wasm_bindgen(typescript_type = "(user_id: string) => boolean")]
+    pub type JoinRequestCallback;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum MemberStatus {
+    // We offered a connection and are waiting for the key exchange
+    Connecting,
+    Joined,
+}
+
+#[derive(Serialize)]
+struct MemberInfo<'a> {
+    user_id: &'a str,
+    status: MemberStatus,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LeaveReason {
+    // The user went offline
+    Left,
+    // We removed them with remove_member
+    Removed,
+    // Their connection closed or couldn't be restored
+    Disconnected,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SessionEvent<'a> {
+    MemberJoined {
+        user_id: &'a str,
+    },
+    MemberLeft {
+        user_id: &'a str,
+        reason: LeaveReason,
+    },
+    // A group message, shaped like the client's message events
+    Message {
+        user_id: &'a str,
+        message: &'a Envelope,
+    },
+    Error {
+        user_id: Option<&'a str>,
+        message: &'a str,
+    },
+}
+
+// Bounded set of recently seen message ids
+struct SeenIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenIds {
+    fn new() -> Self {
+        SeenIds {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Record an id; false if it was already seen
+    fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() == SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+}
+
+struct SessionState {
+    members: BTreeMap<String, MemberStatus>,
+    seen: SeenIds,
+    // Our sender key, and the members who have been sent it
+    sender_key: SenderKey,
+    key_holders: HashSet<String>,
+    // Members' sender keys
+    member_keys: SenderKeyStore,
+    on_event_callback: Option<js_sys::Function>,
+    // Asked whether to admit a peer that connected to us without being added
+    on_join_request_callback: Option<js_sys::Function>,
+    // The client event handler
+    handlers: Handlers,
+    closed: bool,
+}
+
+type SharedSession = Rc<RefCell<SessionState>>;
+
+#[wasm_bindgen]
+pub struct ChatSession {
+    client: SignalingClient,
+    state: SharedSession,
+}
+
+#[wasm_bindgen]
+impl ChatSession {
+    // Connect to the signaling server under display_name. Members are added with add_member;
+    // peers who connect to us only join if the on_join_request callback approves them
+    #[wasm_bindgen(constructor)]
+    pub fn new(display_name: String, turn_config_js: JsValue) -> Result<ChatSession, JsValue> {
+        let client = SignalingClient::new(display_name, turn_config_js)?;
+        let state = Rc::new(RefCell::new(SessionState {
+            members: BTreeMap::new(),
+            seen: SeenIds::new(),
+            sender_key: SenderKey::random(),
+            key_holders: HashSet::new(),
+            member_keys: SenderKeyStore::default(),
+            on_event_callback: None,
+            on_join_request_callback: None,
+            handlers: Handlers::default(),
+            closed: false,
+        }));
+
+        let event_client = client.handle();
+        let event_state = state.clone();
+        let onevent_callback = Closure::wrap(Box::new(move |event: JsValue| {
+            handle_signaling_event(&event_client, &event_state, event);
+        }) as Box<dyn FnMut(JsValue)>);
+        client.on_event(onevent_callback.as_ref().clone().unchecked_into());
+        state.borrow_mut().handlers.keep(onevent_callback);
+
+        Ok(ChatSession { client, state })
+    }
+
+    // Set callback for signaling, membership and (de-duplicated) message events
+    #[wasm_bindgen]
+    pub fn on_event(&self, callback: SessionEventCallback) {
+        self.state.borrow_mut().on_event_callback = Some(callback.unchecked_into());
+    }
+
+    // Set the callback that decides whether a peer who connected to us, rather than being added
+    // with add_member, may join. Without one such peers are turned away.
+    #[wasm_bindgen]
+    pub fn on_join_request(&self, callback: JoinRequestCallback) {
+        self.state.borrow_mut().on_join_request_callback = Some(callback.unchecked_into());
+    }
+
+    // Our user id as assigned by the server, once registered
+    #[wasm_bindgen]
+    pub fn get_user_id(&self) -> Option<String> {
+        self.client.get_user_id()
+    }
+
+    // Users known to the signaling server, members or not
+    #[wasm_bindgen]
+    pub fn get_users(&self) -> Result<JsValue, JsValue> {
+        self.client.get_users()
+    }
+
+    // Ask the server for a fresh user list
+    #[wasm_bindgen]
+    pub fn discover(&self) {
+        self.client.discover();
+    }
+
+    // Current members and whether they have joined yet
+    #[wasm_bindgen]
+    pub fn get_members(&self) -> Result<JsValue, JsValue> {
+        let state = self.state.borrow();
+        let members: Vec<MemberInfo> = state
+            .members
+            .iter()
+            .map(|(user_id, status)| MemberInfo { user_id, status: *status })
+            .collect();
+        Ok(serde_wasm_bindgen::to_value(&members)?)
+    }
+
+    // Connect to a user and add them to the group once the key exchange completes
+    #[wasm_bindgen]
+    pub async fn add_member(&self, user_id: String) -> Result<(), JsValue> {
+        {
+            let mut state = self.state.borrow_mut();
+            if state.members.contains_key(&user_id) {
+                return Ok(());
+            }
+            if state.members.len() + 1 >= MAX_GROUP_SIZE {
+                return Err(JsValue::from_str("Group is full"));
+            }
+            state.members.insert(user_id.clone(), MemberStatus::Connecting);
+        }
+
+        if let Err(e) = self.client.connect_to(user_id.clone()).await {
+            self.state.borrow_mut().members.remove(&user_id);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Drop a member and close the connection to them
+    #[wasm_bindgen]
+    pub fn remove_member(&self, user_id: String) {
+        leave(&self.client, &self.state, &user_id, LeaveReason::Removed);
+    }
+
+    // Send a text message to every member, returning its id
+    #[wasm_bindgen]
+    pub fn send_message(&self, message: String) -> Result<String, JsValue> {
+        let envelope = self.fan_out(MessageContent::Text { text: message })?;
+        Ok(envelope.id)
+    }
+
+    // Send a text, typing, receipt, edit, delete, reaction or control message to every member,
+    // returning the envelope that was sent
+    #[wasm_bindgen]
+    pub fn send_content(&self, content: JsValue) -> Result<JsValue, JsValue> {
+        let content: MessageContent = serde_wasm_bindgen::from_value(content)?;
+        if !content.is_user_content() {
+            return Err(JsValue::from_str("This message kind can't be sent with send_content"));
+        }
+        let envelope = self.fan_out(content)?;
+        envelope_to_js(&envelope)
+    }
+
+    // Leave the group: close every member connection and the signaling client, and forget the
+    // sender keys. Dropping the session does the same.
+    #[wasm_bindgen]
+    pub fn close(&self) {
+        let handlers = {
+            let mut state = self.state.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            state.on_event_callback = None;
+            state.on_join_request_callback = None;
+            state.members.clear();
+            state.key_holders.clear();
+            state.member_keys = SenderKeyStore::default();
+            state.sender_key = SenderKey::random();
+            state.handlers.take()
+        };
+        self.client.close();
+        drop(handlers);
+    }
+}
+
+impl Drop for ChatSession {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl ChatSession {
+    // Encrypt one envelope under our sender key and send the frame to every joined member.
+    // Members we can't reach are reported as errors; the send only fails if nobody got the message.
+    fn fan_out(&self, content: MessageContent) -> Result<Envelope, JsValue> {
+        let sender = self.client.get_user_id().ok_or_else(|| JsValue::from_str("Not registered yet"))?;
+        let envelope = Envelope::new(&sender, now_millis(), content);
+
+        let members = {
+            let mut state = self.state.borrow_mut();
+            state.seen.insert(&envelope.id);
+            joined_members(&state)
+        };
+        if members.is_empty() {
+            return Err(JsValue::from_str("No members connected"));
+        }
+
+        // Members who joined since the last rekey, or whose copy failed to send, get the key first
+        for user_id in &members {
+            if !self.state.borrow().key_holders.contains(user_id) {
+                distribute_sender_key(&self.client, &self.state, user_id);
+            }
+        }
+
+        let plaintext = serde_json::to_string(&envelope).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let frame = {
+            let mut state = self.state.borrow_mut();
+            let key_id = state.sender_key.key_id();
+            let (iteration, mut message_key) = state.sender_key.next_message_key();
+            let sealed = encrypt_message(&message_key, plaintext.as_bytes(), &group_aad(&sender, key_id, iteration));
+            message_key.zeroize();
+            let (ciphertext, nonce) = sealed?;
+            GroupFrame {
+                key_id,
+                iteration,
+                nonce: encode(nonce),
+                ciphertext: encode(ciphertext),
+            }
+        };
+
+        let mut delivered = 0;
+        for user_id in &members {
+            if !self.state.borrow().key_holders.contains(user_id) {
+                continue;
+            }
+            match self.client.send_group_frame(user_id, &frame) {
+                Ok(()) => delivered += 1,
+                Err(e) => report_error(&self.state, user_id, &e),
+            }
+        }
+
+        if delivered == 0 {
+            return Err(JsValue::from_str("Message could not be sent to any member"));
+        }
+        Ok(envelope)
+    }
+}
+
+// Track membership from the client's events and pass them on; duplicate messages are dropped
+fn handle_signaling_event(client: &SignalingClient, state: &SharedSession, event: JsValue) {
+    let event_type = string_field(&event, "type").unwrap_or_default();
+    let user_id = string_field(&event, "user_id");
+
+    match (event_type.as_str(), user_id) {
+        ("message", Some(user_id)) => {
+            if !state.borrow().members.contains_key(&user_id) {
+                console::log_1(&format!("Dropping message from non-member {}", user_id).into());
+                return;
+            }
+            let message = js_sys::Reflect::get(&event, &"message".into()).unwrap_or(JsValue::UNDEFINED);
+            if let Some(id) = string_field(&message, "id") {
+                if !state.borrow_mut().seen.insert(&id) {
+                    return;
+                }
+            }
+            forward(state, &event);
+        }
+        ("connection", Some(user_id)) => {
+            forward(state, &event);
+
+            let inner = js_sys::Reflect::get(&event, &"event".into()).unwrap_or(JsValue::UNDEFINED);
+            let inner_type = string_field(&inner, "type").unwrap_or_default();
+            let inner_state = string_field(&inner, "state").unwrap_or_default();
+            match (inner_type.as_str(), inner_state.as_str()) {
+                ("key", "established") => join(client, state, &user_id),
+                ("data_channel", "closed") | ("reconnect", "failed") => {
+                    leave(client, state, &user_id, LeaveReason::Disconnected);
+                }
+                _ => {}
+            }
+        }
+        ("group_frame", Some(user_id)) => receive_group_frame(state, &user_id, event),
+        ("sender_key", Some(user_id)) => install_sender_key(state, &user_id, event),
+        ("user_left", Some(user_id)) => {
+            forward(state, &event);
+            leave(client, state, &user_id, LeaveReason::Left);
+        }
+        _ => forward(state, &event),
+    }
+}
+
+// A connection finished its key exchange. Peers we added are admitted; anyone else needs the
+// application's approval and room in the group. A member who reconnected has a new session that
+// never saw our sender key, so they get it again.
+fn join(client: &SignalingClient, state: &SharedSession, user_id: &str) {
+    let (rejoined, added, approval) = {
+        let mut state = state.borrow_mut();
+        match state.members.get(user_id) {
+            Some(MemberStatus::Joined) => {
+                state.key_holders.remove(user_id);
+                (true, true, None)
+            }
+            Some(MemberStatus::Connecting) => (false, true, None),
+            None => (false, false, state.on_join_request_callback.clone()),
+        }
+    };
+    if rejoined {
+        distribute_sender_key(client, state, user_id);
+        return;
+    }
+
+    // Ask outside the borrow so the callback may use the session
+    let refusal = if added {
+        None
+    } else {
+        let approved = approval.is_some_and(|callback| {
+            callback.call1(&JsValue::NULL, &user_id.into()).ok().and_then(|value| value.as_bool()) == Some(true)
+        });
+        if !approved {
+            Some("Not a member")
+        } else if state.borrow().members.len() + 1 >= MAX_GROUP_SIZE {
+            Some("Group is full")
+        } else {
+            None
+        }
+    };
+
+    match refusal {
+        None => {
+            state.borrow_mut().members.insert(user_id.to_string(), MemberStatus::Joined);
+            emit(state, &SessionEvent::MemberJoined { user_id });
+            distribute_sender_key(client, state, user_id);
+        }
+        Some(message) => {
+            client.disconnect_from(user_id.to_string());
+            emit(state, &SessionEvent::Error {
+                user_id: Some(user_id),
+                message,
+            });
+        }
+    }
+}
+
+fn leave(client: &SignalingClient, state: &SharedSession, user_id: &str, reason: LeaveReason) {
+    let was_member = {
+        let mut state = state.borrow_mut();
+        state.member_keys.remove(user_id);
+        state.key_holders.remove(user_id);
+        state.members.remove(user_id).is_some()
+    };
+    if client.is_connected_to(user_id) {
+        client.disconnect_from(user_id.to_string());
+    }
+    if was_member {
+        emit(state, &SessionEvent::MemberLeft { user_id, reason });
+        rekey(client, state);
+    }
+}
+
+// Start a new sender key so whoever left can't read what we send from now on
+fn rekey(client: &SignalingClient, state: &SharedSession) {
+    let members = {
+        let mut state = state.borrow_mut();
+        state.sender_key = SenderKey::new(state.sender_key.key_id().wrapping_add(1));
+        state.key_holders.clear();
+        joined_members(&state)
+    };
+    for user_id in &members {
+        distribute_sender_key(client, state, user_id);
+    }
+}
+
+// Send our current sender key to a member over our pairwise connection
+fn distribute_sender_key(client: &SignalingClient, state: &SharedSession, user_id: &str) {
+    let Some(sender) = client.get_user_id() else {
+        return;
+    };
+    let distribution = state.borrow().sender_key.distribution();
+    let envelope = Envelope::new(&sender, now_millis(), MessageContent::SenderKey {
+        key_id: distribution.key_id,
+        chain_key: encode(distribution.chain_key),
+        iteration: distribution.iteration,
+    });
+    match client.send_prepared_envelope(user_id, &envelope) {
+        Ok(()) => {
+            state.borrow_mut().key_holders.insert(user_id.to_string());
+        }
+        Err(e) => report_error(state, user_id, &e),
+    }
+}
+
+fn install_sender_key(state: &SharedSession, user_id: &str, event: JsValue) {
+    if !state.borrow().members.contains_key(user_id) {
+        console::log_1(&format!("Dropping sender key from non-member {}", user_id).into());
+        return;
+    }
+    let content: MessageContent = match serde_wasm_bindgen::from_value(event) {
+        Ok(content) => content,
+        Err(e) => {
+            console::log_1(&format!("Malformed sender key from {}: {}", user_id, e).into());
+            return;
+        }
+    };
+    let MessageContent::SenderKey { key_id, chain_key, iteration } = content else {
+        return;
+    };
+    let chain_key = match decode(&chain_key).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()) {
+        Some(chain_key) => chain_key,
+        None => {
+            console::log_1(&format!("Malformed sender key from {}", user_id).into());
+            return;
+        }
+    };
+    let distribution = SenderKeyDistribution { key_id, chain_key, iteration };
+    state.borrow_mut().member_keys.install(user_id, &distribution);
+}
+
+// Open a member's group frame with their sender key and pass the message on
+fn receive_group_frame(state: &SharedSession, user_id: &str, event: JsValue) {
+    if state.borrow().members.get(user_id) != Some(&MemberStatus::Joined) {
+        console::log_1(&format!("Dropping group frame from non-member {}", user_id).into());
+        return;
+    }
+    let frame: GroupFrame = match serde_wasm_bindgen::from_value(event) {
+        Ok(frame) => frame,
+        Err(e) => {
+            console::log_1(&format!("Malformed group frame from {}: {}", user_id, e).into());
+            return;
+        }
+    };
+
+    let plaintext = match open_group_frame(state, user_id, &frame) {
+        Ok(plaintext) => plaintext,
+        Err(e) => {
+            report_error(state, user_id, &e);
+            return;
+        }
+    };
+    let message = Envelope::from_plaintext(&plaintext, user_id, now_millis());
+    if message.sender != user_id {
+        report_error(state, user_id, &JsValue::from_str("Group message rejected: wrong sender"));
+        return;
+    }
+    if !state.borrow_mut().seen.insert(&message.id) {
+        return;
+    }
+    emit(state, &SessionEvent::Message { user_id, message: &message });
+}
+
+fn open_group_frame(state: &SharedSession, user_id: &str, frame: &GroupFrame) -> Result<String, JsValue> {
+    let nonce = decode(&frame.nonce).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let ciphertext = decode(&frame.ciphertext).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    let mut message_key = state
+        .borrow_mut()
+        .member_keys
+        .message_key(user_id, frame.key_id, frame.iteration)
+        .map_err(|e| JsValue::from_str(&format!("Group message rejected: {}", e)))?;
+    let aad = group_aad(user_id, frame.key_id, frame.iteration);
+    let plaintext = decrypt_message(&message_key, &nonce, &ciphertext, &aad);
+    message_key.zeroize();
+
+    let plaintext = plaintext.map_err(|_| JsValue::from_str("Group message rejected: decryption failed"))?;
+    String::from_utf8(plaintext).map_err(|_| JsValue::from_str("Group message rejected: not text"))
+}
+
+fn joined_members(state: &SessionState) -> Vec<String> {
+    state
+        .members
+        .iter()
+        .filter(|(_, status)| **status == MemberStatus::Joined)
+        .map(|(user_id, _)| user_id.clone())
+        .collect()
+}
+
+fn string_field(object: &JsValue, name: &str) -> Option<String> {
+    js_sys::Reflect::get(object, &name.into()).ok().and_then(|value| value.as_string())
+}
+
+fn forward(state: &SharedSession, event: &JsValue) {
+    // Release the borrow before calling into JS so the callback may use the session
+    let callback = state.borrow().on_event_callback.clone();
+    if let Some(callback) = callback {
+        let _ = callback.call1(&JsValue::NULL, event);
+    }
+}
+
+fn report_error(state: &SharedSession, user_id: &str, error: &JsValue) {
+    let message = error.as_string().unwrap_or_else(|| format!("{:?}", error));
+    emit(state, &SessionEvent::Error {
+        user_id: Some(user_id),
+        message: &message,
+    });
+}
+
+fn emit(state: &SharedSession, event: &SessionEvent) {
+    if let Ok(value) = event.serialize(&serde_wasm_bindgen::Serializer::json_compatible()) {
+        forward(state, &value);
+    }
+}
@@ -0,0 +1,330 @@
+// Compact codes for serverless signaling. When the signaling server can't be reached, the two
+// sides swap their offer and answer by hand instead: pasted into a chat or an email, or shown as
+// a QR code. A code carries the whole session description with every gathered candidate, so no
+// trickled candidates have to follow it. Constant lines and ones browsers don't need are
+// stripped, the rest is deflated, and the result is base64url-encoded behind a version byte and
+// the description type.
+//
+// This module is plain Rust with no browser dependencies.
+
+use std::fmt;
+
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+
+const CODE_VERSION: u8 = 1;
+
+// Largest session description a code may expand to
+const MAX_SDP_BYTES: usize = 64 * 1024;
+
+// Session lines restored by the decoder when missing; only stripped in their usual place
+const VERSION_LINE: &str = "v=0";
+const SESSION_NAME_LINE: &str = "s=-";
+const TIMING_LINE: &str = "t=0 0";
+
+// Lines dropped outright: codes are never trickled, and the rest only matter for features the
+// browser falls back from quietly
+const DROPPED_LINES: &[&str] = &["a=ice-options:trickle", "a=extmap-allow-mixed"];
+const DROPPED_PREFIXES: &[&str] = &["a=msid-semantic:"];
+
+// Candidate extensions that repeat what the description already says or only help trickling
+const DROPPED_CANDIDATE_EXTENSIONS: &[&str] = &["generation", "network-id", "network-cost", "ufrag"];
+
+// Fields of a candidate line before its extensions:
+// candidate:foundation component transport priority address port typ type
+const CANDIDATE_FIELDS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DescriptionType {
+    Offer,
+    Answer,
+}
+
+impl DescriptionType {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            DescriptionType::Offer => "offer",
+            DescriptionType::Answer => "answer",
+        }
+    }
+
+    pub(crate) fn parse(type_: &str) -> Option<Self> {
+        match type_ {
+            "offer" => Some(DescriptionType::Offer),
+            "answer" => Some(DescriptionType::Answer),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            DescriptionType::Offer => 0,
+            DescriptionType::Answer => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(DescriptionType::Offer),
+            1 => Some(DescriptionType::Answer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SignalCodeError {
+    Malformed,
+    UnsupportedVersion(u8),
+    TooLarge,
+}
+
+impl fmt::Display for SignalCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalCodeError::Malformed => write!(f, "Malformed connection code"),
+            SignalCodeError::UnsupportedVersion(version) => write!(f, "Unsupported connection code version {}", version),
+            SignalCodeError::TooLarge => write!(f, "Connection code is too large"),
+        }
+    }
+}
+
+pub(crate) fn encode(type_: DescriptionType, sdp: &str) -> String {
+    let compact = compact_sdp(sdp);
+    let mut bytes = vec![CODE_VERSION, type_.to_byte()];
+    bytes.extend(compress_to_vec(compact.as_bytes(), 9));
+    encode_config(bytes, URL_SAFE_NO_PAD)
+}
+
+// Whitespace is ignored, since codes pasted from emails or chats are often wrapped
+pub(crate) fn decode(code: &str) -> Result<(DescriptionType, String), SignalCodeError> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let bytes = decode_config(code, URL_SAFE_NO_PAD).map_err(|_| SignalCodeError::Malformed)?;
+    let (&version, rest) = bytes.split_first().ok_or(SignalCodeError::Malformed)?;
+    if version != CODE_VERSION {
+        return Err(SignalCodeError::UnsupportedVersion(version));
+    }
+    let (&type_byte, deflated) = rest.split_first().ok_or(SignalCodeError::Malformed)?;
+    let type_ = DescriptionType::from_byte(type_byte).ok_or(SignalCodeError::Malformed)?;
+
+    let compact = decompress_to_vec_with_limit(deflated, MAX_SDP_BYTES).map_err(|e| match e.status {
+        TINFLStatus::HasMoreOutput => SignalCodeError::TooLarge,
+        _ => SignalCodeError::Malformed,
+    })?;
+    let compact = String::from_utf8(compact).map_err(|_| SignalCodeError::Malformed)?;
+    Ok((type_, expand_sdp(&compact)))
+}
+
+fn compact_sdp(sdp: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut previous = "";
+    for (index, line) in sdp.lines().map(str::trim_end).enumerate() {
+        let restorable = (index == 0 && line == VERSION_LINE)
+            || (previous.starts_with("o=") && line == SESSION_NAME_LINE)
+            || (previous == SESSION_NAME_LINE && line == TIMING_LINE);
+        let dropped = line.is_empty()
+            || DROPPED_LINES.contains(&line)
+            || DROPPED_PREFIXES.iter().any(|prefix| line.starts_with(prefix));
+        if !restorable && !dropped {
+            lines.push(compact_candidate(line));
+        }
+        previous = line;
+    }
+    lines.join("\n")
+}
+
+fn expand_sdp(compact: &str) -> String {
+    let mut lines: Vec<&str> = compact.lines().collect();
+    if !lines.first().is_some_and(|line| line.starts_with("v=")) {
+        lines.insert(0, VERSION_LINE);
+    }
+    if let Some(origin) = lines.iter().position(|line| line.starts_with("o=")) {
+        if !lines.get(origin + 1).is_some_and(|line| line.starts_with("s=")) {
+            lines.insert(origin + 1, SESSION_NAME_LINE);
+        }
+        if !lines.get(origin + 2).is_some_and(|line| line.starts_with("t=")) {
+            lines.insert(origin + 2, TIMING_LINE);
+        }
+    }
+
+    let mut sdp = lines.join("\r\n");
+    sdp.push_str("\r\n");
+    sdp
+}
+
+// Drop the redundant extensions of a candidate line; anything that doesn't parse is kept as is
+fn compact_candidate(line: &str) -> String {
+    if !line.starts_with("a=candidate:") {
+        return line.to_string();
+    }
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() < CANDIDATE_FIELDS || !(fields.len() - CANDIDATE_FIELDS).is_multiple_of(2) {
+        return line.to_string();
+    }
+
+    let mut kept = fields[..CANDIDATE_FIELDS].to_vec();
+    for pair in fields[CANDIDATE_FIELDS..].chunks(2) {
+        if !DROPPED_CANDIDATE_EXTENSIONS.contains(&pair[0]) {
+            kept.extend_from_slice(pair);
+        }
+    }
+    kept.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME_OFFER: &str = "v=0\r\n\
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+a=extmap-allow-mixed\r\n\
+a=msid-semantic: WMS\r\n\
+m=application 46243 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 203.0.113.9\r\n\
+a=candidate:1467250027 1 udp 2122260223 192.168.1.5 46243 typ host generation 0 network-id 1 network-cost 10\r\n\
+a=candidate:2998512719 1 tcp 1518280447 192.168.1.5 9 typ host tcptype active generation 0 network-id 1 network-cost 10\r\n\
+a=candidate:842163049 1 udp 1686052607 203.0.113.9 46243 typ srflx raddr 192.168.1.5 rport 46243 generation 0 network-id 1 network-cost 10\r\n\
+a=candidate:3120498542 1 udp 41885439 198.51.100.2 61234 typ relay raddr 203.0.113.9 rport 46243 generation 0 ufrag k9Lx network-id 1 network-cost 10\r\n\
+a=ice-ufrag:k9Lx\r\n\
+a=ice-pwd:Jp2uK0tW5iE4xFqZ8mRbYc3N\r\n\
+a=ice-options:trickle\r\n\
+a=fingerprint:sha-256 6B:8B:5D:EA:59:04:20:23:29:C8:87:1C:CD:87:32:BE:DD:8C:66:A5:8E:50:55:EA:20:C8:F5:4C:8B:9F:3F:2D\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:262144\r\n";
+
+    const CHROME_EXPANDED: &str = "v=0\r\n\
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+m=application 46243 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 203.0.113.9\r\n\
+a=candidate:1467250027 1 udp 2122260223 192.168.1.5 46243 typ host\r\n\
+a=candidate:2998512719 1 tcp 1518280447 192.168.1.5 9 typ host tcptype active\r\n\
+a=candidate:842163049 1 udp 1686052607 203.0.113.9 46243 typ srflx raddr 192.168.1.5 rport 46243\r\n\
+a=candidate:3120498542 1 udp 41885439 198.51.100.2 61234 typ relay raddr 203.0.113.9 rport 46243\r\n\
+a=ice-ufrag:k9Lx\r\n\
+a=ice-pwd:Jp2uK0tW5iE4xFqZ8mRbYc3N\r\n\
+a=fingerprint:sha-256 6B:8B:5D:EA:59:04:20:23:29:C8:87:1C:CD:87:32:BE:DD:8C:66:A5:8E:50:55:EA:20:C8:F5:4C:8B:9F:3F:2D\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:262144\r\n";
+
+    const FIREFOX_OFFER: &str = "v=0\r\n\
+o=mozilla...THIS_IS_SDPARTA-128.0 1865343574958618128 0 IN IP4 0.0.0.0\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=sendrecv\r\n\
+a=fingerprint:sha-256 1F:0C:66:2B:7E:92:A5:48:90:3C:D1:5E:77:21:08:6A:C3:BF:42:19:E0:5D:8B:F6:31:A7:2C:94:6E:D0:85:13\r\n\
+a=group:BUNDLE 0\r\n\
+a=ice-options:trickle\r\n\
+a=msid-semantic:WMS *\r\n\
+m=application 56803 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+c=IN IP4 203.0.113.7\r\n\
+a=candidate:0 1 UDP 2122252543 192.168.1.20 56803 typ host\r\n\
+a=candidate:2 1 TCP 2105524479 192.168.1.20 9 typ host tcptype active\r\n\
+a=candidate:1 1 UDP 1686052863 203.0.113.7 56803 typ srflx raddr 192.168.1.20 rport 56803\r\n\
+a=sendrecv\r\n\
+a=end-of-candidates\r\n\
+a=ice-pwd:6a2f0c8e1d4b7a9f3e5c2d1b0a9f8e7d\r\n\
+a=ice-ufrag:8c4e1a2b\r\n\
+a=mid:0\r\n\
+a=setup:actpass\r\n\
+a=sctp-port:5000\r\n\
+a=max-message-size:1073741823\r\n";
+
+    fn without_lines(sdp: &str, dropped: &[&str]) -> String {
+        sdp.split_inclusive("\r\n")
+            .filter(|line| !dropped.contains(&line.trim_end()))
+            .collect()
+    }
+
+    fn raw_code(version: u8, type_byte: u8, sdp: &[u8]) -> String {
+        let mut bytes = vec![version, type_byte];
+        bytes.extend(compress_to_vec(sdp, 9));
+        encode_config(bytes, URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn round_trips_a_chrome_offer() {
+        let code = encode(DescriptionType::Offer, CHROME_OFFER);
+        assert!(code.len() < CHROME_OFFER.len());
+        assert!(code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+
+        assert_eq!(decode(&code), Ok((DescriptionType::Offer, CHROME_EXPANDED.to_string())));
+    }
+
+    #[test]
+    fn round_trips_a_firefox_offer() {
+        let code = encode(DescriptionType::Offer, FIREFOX_OFFER);
+        let expected = without_lines(FIREFOX_OFFER, &["a=ice-options:trickle", "a=msid-semantic:WMS *"]);
+        assert_eq!(decode(&code), Ok((DescriptionType::Offer, expected)));
+    }
+
+    #[test]
+    fn keeps_the_description_type() {
+        let code = encode(DescriptionType::Answer, CHROME_OFFER);
+        assert_eq!(decode(&code).map(|(type_, _)| type_), Ok(DescriptionType::Answer));
+    }
+
+    #[test]
+    fn strips_and_restores_the_constant_session_lines() {
+        let compact = compact_sdp(CHROME_OFFER);
+        assert!(compact.starts_with("o=- 4611731400430051336 2 IN IP4 127.0.0.1\na=group:BUNDLE 0\n"));
+
+        let expanded = expand_sdp(&compact);
+        assert!(expanded.starts_with("v=0\r\no=- 4611731400430051336 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n"));
+
+        // Lines with other values are kept where they are
+        let named = CHROME_OFFER.replace("s=-\r\n", "s=chat\r\n");
+        let compact = compact_sdp(&named);
+        assert!(compact.contains("\ns=chat\nt=0 0\n"));
+        assert!(expand_sdp(&compact).starts_with("v=0\r\no=- 4611731400430051336 2 IN IP4 127.0.0.1\r\ns=chat\r\nt=0 0\r\n"));
+    }
+
+    #[test]
+    fn ignores_whitespace_in_pasted_codes() {
+        let code = encode(DescriptionType::Offer, CHROME_OFFER);
+        let wrapped: String = code
+            .as_bytes()
+            .chunks(60)
+            .map(|line| format!("  {}\r\n", std::str::from_utf8(line).unwrap()))
+            .collect();
+        assert_eq!(decode(&wrapped), decode(&code));
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        let sdp = compact_sdp(CHROME_OFFER);
+        assert_eq!(
+            decode(&raw_code(CODE_VERSION + 1, 0, sdp.as_bytes())),
+            Err(SignalCodeError::UnsupportedVersion(CODE_VERSION + 1))
+        );
+        assert_eq!(decode(&raw_code(CODE_VERSION, 2, sdp.as_bytes())), Err(SignalCodeError::Malformed));
+        assert_eq!(decode(&encode_config([CODE_VERSION], URL_SAFE_NO_PAD)), Err(SignalCodeError::Malformed));
+        assert_eq!(decode("not a code!"), Err(SignalCodeError::Malformed));
+
+        let mut truncated = raw_code(CODE_VERSION, 0, sdp.as_bytes());
+        truncated.truncate(truncated.len() / 2);
+        assert!(decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn rejects_codes_that_inflate_past_the_limit() {
+        let oversized = "a=x\n".repeat(MAX_SDP_BYTES / 4 + 1);
+        assert_eq!(
+            decode(&raw_code(CODE_VERSION, 0, oversized.as_bytes())),
+            Err(SignalCodeError::TooLarge)
+        );
+
+        let at_limit = "a".repeat(MAX_SDP_BYTES);
+        assert!(decode(&raw_code(CODE_VERSION, 0, at_limit.as_bytes())).is_ok());
+    }
+}
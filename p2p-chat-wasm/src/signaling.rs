@@ -0,0 +1,826 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{console, MediaStream, MessageEvent, WebSocket};
+
+use crate::envelope::{Envelope, GroupFrame};
+use crate::events::ChatEventCallback;
+use crate::handlers::Handlers;
+use crate::transfer::TransferStore;
+use crate::P2PChat;
+
+#[wasm_bindgen(typescript_custom_section)]
+const SIGNALING_EVENT_TS: &'static str = r#"
+export interface UserInfo {
+  user_id: string;
+  display_name: string;
+}
+
+export interface MessageEnvelope {
+  id: string;
+  sender: string;
+  timestamp: number;
+  kind: "text" | "typing" | "receipt" | "edit" | "delete" | "reaction" | "control";
+  [field: string]: unknown;
+}
+
+export interface FileProgress {
+  transfer_id: string;
+  direction: "send" | "receive";
+  name: string;
+  transferred: number;
+  total: number;
+  status: "in_progress" | "complete" | "failed";
+  reason?: string;
+}
+
+export type SignalingEvent =
+  | { type: "registered"; user_id: string }
+  | { type: "user_list"; users: UserInfo[] }
+  | { type: "user_joined"; user_id: string; display_name: string }
+  | { type: "user_left"; user_id: string }
+  | { type: "connection"; user_id: string; event: ChatEvent }
+  | { type: "message"; user_id: string; message: MessageEnvelope }
+  | { type: "file"; user_id: string; transfer_id: string; name: string; size: number; sha256: string; data: Uint8Array }
+  | ({ type: "file_progress"; user_id: string } & FileProgress)
+  | { type: "track"; user_id: string; track: MediaStreamTrack; streams: MediaStream[]; kind: string }
+  | { type: "group_frame"; user_id: string; kind: "group"; key_id: number; iteration: number; nonce: string; ciphertext: string }
+  | { type: "sender_key"; user_id: string; kind: "sender_key"; key_id: number; chain_key: string; iteration: number }
+  | { type: "error"; user_id: string | null; message: string };
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "(event: SignalingEvent) => void")]
+    pub type SignalingEventCallback;
+}
+
+// Messages sent to the signaling server (mirrors web-server's SignalMessage)
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum SignalMessage {
+    #[serde(rename = "register")]
+    Register {
+        display_name: String,
+    },
+    #[serde(rename = "discover")]
+    Discover,
+    #[serde(rename = "offer")]
+    Offer {
+        target_user_id: String,
+        offer: serde_json::Value,
+    },
+    #[serde(rename = "answer")]
+    Answer {
+        target_user_id: String,
+        answer: serde_json::Value,
+    },
+    #[serde(rename = "ice_candidate")]
+    IceCandidate {
+        target_user_id: String,
+        candidate: serde_json::Value,
+    },
+}
+
+// Messages received from the signaling server (mirrors web-server's ServerMessage)
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum ServerMessage {
+    #[serde(rename = "registered")]
+    Registered {
+        user_id: String,
+    },
+    #[serde(rename = "user_list")]
+    UserList {
+        users: Vec<UserInfo>,
+    },
+    #[serde(rename = "user_joined")]
+    UserJoined {
+        user_id: String,
+        display_name: String,
+    },
+    #[serde(rename = "user_left")]
+    UserLeft {
+        user_id: String,
+    },
+    #[serde(rename = "offer")]
+    Offer {
+        from_user_id: String,
+        offer: serde_json::Value,
+    },
+    #[serde(rename = "answer")]
+    Answer {
+        from_user_id: String,
+        answer: serde_json::Value,
+    },
+    #[serde(rename = "ice_candidate")]
+    IceCandidate {
+        from_user_id: String,
+        candidate: serde_json::Value,
+    },
+    #[serde(rename = "error")]
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserInfo {
+    user_id: String,
+    display_name: String,
+}
+
+// Events delivered to the page through the on_event callback
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum SignalingEvent<'a> {
+    #[serde(rename = "registered")]
+    Registered {
+        user_id: &'a str,
+    },
+    #[serde(rename = "user_list")]
+    UserList {
+        users: &'a [UserInfo],
+    },
+    #[serde(rename = "user_joined")]
+    UserJoined {
+        user_id: &'a str,
+        display_name: &'a str,
+    },
+    #[serde(rename = "user_left")]
+    UserLeft {
+        user_id: &'a str,
+    },
+    // A ChatEvent from the peer's connection
+    #[serde(rename = "connection")]
+    Connection {
+        user_id: &'a str,
+        event: &'a serde_json::Value,
+    },
+    #[serde(rename = "message")]
+    Message {
+        user_id: &'a str,
+        message: &'a Envelope,
+    },
+    #[serde(rename = "error")]
+    Error {
+        user_id: Option<&'a str>,
+        message: &'a str,
+    },
+}
+
+// Shared state between the client handle and the WebSocket/peer callbacks
+struct SignalingState {
+    socket: WebSocket,
+    display_name: String,
+    turn_config: JsValue,
+    user_id: Option<String>,
+    users: Vec<UserInfo>,
+    peers: HashMap<String, Rc<P2PChat>>,
+    // File transfers per user, kept when a connection is replaced so they resume on the new one
+    transfers: HashMap<String, TransferStore>,
+    on_event_callback: Option<js_sys::Function>,
+    // Closures installed as the socket's on* handlers
+    handlers: Handlers,
+    // Set by close(); the client can't be used again
+    closed: bool,
+}
+
+type SharedState = Rc<RefCell<SignalingState>>;
+
+#[wasm_bindgen]
+pub struct SignalingClient {
+    state: SharedState,
+    // Only the handle returned to JS closes the client when dropped; handles made with handle()
+    // share it with the layers built on top
+    owner: bool,
+}
+
+#[wasm_bindgen]
+impl SignalingClient {
+    // Connect to the signaling server at /ws and register under display_name
+    #[wasm_bindgen(constructor)]
+    pub fn new(display_name: String, turn_config_js: JsValue) -> Result<SignalingClient, JsValue> {
+        let url = signaling_url()?;
+        console::log_1(&format!("Connecting to signaling server at {}", url).into());
+
+        let socket = WebSocket::new(&url)?;
+        let state = Rc::new(RefCell::new(SignalingState {
+            socket: socket.clone(),
+            display_name,
+            turn_config: turn_config_js,
+            user_id: None,
+            users: Vec::new(),
+            peers: HashMap::new(),
+            transfers: HashMap::new(),
+            on_event_callback: None,
+            handlers: Handlers::default(),
+            closed: false,
+        }));
+
+        // Register as soon as the socket is open
+        let open_state = state.clone();
+        let onopen_callback = Closure::wrap(Box::new(move |_| {
+            let display_name = open_state.borrow().display_name.clone();
+            send_signal(&open_state, &SignalMessage::Register { display_name });
+        }) as Box<dyn FnMut(web_sys::Event)>);
+
+        // Dispatch server messages
+        let message_state = state.clone();
+        let onmessage_callback = Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let Some(text) = event.data().as_string() {
+                match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(message) => handle_server_message(&message_state, message),
+                    Err(e) => console::log_1(&format!("Invalid signaling message: {}", e).into()),
+                }
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+
+        // Report socket loss
+        let close_state = state.clone();
+        let onclose_callback = Closure::wrap(Box::new(move |_| {
+            emit(&close_state, &SignalingEvent::Error {
+                user_id: None,
+                message: "Signaling connection closed",
+            });
+        }) as Box<dyn FnMut(web_sys::Event)>);
+
+        // The close event that follows reports the loss; this only logs the cause
+        let onerror_callback = Closure::wrap(Box::new(move |event: web_sys::Event| {
+            console::log_2(&"Signaling connection error:".into(), &event);
+        }) as Box<dyn FnMut(web_sys::Event)>);
+
+        socket.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
+
+        {
+            let mut state = state.borrow_mut();
+            state.handlers.keep(onopen_callback);
+            state.handlers.keep(onmessage_callback);
+            state.handlers.keep(onclose_callback);
+            state.handlers.keep(onerror_callback);
+        }
+
+        Ok(SignalingClient { state, owner: true })
+    }
+
+    // Set callback for signaling, connection and message events
+    #[wasm_bindgen]
+    pub fn on_event(&self, callback: SignalingEventCallback) {
+        self.state.borrow_mut().on_event_callback = Some(callback.unchecked_into());
+    }
+
+    // Our user id as assigned by the server, once registered
+    #[wasm_bindgen]
+    pub fn get_user_id(&self) -> Option<String> {
+        self.state.borrow().user_id.clone()
+    }
+
+    // Currently known users, excluding ourselves
+    #[wasm_bindgen]
+    pub fn get_users(&self) -> Result<JsValue, JsValue> {
+        let state = self.state.borrow();
+        Ok(serde_wasm_bindgen::to_value(&state.users)?)
+    }
+
+    // Ask the server for a fresh user list
+    #[wasm_bindgen]
+    pub fn discover(&self) {
+        send_signal(&self.state, &SignalMessage::Discover);
+    }
+
+    // Open a chat with another user; offer, answer and candidates are exchanged automatically
+    #[wasm_bindgen]
+    pub async fn connect_to(&self, user_id: String) -> Result<(), JsValue> {
+        // The peer may have connected to us first; that connection is already being set up
+        if self.state.borrow().peers.contains_key(&user_id) {
+            return Ok(());
+        }
+        let chat = create_peer(&self.state, &user_id)?;
+
+        let offer = chat.create_offer().await?;
+
+        // If the peer's offer crossed ours and we yielded, our offer was rolled back
+        if !chat.has_pending_offer() {
+            return Ok(());
+        }
+        let offer: serde_json::Value = serde_wasm_bindgen::from_value(offer)?;
+        send_signal(&self.state, &SignalMessage::Offer {
+            target_user_id: user_id,
+            offer,
+        });
+
+        Ok(())
+    }
+
+    // Send an encrypted text message to a connected user, returning its message id
+    #[wasm_bindgen]
+    pub fn send_message(&self, user_id: String, message: String) -> Result<String, JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.send_message(message),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Send any message kind (typing, receipt, edit, ...) to a connected user
+    #[wasm_bindgen]
+    pub fn send_content(&self, user_id: String, content: JsValue) -> Result<JsValue, JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.send_content(content),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Send a file to a connected user, returning the transfer id
+    #[wasm_bindgen]
+    pub fn send_file(&self, user_id: String, name: String, data: Vec<u8>) -> Result<String, JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.send_file(name, data),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Start a call with a connected user, returning the local stream
+    #[wasm_bindgen]
+    pub async fn start_call(&self, user_id: String, audio: bool, video: bool) -> Result<MediaStream, JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.start_call(audio, video).await,
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Mute or unmute our "audio" or "video" tracks in the call with a user
+    #[wasm_bindgen]
+    pub fn set_muted(&self, user_id: String, kind: String, muted: bool) -> Result<(), JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.set_muted(kind, muted),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Share a screen in place of the camera in the call with a user
+    #[wasm_bindgen]
+    pub async fn start_screen_share(&self, user_id: String) -> Result<MediaStream, JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.start_screen_share().await,
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub async fn stop_screen_share(&self, user_id: String) -> Result<(), JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.stop_screen_share().await,
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // End the call with a user, keeping the chat connected
+    #[wasm_bindgen]
+    pub fn hang_up(&self, user_id: String) -> Result<(), JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.hang_up(),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Outgoing frames queued for a user while their data channel drains
+    #[wasm_bindgen]
+    pub fn get_queue_depth(&self, user_id: String) -> u32 {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        chat.map_or(0, |chat| chat.get_queue_depth())
+    }
+
+    // Connection stats summary for the chat with a user
+    #[wasm_bindgen(unchecked_return_type = "StatsSummary")]
+    pub async fn get_stats(&self, user_id: String) -> Result<JsValue, JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.get_stats().await,
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Short authentication string for the chat with a user, once keys are established
+    #[wasm_bindgen]
+    pub fn get_authentication_string(&self, user_id: String) -> Option<String> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        chat.and_then(|chat| chat.get_authentication_string())
+    }
+
+    // Mark a user as verified after comparing authentication strings out of band
+    #[wasm_bindgen]
+    pub fn mark_peer_verified(&self, user_id: String) -> Result<(), JsValue> {
+        let chat = self.state.borrow().peers.get(&user_id).cloned();
+        match chat {
+            Some(chat) => chat.mark_peer_verified(),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Close every peer connection and the signaling socket. Dropping the client does the same.
+    #[wasm_bindgen]
+    pub fn close(&self) {
+        let (peers, handlers) = {
+            let mut state = self.state.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+
+            // Clear the handlers first so the close event isn't reported
+            state.socket.set_onopen(None);
+            state.socket.set_onmessage(None);
+            state.socket.set_onclose(None);
+            state.socket.set_onerror(None);
+            if let Err(e) = state.socket.close() {
+                console::log_2(&"Failed to close signaling connection:".into(), &e);
+            }
+
+            state.on_event_callback = None;
+            state.transfers.clear();
+            (std::mem::take(&mut state.peers), state.handlers.take())
+        };
+
+        // Outside the borrow: closing a chat may call back into the client
+        for chat in peers.into_values() {
+            chat.close();
+        }
+        drop(handlers);
+    }
+
+    // Drop the chat with a user, abandoning unfinished file transfers
+    #[wasm_bindgen]
+    pub fn disconnect_from(&self, user_id: String) {
+        let chat = {
+            let mut state = self.state.borrow_mut();
+            state.transfers.remove(&user_id);
+            state.peers.remove(&user_id)
+        };
+        if let Some(chat) = chat {
+            chat.close();
+        }
+    }
+}
+
+impl Drop for SignalingClient {
+    fn drop(&mut self) {
+        if self.owner {
+            self.close();
+        }
+    }
+}
+
+impl SignalingClient {
+    // Another handle on the same client, for layers built on top of it
+    pub(crate) fn handle(&self) -> SignalingClient {
+        SignalingClient {
+            state: self.state.clone(),
+            owner: false,
+        }
+    }
+
+    // Send one already built envelope to a connected user
+    pub(crate) fn send_prepared_envelope(&self, user_id: &str, envelope: &Envelope) -> Result<(), JsValue> {
+        let chat = self.state.borrow().peers.get(user_id).cloned();
+        match chat {
+            Some(chat) => chat.send_prepared_envelope(envelope),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    // Send a group frame, encrypted once for the whole group, to a connected user
+    pub(crate) fn send_group_frame(&self, user_id: &str, frame: &GroupFrame) -> Result<(), JsValue> {
+        let chat = self.state.borrow().peers.get(user_id).cloned();
+        match chat {
+            Some(chat) => chat.send_group_frame(frame),
+            None => Err(JsValue::from_str("Not connected to user")),
+        }
+    }
+
+    pub(crate) fn is_connected_to(&self, user_id: &str) -> bool {
+        self.state.borrow().peers.contains_key(user_id)
+    }
+}
+
+// Build the ws:// or wss:// URL of the signaling endpoint from the page location
+fn signaling_url() -> Result<String, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window found"))?;
+    let location = window.location();
+    let scheme = if location.protocol()? == "https:" { "wss" } else { "ws" };
+    Ok(format!("{}://{}/ws", scheme, location.host()?))
+}
+
+fn send_signal(state: &SharedState, message: &SignalMessage) {
+    let socket = state.borrow().socket.clone();
+    match serde_json::to_string(message) {
+        Ok(json) => {
+            if let Err(e) = socket.send_with_str(&json) {
+                console::log_2(&"Failed to send signaling message:".into(), &e);
+            }
+        }
+        Err(e) => console::log_1(&format!("Failed to encode signaling message: {}", e).into()),
+    }
+}
+
+fn emit(state: &SharedState, event: &SignalingEvent) {
+    // Release the borrow before calling into JS so the callback may use the client
+    let callback = state.borrow().on_event_callback.clone();
+    if let Some(callback) = callback {
+        if let Ok(value) = event.serialize(&serde_wasm_bindgen::Serializer::json_compatible()) {
+            let _ = callback.call1(&JsValue::NULL, &value);
+        }
+    }
+}
+
+fn handle_server_message(state: &SharedState, message: ServerMessage) {
+    match message {
+        ServerMessage::Registered { user_id } => {
+            state.borrow_mut().user_id = Some(user_id.clone());
+            emit(state, &SignalingEvent::Registered { user_id: &user_id });
+        }
+        ServerMessage::UserList { users } => {
+            let users: Vec<UserInfo> = {
+                let mut signaling = state.borrow_mut();
+                let own_id = signaling.user_id.clone();
+                signaling.users = users
+                    .into_iter()
+                    .filter(|user| Some(&user.user_id) != own_id.as_ref())
+                    .collect();
+                signaling.users.clone()
+            };
+            emit(state, &SignalingEvent::UserList { users: &users });
+        }
+        ServerMessage::UserJoined { user_id, display_name } => {
+            state.borrow_mut().users.push(UserInfo {
+                user_id: user_id.clone(),
+                display_name: display_name.clone(),
+            });
+            emit(state, &SignalingEvent::UserJoined {
+                user_id: &user_id,
+                display_name: &display_name,
+            });
+        }
+        ServerMessage::UserLeft { user_id } => {
+            let chat = {
+                let mut signaling = state.borrow_mut();
+                signaling.users.retain(|user| user.user_id != user_id);
+                signaling.peers.remove(&user_id)
+            };
+            if let Some(chat) = chat {
+                chat.close();
+            }
+            emit(state, &SignalingEvent::UserLeft { user_id: &user_id });
+        }
+        ServerMessage::Offer { from_user_id, offer } => {
+            // An offer for an existing connection is an ICE restart, a renegotiation, or an offer
+            // that crossed ours; perfect negotiation sorts those out. Anything else (say, the peer
+            // reloaded the page) starts a new connection.
+            let existing = state.borrow().peers.get(&from_user_id).cloned();
+            let sdp = offer.get("sdp").and_then(|sdp| sdp.as_str()).unwrap_or_default();
+            if let Some(chat) = existing.filter(|chat| chat.is_offer_from_current_peer(sdp)) {
+                let state = state.clone();
+                spawn_local(async move {
+                    let result = async {
+                        let offer = serde_wasm_bindgen::to_value(&offer)?;
+                        let answer = chat.handle_remote_offer(offer).await?;
+                        if answer.is_undefined() {
+                            return Ok(None);
+                        }
+                        let answer: serde_json::Value = serde_wasm_bindgen::from_value(answer)?;
+                        Ok::<_, JsValue>(Some(answer))
+                    }
+                    .await;
+
+                    match result {
+                        Ok(Some(answer)) => send_signal(&state, &SignalMessage::Answer {
+                            target_user_id: from_user_id,
+                            answer,
+                        }),
+                        Ok(None) => {}
+                        Err(e) => report_error(&state, &from_user_id, &e),
+                    }
+                });
+                return;
+            }
+
+            let chat = match create_peer(state, &from_user_id) {
+                Ok(chat) => chat,
+                Err(e) => {
+                    report_error(state, &from_user_id, &e);
+                    return;
+                }
+            };
+
+            let state = state.clone();
+            spawn_local(async move {
+                let result = async {
+                    let offer = serde_wasm_bindgen::to_value(&offer)?;
+                    let answer = chat.accept_offer(offer).await?;
+                    let answer: serde_json::Value = serde_wasm_bindgen::from_value(answer)?;
+                    Ok::<_, JsValue>(answer)
+                }
+                .await;
+
+                match result {
+                    Ok(answer) => send_signal(&state, &SignalMessage::Answer {
+                        target_user_id: from_user_id,
+                        answer,
+                    }),
+                    Err(e) => report_error(&state, &from_user_id, &e),
+                }
+            });
+        }
+        ServerMessage::Answer { from_user_id, answer } => {
+            let Some(chat) = state.borrow().peers.get(&from_user_id).cloned() else {
+                console::log_1(&format!("Answer from unknown peer {}", from_user_id).into());
+                return;
+            };
+
+            let state = state.clone();
+            spawn_local(async move {
+                let result = match serde_wasm_bindgen::to_value(&answer) {
+                    Ok(answer) => chat.complete_connection(answer).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    report_error(&state, &from_user_id, &e);
+                }
+            });
+        }
+        ServerMessage::IceCandidate { from_user_id, candidate } => {
+            let Some(chat) = state.borrow().peers.get(&from_user_id).cloned() else {
+                return;
+            };
+
+            let state = state.clone();
+            spawn_local(async move {
+                let result = match serde_wasm_bindgen::to_value(&candidate) {
+                    Ok(candidate) => chat.add_ice_candidate(candidate).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    report_error(&state, &from_user_id, &e);
+                }
+            });
+        }
+        ServerMessage::Error { message } => {
+            emit(state, &SignalingEvent::Error {
+                user_id: None,
+                message: &message,
+            });
+        }
+    }
+}
+
+// Emit an object built by P2PChat (files, progress, tracks) as an event, tagging it with type and user
+fn emit_object(state: &SharedState, event_type: &str, user_id: &str, object: &JsValue) {
+    let _ = js_sys::Reflect::set(object, &"type".into(), &event_type.into());
+    let _ = js_sys::Reflect::set(object, &"user_id".into(), &user_id.into());
+    let callback = state.borrow().on_event_callback.clone();
+    if let Some(callback) = callback {
+        let _ = callback.call1(&JsValue::NULL, object);
+    }
+}
+
+fn report_error(state: &SharedState, user_id: &str, error: &JsValue) {
+    let message = error.as_string().unwrap_or_else(|| format!("{:?}", error));
+    emit(state, &SignalingEvent::Error {
+        user_id: Some(user_id),
+        message: &message,
+    });
+}
+
+// Create a P2PChat for a remote user, wire its callbacks to signaling and events, and store it
+fn create_peer(state: &SharedState, user_id: &str) -> Result<Rc<P2PChat>, JsValue> {
+    if state.borrow().closed {
+        return Err(JsValue::from_str("Signaling client is closed"));
+    }
+    let turn_config = state.borrow().turn_config.clone();
+    let mut chat = P2PChat::new(turn_config)?;
+    if let Some(own_id) = state.borrow().user_id.clone() {
+        chat.set_negotiation_role(own_id.clone(), user_id.to_string());
+        chat.set_sender_id(own_id);
+    }
+    chat.set_peer_sender_id(user_id.to_string());
+    chat.set_transfer_store(state.borrow_mut().transfers.entry(user_id.to_string()).or_default())?;
+
+    // Decrypted messages become "message" events
+    let message_state = state.clone();
+    let message_user = user_id.to_string();
+    let onmessage_callback = Closure::wrap(Box::new(move |message: JsValue| {
+        match serde_wasm_bindgen::from_value::<Envelope>(message) {
+            Ok(message) => emit(&message_state, &SignalingEvent::Message {
+                user_id: &message_user,
+                message: &message,
+            }),
+            Err(e) => console::log_1(&format!("Malformed message from {}: {}", message_user, e).into()),
+        }
+    }) as Box<dyn FnMut(JsValue)>);
+
+    // Local ICE candidates and offers go to the peer, rejected frames and candidates become
+    // "error" events, and everything else becomes "connection" events
+    let event_state = state.clone();
+    let event_user = user_id.to_string();
+    let onevent_callback = Closure::wrap(Box::new(move |event: JsValue| {
+        let event: serde_json::Value = match serde_wasm_bindgen::from_value(event) {
+            Ok(event) => event,
+            Err(e) => {
+                console::log_1(&format!("Malformed chat event for {}: {}", event_user, e).into());
+                return;
+            }
+        };
+        match event["type"].as_str().unwrap_or_default() {
+            "ice_candidate" => send_signal(&event_state, &SignalMessage::IceCandidate {
+                target_user_id: event_user.clone(),
+                candidate: event["candidate"].clone(),
+            }),
+            "offer" => send_signal(&event_state, &SignalMessage::Offer {
+                target_user_id: event_user.clone(),
+                offer: event["description"].clone(),
+            }),
+            "error" => {
+                let reason = event["reason"].as_str().unwrap_or("unknown error");
+                let message = if event["source"] == "ice_candidate" {
+                    format!("ICE candidate rejected: {}", reason)
+                } else {
+                    format!("Rejected frame: {}", reason)
+                };
+                emit(&event_state, &SignalingEvent::Error {
+                    user_id: Some(&event_user),
+                    message: &message,
+                });
+            }
+            _ => emit(&event_state, &SignalingEvent::Connection {
+                user_id: &event_user,
+                event: &event,
+            }),
+        }
+    }) as Box<dyn FnMut(JsValue)>);
+
+    // Received files and transfer progress become "file" and "file_progress" events
+    let file_state = state.clone();
+    let file_user = user_id.to_string();
+    let onfile_callback = Closure::wrap(Box::new(move |file: JsValue| {
+        emit_object(&file_state, "file", &file_user, &file);
+    }) as Box<dyn FnMut(JsValue)>);
+
+    let progress_state = state.clone();
+    let progress_user = user_id.to_string();
+    let onprogress_callback = Closure::wrap(Box::new(move |progress: JsValue| {
+        emit_object(&progress_state, "file_progress", &progress_user, &progress);
+    }) as Box<dyn FnMut(JsValue)>);
+
+    // Group frames and sender keys become "group_frame" and "sender_key" events for ChatSession,
+    // which keeps the sender keys and opens the frames
+    let group_state = state.clone();
+    let group_user = user_id.to_string();
+    let ongroup_callback = Closure::wrap(Box::new(move |content: JsValue| {
+        let kind = js_sys::Reflect::get(&content, &"kind".into()).ok().and_then(|kind| kind.as_string());
+        let event_type = if kind.as_deref() == Some("sender_key") { "sender_key" } else { "group_frame" };
+        emit_object(&group_state, event_type, &group_user, &content);
+    }) as Box<dyn FnMut(JsValue)>);
+
+    // The peer's call tracks become "track" events
+    let track_state = state.clone();
+    let track_user = user_id.to_string();
+    let ontrack_callback = Closure::wrap(Box::new(move |track: JsValue| {
+        emit_object(&track_state, "track", &track_user, &track);
+    }) as Box<dyn FnMut(JsValue)>);
+
+    chat.on_message(onmessage_callback.as_ref().unchecked_ref::<js_sys::Function>().clone());
+    chat.on_event(onevent_callback.as_ref().clone().unchecked_into::<ChatEventCallback>());
+    chat.on_file(onfile_callback.as_ref().unchecked_ref::<js_sys::Function>().clone());
+    chat.on_file_progress(onprogress_callback.as_ref().unchecked_ref::<js_sys::Function>().clone());
+    chat.on_group_frame(ongroup_callback.as_ref().unchecked_ref::<js_sys::Function>().clone());
+    chat.on_track(ontrack_callback.as_ref().unchecked_ref::<js_sys::Function>().clone());
+
+    // The chat owns these, so they go away when it is closed
+    chat.keep_handler(onmessage_callback);
+    chat.keep_handler(onevent_callback);
+    chat.keep_handler(onfile_callback);
+    chat.keep_handler(onprogress_callback);
+    chat.keep_handler(ongroup_callback);
+    chat.keep_handler(ontrack_callback);
+
+    let chat = Rc::new(chat);
+    let replaced = state.borrow_mut().peers.insert(user_id.to_string(), chat.clone());
+    if let Some(replaced) = replaced {
+        replaced.close();
+    }
+    Ok(chat)
+}
@@ -0,0 +1,303 @@
+// Connection statistics. Boils RTCPeerConnection.getStats() down to what explains a slow chat:
+// which candidate pair carries the traffic (direct or through the TURN relay), its round-trip
+// time, the transport protocol, and how much went over the data channel.
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{console, RtcPeerConnection, RtcSignalingState};
+
+use crate::reconnect::sleep;
+use crate::SharedChatState;
+
+// Shortest interval accepted for periodic sampling
+const MIN_SAMPLE_INTERVAL_MS: u32 = 250;
+
+// Kept in step with StatsSummary; fields that are None until a candidate pair is selected are
+// left undefined
+#[wasm_bindgen(typescript_custom_section)]
+const STATS_SUMMARY_TS: &'static str = r#"
+export type CandidateTypeName = "host" | "srflx" | "prflx" | "relay";
+
+export interface StatsSummary {
+  timestamp: number;
+  local_candidate_type?: CandidateTypeName;
+  remote_candidate_type?: CandidateTypeName;
+  relayed: boolean;
+  transport_protocol?: "udp" | "tcp";
+  relay_protocol?: "udp" | "tcp" | "tls";
+  round_trip_time_ms?: number;
+  bytes_sent: number;
+  bytes_received: number;
+  messages_sent: number;
+  messages_received: number;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "(stats: StatsSummary) => void")]
+    pub type StatsCallback;
+}
+
+// The fields we read from the entries of an RTCStatsReport; everything else is ignored
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawStats {
+    #[serde(default)]
+    id: String,
+    #[serde(rename = "type", default)]
+    type_: String,
+    timestamp: Option<f64>,
+    // transport
+    selected_candidate_pair_id: Option<String>,
+    // candidate-pair
+    local_candidate_id: Option<String>,
+    remote_candidate_id: Option<String>,
+    state: Option<String>,
+    nominated: Option<bool>,
+    // Firefox marks the active pair instead of exposing it on the transport
+    selected: Option<bool>,
+    current_round_trip_time: Option<f64>,
+    // local-candidate, remote-candidate
+    candidate_type: Option<String>,
+    protocol: Option<String>,
+    relay_protocol: Option<String>,
+    // data-channel
+    bytes_sent: Option<f64>,
+    bytes_received: Option<f64>,
+    messages_sent: Option<f64>,
+    messages_received: Option<f64>,
+}
+
+// Summary handed to the application
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct StatsSummary {
+    // Milliseconds since the Unix epoch when the browser took the stats
+    pub(crate) timestamp: f64,
+    // "host", "srflx", "prflx" or "relay"; None until a candidate pair is selected
+    pub(crate) local_candidate_type: Option<String>,
+    pub(crate) remote_candidate_type: Option<String>,
+    // Whether either end of the selected pair goes through a TURN server
+    pub(crate) relayed: bool,
+    // "udp" or "tcp" on the selected pair
+    pub(crate) transport_protocol: Option<String>,
+    // How we reach our TURN server when relayed: "udp", "tcp" or "tls"
+    pub(crate) relay_protocol: Option<String>,
+    pub(crate) round_trip_time_ms: Option<f64>,
+    // Totals over the data channels of this connection
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) messages_sent: u64,
+    pub(crate) messages_received: u64,
+}
+
+// Fetch and summarize the current stats of a peer connection
+pub(crate) async fn collect_stats(peer_connection: &RtcPeerConnection) -> Result<StatsSummary, JsValue> {
+    let report = JsFuture::from(peer_connection.get_stats()).await?;
+
+    // RTCStatsReport is map-like, so Map's forEach works on it
+    let mut entries = Vec::new();
+    report.unchecked_into::<js_sys::Map>().for_each(&mut |value, _| {
+        if let Ok(entry) = serde_wasm_bindgen::from_value::<RawStats>(value) {
+            entries.push(entry);
+        }
+    });
+
+    Ok(summarize(&entries))
+}
+
+fn summarize(entries: &[RawStats]) -> StatsSummary {
+    let mut summary = StatsSummary::default();
+
+    for entry in entries.iter().filter(|entry| entry.type_ == "data-channel") {
+        summary.bytes_sent += count(entry.bytes_sent);
+        summary.bytes_received += count(entry.bytes_received);
+        summary.messages_sent += count(entry.messages_sent);
+        summary.messages_received += count(entry.messages_received);
+    }
+
+    summary.timestamp = entries.iter().filter_map(|entry| entry.timestamp).fold(0.0, f64::max);
+
+    let Some(pair) = selected_pair(entries) else {
+        return summary;
+    };
+    summary.round_trip_time_ms = pair.current_round_trip_time.map(|seconds| seconds * 1000.0);
+
+    let find = |id: &Option<String>| {
+        id.as_ref().and_then(|id| entries.iter().find(|entry| &entry.id == id))
+    };
+    let local = find(&pair.local_candidate_id);
+    let remote = find(&pair.remote_candidate_id);
+
+    summary.local_candidate_type = local.and_then(|candidate| candidate.candidate_type.clone());
+    summary.remote_candidate_type = remote.and_then(|candidate| candidate.candidate_type.clone());
+    summary.relayed = summary.local_candidate_type.as_deref() == Some("relay")
+        || summary.remote_candidate_type.as_deref() == Some("relay");
+    summary.transport_protocol = local.and_then(|candidate| candidate.protocol.clone());
+    if summary.local_candidate_type.as_deref() == Some("relay") {
+        summary.relay_protocol = local.and_then(|candidate| candidate.relay_protocol.clone());
+    }
+
+    summary
+}
+
+// The candidate pair carrying traffic: the one the transport names, the one Firefox flags as
+// selected, or failing both a nominated pair that succeeded
+fn selected_pair(entries: &[RawStats]) -> Option<&RawStats> {
+    let pairs = || entries.iter().filter(|entry| entry.type_ == "candidate-pair");
+
+    let from_transport = entries
+        .iter()
+        .filter(|entry| entry.type_ == "transport")
+        .filter_map(|transport| transport.selected_candidate_pair_id.as_ref())
+        .find_map(|id| pairs().find(|pair| &pair.id == id));
+
+    from_transport
+        .or_else(|| pairs().find(|pair| pair.selected == Some(true)))
+        .or_else(|| pairs().find(|pair| pair.nominated == Some(true) && pair.state.as_deref() == Some("succeeded")))
+}
+
+fn count(value: Option<f64>) -> u64 {
+    value.map_or(0, |value| value.max(0.0) as u64)
+}
+
+// Take a sample every interval and pass it to the callback, until the connection closes or
+// sampling is stopped or restarted
+pub(crate) async fn sample_loop(
+    peer_connection: RtcPeerConnection,
+    state: SharedChatState,
+    generation: u32,
+    interval_ms: u32,
+    callback: js_sys::Function,
+) {
+    let interval_ms = interval_ms.max(MIN_SAMPLE_INTERVAL_MS);
+    loop {
+        if let Err(e) = sleep(interval_ms).await {
+            console::log_2(&"Stats timer failed:".into(), &e);
+            return;
+        }
+        if state.borrow().stats_generation != generation
+            || peer_connection.signaling_state() == RtcSignalingState::Closed
+        {
+            return;
+        }
+
+        match collect_stats(&peer_connection).await {
+            Ok(summary) => {
+                if let Ok(arg) = serde_wasm_bindgen::to_value(&summary) {
+                    let _ = callback.call1(&JsValue::NULL, &arg);
+                }
+            }
+            Err(e) => console::log_2(&"Failed to read connection stats:".into(), &e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(json: serde_json::Value) -> Vec<RawStats> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn candidates() -> serde_json::Value {
+        serde_json::json!([
+            { "id": "L1", "type": "local-candidate", "candidateType": "host", "protocol": "udp" },
+            { "id": "L2", "type": "local-candidate", "candidateType": "relay", "protocol": "udp", "relayProtocol": "tls" },
+            { "id": "R1", "type": "remote-candidate", "candidateType": "srflx", "protocol": "udp" },
+        ])
+    }
+
+    fn with(mut base: serde_json::Value, extra: serde_json::Value) -> Vec<RawStats> {
+        let list = base.as_array_mut().unwrap();
+        list.extend(extra.as_array().unwrap().iter().cloned());
+        entries(base)
+    }
+
+    #[test]
+    fn uses_the_pair_the_transport_names() {
+        let stats = with(candidates(), serde_json::json!([
+            { "id": "T", "type": "transport", "timestamp": 1000.0, "selectedCandidatePairId": "P2" },
+            { "id": "P1", "type": "candidate-pair", "localCandidateId": "L1", "remoteCandidateId": "R1",
+              "state": "succeeded", "nominated": true, "currentRoundTripTime": 0.5 },
+            { "id": "P2", "type": "candidate-pair", "localCandidateId": "L2", "remoteCandidateId": "R1",
+              "state": "succeeded", "currentRoundTripTime": 0.042 },
+        ]));
+        let summary = summarize(&stats);
+        assert_eq!(summary.timestamp, 1000.0);
+        assert_eq!(summary.local_candidate_type.as_deref(), Some("relay"));
+        assert_eq!(summary.remote_candidate_type.as_deref(), Some("srflx"));
+        assert_eq!(summary.round_trip_time_ms, Some(42.0));
+    }
+
+    #[test]
+    fn uses_the_pair_firefox_marks_selected() {
+        let stats = with(candidates(), serde_json::json!([
+            { "id": "P1", "type": "candidate-pair", "localCandidateId": "L2", "remoteCandidateId": "R1",
+              "state": "succeeded", "nominated": true },
+            { "id": "P2", "type": "candidate-pair", "localCandidateId": "L1", "remoteCandidateId": "R1",
+              "state": "succeeded", "selected": true },
+        ]));
+        let summary = summarize(&stats);
+        assert_eq!(summary.local_candidate_type.as_deref(), Some("host"));
+        assert!(!summary.relayed);
+    }
+
+    #[test]
+    fn falls_back_to_a_nominated_pair_that_succeeded() {
+        let stats = with(candidates(), serde_json::json!([
+            { "id": "P1", "type": "candidate-pair", "localCandidateId": "L2", "remoteCandidateId": "R1",
+              "state": "in-progress", "nominated": true },
+            { "id": "P2", "type": "candidate-pair", "localCandidateId": "L1", "remoteCandidateId": "R1",
+              "state": "succeeded", "nominated": true },
+        ]));
+        assert_eq!(summarize(&stats).local_candidate_type.as_deref(), Some("host"));
+
+        let unselected = with(candidates(), serde_json::json!([
+            { "id": "P1", "type": "candidate-pair", "localCandidateId": "L1", "remoteCandidateId": "R1",
+              "state": "succeeded" },
+        ]));
+        let summary = summarize(&unselected);
+        assert_eq!(summary.local_candidate_type, None);
+        assert_eq!(summary.round_trip_time_ms, None);
+    }
+
+    #[test]
+    fn detects_a_relayed_pair_and_its_relay_protocol() {
+        let stats = with(candidates(), serde_json::json!([
+            { "id": "P1", "type": "candidate-pair", "localCandidateId": "L2", "remoteCandidateId": "R1",
+              "selected": true },
+        ]));
+        let summary = summarize(&stats);
+        assert!(summary.relayed);
+        assert_eq!(summary.transport_protocol.as_deref(), Some("udp"));
+        assert_eq!(summary.relay_protocol.as_deref(), Some("tls"));
+
+        let direct = with(candidates(), serde_json::json!([
+            { "id": "P1", "type": "candidate-pair", "localCandidateId": "L1", "remoteCandidateId": "R1",
+              "selected": true },
+        ]));
+        let summary = summarize(&direct);
+        assert!(!summary.relayed);
+        assert_eq!(summary.relay_protocol, None);
+    }
+
+    #[test]
+    fn totals_every_data_channel() {
+        let stats = entries(serde_json::json!([
+            { "id": "D1", "type": "data-channel", "timestamp": 5.0,
+              "bytesSent": 100.0, "bytesReceived": 50.0, "messagesSent": 2.0, "messagesReceived": 1.0 },
+            { "id": "D2", "type": "data-channel", "timestamp": 7.0, "bytesSent": 20.0, "bytesReceived": -1.0 },
+            { "id": "O1", "type": "outbound-rtp", "bytesSent": 9999.0 },
+        ]));
+        let summary = summarize(&stats);
+        assert_eq!(summary.bytes_sent, 120);
+        assert_eq!(summary.bytes_received, 50);
+        assert_eq!(summary.messages_sent, 2);
+        assert_eq!(summary.messages_received, 1);
+        assert_eq!(summary.timestamp, 7.0);
+        assert!(!summary.relayed);
+    }
+}
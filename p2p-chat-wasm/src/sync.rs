@@ -0,0 +1,223 @@
+// History sync between two devices of the same user. Both devices attach their HistoryStore with
+// P2PChat::sync_history and announce their device ids; each then asks the other for everything it
+// stored after the watermark kept from their last sync. Messages travel inside ordinary encrypted
+// envelopes in byte-bounded batches, one batch in flight at a time: the receiver merges a batch,
+// persists the new watermark and acknowledges it, so an interrupted sync resumes where it stopped.
+//
+// History only goes to, and is only merged from, a device the user trusts: one whose connection
+// was verified with mark_peer_verified (which pins the device for later syncs) or one pinned with
+// HistoryStore::pin_device. The first hello fixes the peer device for the rest of the sync.
+
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::envelope::{Envelope, MessageContent};
+use crate::events::{ChatEvent, HistorySyncStatus};
+use crate::history::HistoryDatabase;
+use crate::{notify_event, send_content, SharedChatState};
+
+// Serialized messages per batch, keeping batch frames well under the data channel's message size
+pub(crate) const SYNC_BATCH_BYTES: usize = 12 * 1024;
+
+// Position in the sending device's history: its local storage order, then the message id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SyncWatermark {
+    pub(crate) stored_at: f64,
+    pub(crate) id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SyncedMessage {
+    pub(crate) conversation: String,
+    pub(crate) message: Envelope,
+}
+
+// One batch read from the history
+pub(crate) struct SyncBatch {
+    pub(crate) messages: Vec<SyncedMessage>,
+    // Last record looked at, including ones that weren't sent
+    pub(crate) through: Option<SyncWatermark>,
+    pub(crate) done: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct SyncState {
+    history: Option<Rc<HistoryDatabase>>,
+    hello_sent: bool,
+    peer_device: Option<String>,
+    // Whether peer_device is verified or pinned
+    trusted: bool,
+    merged: u32,
+}
+
+// Attach a history and announce ourselves; the peer answers with its own hello if it has one
+pub(crate) fn start(channel: &web_sys::RtcDataChannel, state: &SharedChatState, history: Rc<HistoryDatabase>) -> Result<(), JsValue> {
+    let device_id = history.device_id().to_string();
+    state.borrow_mut().sync = SyncState {
+        history: Some(history),
+        hello_sent: true,
+        ..SyncState::default()
+    };
+    send_content(channel, state, MessageContent::SyncHello { device_id })?;
+    notify_sync(state, HistorySyncStatus::Started, None, None);
+    Ok(())
+}
+
+// Handle one of the sync kinds of message content
+pub(crate) fn handle_sync(channel: &web_sys::RtcDataChannel, state: &SharedChatState, content: MessageContent) {
+    match content {
+        MessageContent::SyncHello { device_id } => {
+            // A repeated hello from the same device asks again from the stored watermark; one from
+            // another device is refused
+            let device_changed = state.borrow().sync.peer_device.as_ref().is_some_and(|known| *known != device_id);
+            if device_changed {
+                send_or_fail(channel, state, MessageContent::SyncUnavailable);
+                fail(state, &JsValue::from_str("Peer device changed during sync"));
+                return;
+            }
+            let (history, hello_sent, verified) = {
+                let mut state = state.borrow_mut();
+                state.sync.peer_device = Some(device_id.clone());
+                let hello_sent = std::mem::replace(&mut state.sync.hello_sent, true);
+                (state.sync.history.clone(), hello_sent, state.peer_verified)
+            };
+            let Some(history) = history else {
+                send_or_fail(channel, state, MessageContent::SyncUnavailable);
+                return;
+            };
+            if !verified && !history.is_device_pinned(&device_id) {
+                send_or_fail(channel, state, MessageContent::SyncUnavailable);
+                fail(state, &JsValue::from_str("Peer device is not verified or pinned"));
+                return;
+            }
+            state.borrow_mut().sync.trusted = true;
+            if !hello_sent {
+                let device_id = history.device_id().to_string();
+                send_or_fail(channel, state, MessageContent::SyncHello { device_id });
+                notify_sync(state, HistorySyncStatus::Started, None, None);
+            }
+
+            let channel = channel.clone();
+            let state = state.clone();
+            spawn_local(async move {
+                // Remember a verified device so later syncs with it don't need verifying again
+                if verified {
+                    if let Err(e) = history.pin_device(&device_id).await {
+                        fail(&state, &e);
+                        return;
+                    }
+                }
+                match history.watermark(&device_id).await {
+                    Ok(watermark) => send_or_fail(&channel, &state, MessageContent::SyncRequest { watermark }),
+                    Err(e) => fail(&state, &e),
+                }
+            });
+        }
+        MessageContent::SyncRequest { watermark } | MessageContent::SyncAck { through: watermark } => {
+            send_batch(channel, state, watermark);
+        }
+        MessageContent::SyncBatch { messages, through, done } => {
+            receive_batch(channel, state, messages, through, done);
+        }
+        MessageContent::SyncUnavailable => {
+            notify_sync(state, HistorySyncStatus::Unavailable, None, None);
+        }
+        _ => {}
+    }
+}
+
+// Send the next batch of what we stored after the peer's watermark
+fn send_batch(channel: &web_sys::RtcDataChannel, state: &SharedChatState, after: Option<SyncWatermark>) {
+    let (history, peer_device) = trusted_peer(state);
+    let (Some(history), Some(peer_device)) = (history, peer_device) else {
+        send_or_fail(channel, state, MessageContent::SyncUnavailable);
+        return;
+    };
+
+    let channel = channel.clone();
+    let state = state.clone();
+    spawn_local(async move {
+        match history.records_after(after.as_ref(), &peer_device, SYNC_BATCH_BYTES).await {
+            Ok(batch) => send_or_fail(&channel, &state, MessageContent::SyncBatch {
+                messages: batch.messages,
+                through: batch.through.or(after),
+                done: batch.done,
+            }),
+            Err(e) => fail(&state, &e),
+        }
+    });
+}
+
+// Merge a batch from the peer, remember how far we got and ask for more
+fn receive_batch(
+    channel: &web_sys::RtcDataChannel,
+    state: &SharedChatState,
+    messages: Vec<SyncedMessage>,
+    through: Option<SyncWatermark>,
+    done: bool,
+) {
+    let (history, peer_device) = trusted_peer(state);
+    let (Some(history), Some(peer_device)) = (history, peer_device) else {
+        return;
+    };
+
+    let channel = channel.clone();
+    let state = state.clone();
+    spawn_local(async move {
+        let result = async {
+            let merged = history.merge(&messages, &peer_device).await?;
+            if let Some(ref through) = through {
+                history.set_watermark(&peer_device, through).await?;
+            }
+            Ok::<u32, JsValue>(merged)
+        }
+        .await;
+
+        let merged = match result {
+            Ok(merged) => merged,
+            Err(e) => {
+                fail(&state, &e);
+                return;
+            }
+        };
+        let total = {
+            let mut state = state.borrow_mut();
+            state.sync.merged += merged;
+            state.sync.merged
+        };
+        if done {
+            notify_sync(&state, HistorySyncStatus::Complete, Some(total), None);
+        } else {
+            send_or_fail(&channel, &state, MessageContent::SyncAck { through });
+        }
+    });
+}
+
+// Our history and the peer's device, the latter only once it is trusted
+fn trusted_peer(state: &SharedChatState) -> (Option<Rc<HistoryDatabase>>, Option<String>) {
+    let state = state.borrow();
+    let peer_device = state.sync.peer_device.clone().filter(|_| state.sync.trusted);
+    (state.sync.history.clone(), peer_device)
+}
+
+fn send_or_fail(channel: &web_sys::RtcDataChannel, state: &SharedChatState, content: MessageContent) {
+    if let Err(e) = send_content(channel, state, content) {
+        fail(state, &e);
+    }
+}
+
+fn fail(state: &SharedChatState, error: &JsValue) {
+    let reason = error.as_string().unwrap_or_else(|| format!("{:?}", error));
+    notify_sync(state, HistorySyncStatus::Failed, None, Some(&reason));
+}
+
+fn notify_sync(state: &SharedChatState, status: HistorySyncStatus, merged: Option<u32>, reason: Option<&str>) {
+    notify_event(state, &ChatEvent::HistorySync {
+        state: status,
+        merged,
+        reason,
+    });
+}
@@ -0,0 +1,539 @@
+// Chunked file transfer state. Offers, acknowledgements and cancellations travel inside encrypted
+// message envelopes, and chunks as raw bytes in their own binary frame kind; the sender keeps
+// a window of unacknowledged chunks in flight and can rewind to the last acknowledged chunk when
+// the channel comes back after a reconnect.
+//
+// The state lives in a TransferStore that can outlive a connection: a new key exchange, or a new
+// P2PChat attached to the same store, re-offers unfinished files, and the receiver resumes any
+// transfer whose id and file hash match one it holds.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasm_bindgen::prelude::*;
+
+use crate::envelope::random_id;
+
+// Raw bytes per chunk; with the chunk and frame headers this keeps the frame under 16 KiB, the
+// largest message size every browser accepts
+pub(crate) const CHUNK_SIZE: usize = 8 * 1024;
+// Chunks in flight before the sender waits for an acknowledgement
+const WINDOW_CHUNKS: u32 = 16;
+// Largest file we accept from a peer, since incoming files are held in memory
+const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+// Incoming transfers held at once; each may grow to MAX_FILE_SIZE
+const MAX_INCOMING_TRANSFERS: usize = 4;
+
+// Announces a file; re-sent on reconnect so the receiver can report where to resume
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileOffer {
+    pub(crate) transfer_id: String,
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) chunk_size: u32,
+    pub(crate) chunk_count: u32,
+    // Hex SHA-256 of the whole file
+    pub(crate) sha256: String,
+}
+
+impl FileOffer {
+    fn is_valid(&self) -> bool {
+        self.size <= MAX_FILE_SIZE
+            && self.chunk_size > 0
+            && self.chunk_size as usize <= CHUNK_SIZE
+            && self.size.div_ceil(self.chunk_size as u64) == self.chunk_count as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TransferDirection {
+    Send,
+    Receive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TransferStatus {
+    InProgress,
+    Complete,
+    Failed,
+}
+
+// Progress report handed to the application
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TransferProgress {
+    pub(crate) transfer_id: String,
+    pub(crate) direction: TransferDirection,
+    pub(crate) name: String,
+    pub(crate) transferred: u64,
+    pub(crate) total: u64,
+    pub(crate) status: TransferStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<&'static str>,
+}
+
+struct OutgoingTransfer {
+    offer: FileOffer,
+    data: Vec<u8>,
+    // Next chunk to put on the wire, and the first chunk the receiver hasn't acknowledged
+    next_to_send: u32,
+    acked: u32,
+}
+
+impl OutgoingTransfer {
+    fn progress(&self, status: TransferStatus) -> TransferProgress {
+        TransferProgress {
+            transfer_id: self.offer.transfer_id.clone(),
+            direction: TransferDirection::Send,
+            name: self.offer.name.clone(),
+            transferred: (self.acked as u64 * self.offer.chunk_size as u64).min(self.offer.size),
+            total: self.offer.size,
+            status,
+            reason: None,
+        }
+    }
+}
+
+struct IncomingTransfer {
+    offer: FileOffer,
+    data: Vec<u8>,
+    next_index: u32,
+}
+
+impl IncomingTransfer {
+    fn progress(&self, status: TransferStatus, reason: Option<&'static str>) -> TransferProgress {
+        TransferProgress {
+            transfer_id: self.offer.transfer_id.clone(),
+            direction: TransferDirection::Receive,
+            name: self.offer.name.clone(),
+            transferred: self.data.len() as u64,
+            total: self.offer.size,
+            status,
+            reason,
+        }
+    }
+}
+
+// A file chunk as it travels in a chunk frame:
+//   transfer id length (1) | transfer id | index (4, big-endian) | chunk bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OutgoingChunk {
+    pub(crate) transfer_id: String,
+    pub(crate) index: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+impl OutgoingChunk {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        // Outgoing transfer ids come from random_id, well under 255 bytes
+        let id = self.transfer_id.as_bytes();
+        let mut bytes = Vec::with_capacity(1 + id.len() + 4 + self.data.len());
+        bytes.push(id.len() as u8);
+        bytes.extend_from_slice(id);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    // Parse a chunk frame's plaintext; None if it is truncated
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&id_len, rest) = bytes.split_first()?;
+        let id_len = id_len as usize;
+        if rest.len() < id_len + 4 {
+            return None;
+        }
+        let transfer_id = String::from_utf8(rest[..id_len].to_vec()).ok()?;
+        let index = u32::from_be_bytes(rest[id_len..id_len + 4].try_into().ok()?);
+        Some(OutgoingChunk {
+            transfer_id,
+            index,
+            data: rest[id_len + 4..].to_vec(),
+        })
+    }
+}
+
+// A fully received and verified file
+pub(crate) struct ReceivedFile {
+    pub(crate) offer: FileOffer,
+    pub(crate) data: Vec<u8>,
+}
+
+// What the receiver should do after an offer arrived
+pub(crate) enum OfferOutcome {
+    // Acknowledge with the chunk to resume from
+    Resume { next_index: u32, progress: TransferProgress },
+    // Zero-byte file, complete as soon as it is offered
+    Complete { file: ReceivedFile, progress: TransferProgress },
+    Rejected,
+}
+
+// What the receiver should do after a chunk arrived
+pub(crate) enum ChunkOutcome {
+    // Acknowledge up to next_index; the transfer continues
+    Progress { next_index: u32, progress: TransferProgress },
+    // Last chunk arrived and the hash matched
+    Complete { next_index: u32, file: ReceivedFile, progress: TransferProgress },
+    // The transfer is dropped; tell the sender why
+    Failed { reason: &'static str, progress: TransferProgress },
+    // Chunk for an unknown transfer or out of order; re-acknowledge where we are, if anywhere
+    Ignored { next_index: Option<u32> },
+}
+
+#[derive(Default)]
+pub(crate) struct Transfers {
+    outgoing: HashMap<String, OutgoingTransfer>,
+    incoming: HashMap<String, IncomingTransfer>,
+}
+
+pub(crate) type SharedTransfers = Rc<RefCell<Transfers>>;
+
+// File transfer state shared by the connections to one peer, so unfinished transfers resume on a
+// new P2PChat after the old one is gone
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct TransferStore {
+    inner: SharedTransfers,
+}
+
+#[wasm_bindgen]
+impl TransferStore {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TransferStore {
+        TransferStore::default()
+    }
+}
+
+impl TransferStore {
+    pub(crate) fn transfers(&self) -> SharedTransfers {
+        self.inner.clone()
+    }
+}
+
+impl Transfers {
+    // Register a file to send and return its offer
+    pub(crate) fn start_send(&mut self, name: String, data: Vec<u8>) -> FileOffer {
+        let offer = FileOffer {
+            transfer_id: random_id(),
+            name,
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE as u32,
+            chunk_count: data.len().div_ceil(CHUNK_SIZE) as u32,
+            sha256: sha256_hex(&data),
+        };
+        self.outgoing.insert(offer.transfer_id.clone(), OutgoingTransfer {
+            offer: offer.clone(),
+            data,
+            next_to_send: 0,
+            acked: 0,
+        });
+        offer
+    }
+
+    // Offers for every unfinished outgoing transfer, rewound to the last acknowledged chunk
+    pub(crate) fn resume_offers(&mut self) -> Vec<FileOffer> {
+        self.outgoing
+            .values_mut()
+            .map(|transfer| {
+                transfer.next_to_send = transfer.acked;
+                transfer.offer.clone()
+            })
+            .collect()
+    }
+
+    // Chunks of a transfer that fit in the send window
+    pub(crate) fn next_chunks(&mut self, transfer_id: &str) -> Vec<OutgoingChunk> {
+        let Some(transfer) = self.outgoing.get_mut(transfer_id) else {
+            return Vec::new();
+        };
+
+        let mut chunks = Vec::new();
+        while transfer.next_to_send < transfer.offer.chunk_count
+            && transfer.next_to_send - transfer.acked < WINDOW_CHUNKS
+        {
+            let index = transfer.next_to_send;
+            let start = index as usize * CHUNK_SIZE;
+            let end = (start + CHUNK_SIZE).min(transfer.data.len());
+            chunks.push(OutgoingChunk {
+                transfer_id: transfer_id.to_string(),
+                index,
+                data: transfer.data[start..end].to_vec(),
+            });
+            transfer.next_to_send += 1;
+        }
+        chunks
+    }
+
+    // Record the receiver's acknowledgement. Returns None for unknown transfers, and drops the
+    // transfer once every chunk is acknowledged.
+    pub(crate) fn acknowledge(&mut self, transfer_id: &str, next_index: u32) -> Option<TransferProgress> {
+        let transfer = self.outgoing.get_mut(transfer_id)?;
+        let next_index = next_index.min(transfer.offer.chunk_count);
+
+        // The receiver is the authority on what it holds, so an ack behind us rewinds the sender
+        if next_index < transfer.acked || next_index > transfer.next_to_send {
+            transfer.next_to_send = next_index;
+        }
+        transfer.acked = next_index;
+
+        if transfer.acked == transfer.offer.chunk_count {
+            let transfer = self.outgoing.remove(transfer_id)?;
+            Some(transfer.progress(TransferStatus::Complete))
+        } else {
+            Some(transfer.progress(TransferStatus::InProgress))
+        }
+    }
+
+    pub(crate) fn cancel_send(&mut self, transfer_id: &str) -> Option<TransferProgress> {
+        let transfer = self.outgoing.remove(transfer_id)?;
+        Some(transfer.progress(TransferStatus::Failed))
+    }
+
+    // Handle an offer from the peer, which is either new or a resumption after a reconnect. A
+    // held transfer only resumes for the same transfer id and file hash.
+    pub(crate) fn accept_offer(&mut self, offer: FileOffer) -> OfferOutcome {
+        if !offer.is_valid() {
+            return OfferOutcome::Rejected;
+        }
+
+        if let Some(existing) = self.incoming.get(&offer.transfer_id) {
+            if existing.offer != offer {
+                return OfferOutcome::Rejected;
+            }
+            return OfferOutcome::Resume {
+                next_index: existing.next_index,
+                progress: existing.progress(TransferStatus::InProgress, None),
+            };
+        }
+
+        if self.incoming.len() >= MAX_INCOMING_TRANSFERS {
+            return OfferOutcome::Rejected;
+        }
+
+        let transfer = IncomingTransfer {
+            offer,
+            data: Vec::new(),
+            next_index: 0,
+        };
+
+        if transfer.offer.chunk_count == 0 {
+            if sha256_hex(&[]) != transfer.offer.sha256.to_ascii_lowercase() {
+                return OfferOutcome::Rejected;
+            }
+            let progress = transfer.progress(TransferStatus::Complete, None);
+            return OfferOutcome::Complete {
+                file: ReceivedFile {
+                    offer: transfer.offer,
+                    data: transfer.data,
+                },
+                progress,
+            };
+        }
+
+        let progress = transfer.progress(TransferStatus::InProgress, None);
+        self.incoming.insert(transfer.offer.transfer_id.clone(), transfer);
+        OfferOutcome::Resume { next_index: 0, progress }
+    }
+
+    pub(crate) fn receive_chunk(&mut self, transfer_id: &str, index: u32, data: Vec<u8>) -> ChunkOutcome {
+        let Some(transfer) = self.incoming.get_mut(transfer_id) else {
+            return ChunkOutcome::Ignored { next_index: None };
+        };
+
+        // The channel is ordered, so anything but the next chunk is a retransmission after a rewind
+        if index != transfer.next_index {
+            return ChunkOutcome::Ignored { next_index: Some(transfer.next_index) };
+        }
+
+        let remaining = transfer.offer.size - transfer.data.len() as u64;
+        let expected = remaining.min(transfer.offer.chunk_size as u64) as usize;
+        if data.len() != expected {
+            return self.fail_incoming(transfer_id, "bad_chunk");
+        }
+
+        transfer.data.extend_from_slice(&data);
+        transfer.next_index += 1;
+        let next_index = transfer.next_index;
+
+        if next_index < transfer.offer.chunk_count {
+            return ChunkOutcome::Progress {
+                next_index,
+                progress: transfer.progress(TransferStatus::InProgress, None),
+            };
+        }
+
+        if sha256_hex(&transfer.data) != transfer.offer.sha256.to_ascii_lowercase() {
+            return self.fail_incoming(transfer_id, "hash_mismatch");
+        }
+
+        match self.incoming.remove(transfer_id) {
+            Some(transfer) => {
+                let progress = transfer.progress(TransferStatus::Complete, None);
+                ChunkOutcome::Complete {
+                    next_index,
+                    file: ReceivedFile {
+                        offer: transfer.offer,
+                        data: transfer.data,
+                    },
+                    progress,
+                }
+            }
+            None => ChunkOutcome::Ignored { next_index: None },
+        }
+    }
+
+    // The sender gave up on a transfer
+    pub(crate) fn cancel_receive(&mut self, transfer_id: &str) -> Option<TransferProgress> {
+        let transfer = self.incoming.remove(transfer_id)?;
+        Some(transfer.progress(TransferStatus::Failed, Some("cancelled")))
+    }
+
+    fn fail_incoming(&mut self, transfer_id: &str, reason: &'static str) -> ChunkOutcome {
+        match self.incoming.remove(transfer_id) {
+            Some(transfer) => ChunkOutcome::Failed {
+                reason,
+                progress: transfer.progress(TransferStatus::Failed, Some(reason)),
+            },
+            None => ChunkOutcome::Ignored { next_index: None },
+        }
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn offer(transfer_id: &str, data: &[u8]) -> FileOffer {
+        FileOffer {
+            transfer_id: transfer_id.to_string(),
+            name: "file.bin".to_string(),
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE as u32,
+            chunk_count: data.len().div_ceil(CHUNK_SIZE) as u32,
+            sha256: sha256_hex(data),
+        }
+    }
+
+    #[test]
+    fn resumes_an_interrupted_transfer() {
+        let data = file(CHUNK_SIZE * 40 + 123);
+        let sender = TransferStore::new();
+        let receiver = TransferStore::new();
+        let offer = sender.transfers().borrow_mut().start_send("file.bin".to_string(), data.clone());
+        let transfer_id = offer.transfer_id.clone();
+
+        let OfferOutcome::Resume { next_index: 0, .. } = receiver.transfers().borrow_mut().accept_offer(offer) else {
+            panic!("offer not accepted");
+        };
+
+        // The first window gets through, then the connection drops with the second in flight
+        let chunks = sender.transfers().borrow_mut().next_chunks(&transfer_id);
+        assert_eq!(chunks.len(), WINDOW_CHUNKS as usize);
+        let mut acked = 0;
+        for chunk in chunks {
+            match receiver.transfers().borrow_mut().receive_chunk(&transfer_id, chunk.index, chunk.data) {
+                ChunkOutcome::Progress { next_index, .. } => acked = next_index,
+                _ => panic!("chunk not accepted"),
+            }
+        }
+        sender.transfers().borrow_mut().acknowledge(&transfer_id, acked).unwrap();
+        let lost = sender.transfers().borrow_mut().next_chunks(&transfer_id);
+        assert_eq!(lost[0].index, WINDOW_CHUNKS);
+
+        // The next session re-offers the file
+        let offers = sender.transfers().borrow_mut().resume_offers();
+        assert_eq!(offers.len(), 1);
+        let next_index = match receiver.transfers().borrow_mut().accept_offer(offers[0].clone()) {
+            OfferOutcome::Resume { next_index, .. } => next_index,
+            _ => panic!("resumption rejected"),
+        };
+        assert_eq!(next_index, WINDOW_CHUNKS);
+        sender.transfers().borrow_mut().acknowledge(&transfer_id, next_index).unwrap();
+
+        let mut received = None;
+        while received.is_none() {
+            let chunks = sender.transfers().borrow_mut().next_chunks(&transfer_id);
+            assert!(!chunks.is_empty());
+            for chunk in chunks {
+                match receiver.transfers().borrow_mut().receive_chunk(&transfer_id, chunk.index, chunk.data) {
+                    ChunkOutcome::Progress { next_index, .. } => {
+                        sender.transfers().borrow_mut().acknowledge(&transfer_id, next_index).unwrap();
+                    }
+                    ChunkOutcome::Complete { next_index, file, .. } => {
+                        let progress = sender.transfers().borrow_mut().acknowledge(&transfer_id, next_index).unwrap();
+                        assert_eq!(progress.status, TransferStatus::Complete);
+                        received = Some(file);
+                    }
+                    _ => panic!("chunk not accepted"),
+                }
+            }
+        }
+        assert_eq!(received.unwrap().data, data);
+        assert!(sender.transfers().borrow_mut().resume_offers().is_empty());
+    }
+
+    #[test]
+    fn only_resumes_offers_with_the_same_hash() {
+        let mut transfers = Transfers::default();
+        let data = file(CHUNK_SIZE * 2);
+        assert!(matches!(transfers.accept_offer(offer("t1", &data)), OfferOutcome::Resume { next_index: 0, .. }));
+
+        let mut changed = offer("t1", &data);
+        changed.sha256 = sha256_hex(b"something else");
+        assert!(matches!(transfers.accept_offer(changed), OfferOutcome::Rejected));
+        assert!(matches!(transfers.accept_offer(offer("t1", &data)), OfferOutcome::Resume { next_index: 0, .. }));
+    }
+
+    #[test]
+    fn limits_concurrent_incoming_transfers() {
+        let mut transfers = Transfers::default();
+        let data = file(CHUNK_SIZE + 1);
+        for i in 0..MAX_INCOMING_TRANSFERS {
+            let outcome = transfers.accept_offer(offer(&format!("t{}", i), &data));
+            assert!(matches!(outcome, OfferOutcome::Resume { .. }));
+        }
+        assert!(matches!(transfers.accept_offer(offer("extra", &data)), OfferOutcome::Rejected));
+        // Resuming one already held is still fine
+        assert!(matches!(transfers.accept_offer(offer("t0", &data)), OfferOutcome::Resume { .. }));
+
+        // Finishing one makes room again
+        assert!(transfers.cancel_receive("t0").is_some());
+        assert!(matches!(transfers.accept_offer(offer("extra", &data)), OfferOutcome::Resume { .. }));
+    }
+
+    #[test]
+    fn round_trips_chunk_frames() {
+        let chunk = OutgoingChunk {
+            transfer_id: "abc123".to_string(),
+            index: 7,
+            data: file(CHUNK_SIZE),
+        };
+        let bytes = chunk.to_bytes();
+        assert_eq!(bytes.len(), 1 + 6 + 4 + CHUNK_SIZE);
+        assert_eq!(OutgoingChunk::from_bytes(&bytes), Some(chunk));
+        assert_eq!(OutgoingChunk::from_bytes(&bytes[..1 + 6 + 3]), None);
+        assert_eq!(OutgoingChunk::from_bytes(&[]), None);
+    }
+
+    #[test]
+    fn fails_a_transfer_whose_hash_does_not_match() {
+        let mut transfers = Transfers::default();
+        let data = file(100);
+        let mut bad = offer("t1", &data);
+        bad.sha256 = sha256_hex(b"other");
+        transfers.accept_offer(bad);
+        let outcome = transfers.receive_chunk("t1", 0, data);
+        assert!(matches!(outcome, ChunkOutcome::Failed { reason: "hash_mismatch", .. }));
+    }
+}